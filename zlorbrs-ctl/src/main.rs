@@ -1,10 +1,26 @@
 mod utils;
-use crate::utils::{daemon, repo};
+use crate::utils::{build, check, control, daemon, edit, history, list, logs, migrate, repo};
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
+use log::{error, warn};
+use std::path::PathBuf;
+use zlorbrs_lib::{
+    config,
+    format::Format,
+    history::{BuildStatus, Filter, Trigger},
+    paths,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Keep configs, logs and history in this directory instead, for running
+    /// an isolated instance. Also read from ZLORBRS_HOME
+    #[arg(long, global = true)]
+    config_dir: Option<PathBuf>,
+    /// Use the system-wide service's configs in /etc/zlorbrs
+    #[arg(long, global = true, conflicts_with = "config_dir")]
+    system: bool,
     #[command(subcommand)]
     cmd: Commands,
 }
@@ -15,9 +31,113 @@ enum Commands {
         #[arg(short, long)]
         repo_name: String,
     },
-    Add,
-    List,
+    /// Adds a repo, detecting whatever isn't given from the repository
+    Add {
+        /// Defaults to the repository's directory name
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Defaults to the current directory
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+        #[arg(short, long)]
+        branch: Option<String>,
+        #[arg(short, long)]
+        remote: Option<String>,
+        /// e.g. "bun run build"
+        #[arg(long)]
+        build_command: Option<String>,
+        /// Ask for every field, suggesting the detected values
+        #[arg(short, long)]
+        interactive: bool,
+        /// Write the config as json, toml or yaml
+        #[arg(long, default_value_t = Format::Json)]
+        config_format: Format,
+    },
+    /// Lists the configured repos with their branch, commits and last build
+    List {
+        /// Print a JSON array instead of a table
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+        /// One line per repo, e.g. "{name} {last_build_status}"
+        #[arg(long)]
+        format: Option<String>,
+    },
+    /// Starts the service through systemd, or detached when no unit is installed
     Start,
+    /// Stops the service
+    Stop,
+    /// Runs a repo's build in the foreground
+    Build {
+        repo_name: String,
+    },
+    /// Prints the log of a repo's latest build, or of build N
+    Logs {
+        repo_name: String,
+        #[arg(short, long)]
+        build: Option<u64>,
+        /// Keep printing output as the build writes it, until it finishes
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Lists a repo's past builds, newest first
+    History {
+        repo_name: String,
+        /// Show at most this many builds
+        #[arg(short, long)]
+        limit: Option<usize>,
+        /// success, failed, error, timed_out or cancelled
+        #[arg(short, long)]
+        status: Option<BuildStatus>,
+        /// new_commit, missing_artifacts, manual or scheduled
+        #[arg(short, long)]
+        trigger: Option<Trigger>,
+        /// Only builds started since YYYY-MM-DD or an RFC 3339 timestamp
+        #[arg(long, value_parser = history::parse_since)]
+        since: Option<DateTime<Utc>>,
+    },
+    /// Validates repo configs and the service config, exiting non-zero on errors
+    Check {
+        repo_name: Option<String>,
+    },
+    /// Opens a repo's config in $EDITOR and saves it once it is valid
+    Edit {
+        repo_name: String,
+    },
+    /// Changes fields of a repo's config, e.g. branch=main env.NODE_ENV=production
+    Set {
+        repo_name: String,
+        /// key=value, values are parsed as JSON when possible
+        #[arg(required = true)]
+        assignments: Vec<String>,
+    },
+    /// Upgrades configs written by an older zlorbrs, keeping backups
+    Migrate {
+        /// Only show what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Shows whether the service is running and what it is doing
+    Status,
+    /// Asks the service to check a repo right now
+    Trigger {
+        repo_name: String,
+        /// Build even if nothing changed
+        #[arg(short, long)]
+        build: bool,
+    },
+    /// Stops the service from checking a repo until it is resumed
+    Pause {
+        repo_name: String,
+    },
+    Resume {
+        repo_name: String,
+    },
+    /// Stops the build of a repo the service is running
+    Cancel {
+        repo_name: String,
+    },
+    /// Makes the service re-read its config
+    Reload,
 }
 
 fn main() {
@@ -25,11 +145,91 @@ fn main() {
     env_logger::init();
 
     let args = Args::parse();
+    if let Some(config_dir) = args.config_dir
+        && let Err(e) = paths::set_root(config_dir)
+    {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    if args.system {
+        if let Err(e) = paths::set_system() {
+            error!("{e}");
+            std::process::exit(1);
+        }
+        if let Err(e) = config::trust_configured_repos(None) {
+            warn!("Failed to list the configured repositories for git: {e}");
+        }
+    }
 
-    match args.cmd {
-        Commands::Add => repo::add(),
-        Commands::List => repo::list(),
+    let result = match args.cmd {
+        Commands::Add {
+            name,
+            path,
+            branch,
+            remote,
+            build_command,
+            interactive,
+            config_format,
+        } => repo::add(repo::AddOptions {
+            name,
+            path,
+            branch,
+            remote,
+            build_command,
+            interactive,
+            config_format,
+        }),
+        Commands::List { json, format } => list::list(json, format),
         Commands::Start => daemon::start(),
+        Commands::Stop => daemon::stop(),
         Commands::Remove { repo_name } => repo::remove(repo_name),
+        Commands::Build { repo_name } => build::run(repo_name),
+        Commands::Logs {
+            repo_name,
+            build,
+            follow,
+        } => logs::show(repo_name, build, follow),
+        Commands::History {
+            repo_name,
+            limit,
+            status,
+            trigger,
+            since,
+        } => history::show(
+            repo_name,
+            Filter {
+                status,
+                trigger,
+                since,
+                limit,
+            },
+        ),
+        Commands::Check { repo_name } => {
+            if !check::check(repo_name) {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Commands::Edit { repo_name } => edit::edit(repo_name),
+        Commands::Set {
+            repo_name,
+            assignments,
+        } => edit::set(repo_name, assignments),
+        Commands::Migrate { dry_run } => migrate::migrate(dry_run),
+        Commands::Status => daemon::status().map(|running| {
+            if !running {
+                std::process::exit(1);
+            }
+        }),
+        Commands::Trigger { repo_name, build } => control::trigger(repo_name, build),
+        Commands::Pause { repo_name } => control::pause(repo_name),
+        Commands::Resume { repo_name } => control::resume(repo_name),
+        Commands::Cancel { repo_name } => control::cancel(repo_name),
+        Commands::Reload => control::reload(),
+    };
+
+    if let Err(e) = result {
+        error!("{e}");
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,222 @@
+use std::{
+    io::{self, Write},
+    time::SystemTime,
+};
+use zlorbrs_lib::{
+    Error, Result,
+    build::{self, Cancel, RepoLock},
+    config::Config,
+    history::{self, BuildRecord, Trigger},
+    logs::{BuildLog, Retention},
+    service::ServiceConfig,
+};
+
+/// .
+///
+/// # Builds a repo in the foreground
+///
+/// Runs the same build the service would for `repo_name`, including the
+/// repository's `.zlorbrs.toml`, and streams its output without fetching
+/// anything first. It waits for the service to be done with the repo, and
+/// gets a log and a history entry like the service's builds. Ctrl-C stops
+/// the build
+pub(crate) fn run(repo_name: String) -> Result<()> {
+    let config = Config::from_name(&repo_name)?.with_pipeline()?;
+    let _lock = match RepoLock::try_acquire(&repo_name)? {
+        Some(lock) => lock,
+        None => {
+            println!("Waiting for the service to be done with {repo_name}...");
+            RepoLock::acquire(&repo_name)?
+        }
+    };
+
+    let cancel = Cancel::default();
+    build::cancel_on_interrupt(&cancel);
+    let mut log = BuildLog::create(&repo_name, retention())?;
+    println!("Build #{} log: {}", log.id, log.path.display());
+
+    let head = git2::Repository::open(&config.path)
+        .ok()
+        .and_then(|repo| repo.head().ok()?.target())
+        .map(|oid| oid.to_string());
+    let started_at = SystemTime::now();
+    let outcome = build::run(&config, &mut Tee(&mut io::stdout(), &mut log), &cancel);
+    let record = match &outcome {
+        Ok(result) => {
+            BuildRecord::from_result(log.id, &repo_name, Trigger::Manual, None, head, result)
+        }
+        Err(e) => BuildRecord::from_error(
+            log.id,
+            &repo_name,
+            Trigger::Manual,
+            None,
+            head,
+            started_at,
+            e,
+        ),
+    };
+    history::append(&record)?;
+    let result = outcome?;
+
+    if let Some(stopped) = result.stopped {
+        return Err(Error::Build(format!(
+            "{} {} after {:?}",
+            repo_name, stopped, result.duration
+        )));
+    }
+    if !result.success() {
+        return Err(Error::Build(format!(
+            "{} failed at {} with exit code {:?} after {:?}",
+            repo_name,
+            result.failed_step.unwrap_or_default(),
+            result.exit_code,
+            result.duration
+        )));
+    }
+    println!("Built {} in {:?}", repo_name, result.duration);
+    Ok(())
+}
+
+/// How many logs the service keeps, so a foreground build prunes the same
+fn retention() -> Retention {
+    ServiceConfig::path()
+        .and_then(|path| ServiceConfig::from_file(&path))
+        .map(|config| config.log_retention())
+        .unwrap_or_default()
+}
+
+/// Writes build output to the terminal and the build log alike
+struct Tee<'a>(&'a mut dyn Write, &'a mut dyn Write);
+
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use zlorbrs_lib::config::BuildCommand;
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
+
+    struct TestEnv {
+        home_dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.home_dir);
+        }
+    }
+
+    fn setup_test_env(test_name: &str, build_command: &str) -> TestEnv {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push(format!("zlorbrs_ctl_build_{}", test_name));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        let config = Config {
+            name: String::from("test_repo"),
+            path: home_dir.to_str().unwrap().to_string(),
+            build_command: BuildCommand::from(build_command),
+            ..Default::default()
+        };
+        let config_dir = home_dir.join(".config/zlorbrs/configs/test_repo");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(
+            config_dir.join("config.json"),
+            serde_json::to_string(&config).unwrap(),
+        )
+        .unwrap();
+
+        TestEnv {
+            home_dir,
+            _lock: lock,
+        }
+    }
+
+    #[test]
+    fn test_run_success() {
+        let _env = setup_test_env("success", "true");
+        assert!(run(String::from("test_repo")).is_ok());
+    }
+
+    #[test]
+    fn test_run_failure() {
+        let _env = setup_test_env("failure", "false");
+        assert!(matches!(
+            run(String::from("test_repo")),
+            Err(Error::Build(_))
+        ));
+    }
+
+    #[test]
+    fn test_run_records_log_and_history() {
+        let _env = setup_test_env("records", "echo built");
+        assert!(run(String::from("test_repo")).is_ok());
+
+        let logs = zlorbrs_lib::logs::list("test_repo").unwrap();
+        assert_eq!(logs.len(), 1);
+        assert!(fs::read_to_string(&logs[0].1).unwrap().contains("built"));
+
+        let records = history::read("test_repo").unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, logs[0].0);
+        assert_eq!(records[0].trigger, Trigger::Manual);
+        assert_eq!(records[0].status, history::BuildStatus::Success);
+    }
+
+    #[test]
+    fn test_run_waits_for_the_service() {
+        let env = setup_test_env("waits", "touch built");
+        let service = RepoLock::acquire("test_repo").unwrap();
+
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || done.send(run(String::from("test_repo")).is_ok()).unwrap());
+        assert!(finished.recv_timeout(Duration::from_millis(300)).is_err());
+        assert!(!env.home_dir.join("built").exists());
+
+        drop(service);
+        assert!(finished.recv_timeout(Duration::from_secs(10)).unwrap());
+        assert!(env.home_dir.join("built").exists());
+    }
+
+    #[test]
+    fn test_run_unknown_repo() {
+        let _env = setup_test_env("unknown_repo", "true");
+        assert!(run(String::from("nope")).is_err());
+    }
+
+    #[test]
+    fn test_run_uses_pipeline() {
+        let env = setup_test_env("pipeline", "bun run build");
+        fs::write(
+            env.home_dir.join(".zlorbrs.toml"),
+            "build_command = \"touch built-by-pipeline\"\n",
+        )
+        .unwrap();
+
+        assert!(run(String::from("test_repo")).is_ok());
+        assert!(env.home_dir.join("built-by-pipeline").exists());
+    }
+}
@@ -0,0 +1,235 @@
+use log::error;
+use zlorbrs_lib::{
+    config::{Config, Diagnostic, Severity},
+    service::ServiceConfig,
+};
+
+use super::repo;
+
+/// .
+///
+/// # Checks repo configs
+///
+/// Validates every repo config (or just `repo_name`) and, when checking
+/// everything, the service config too. Prints each problem found and
+/// returns whether all checked configs are usable, warnings aside.
+pub(crate) fn check(repo_name: Option<String>) -> bool {
+    let repos = match repo::get_all() {
+        Ok(repos) => repos,
+        Err(e) => {
+            error!("Failed to read configurations: {e}");
+            return false;
+        }
+    };
+
+    let mut healthy = true;
+    let mut checked = 0;
+    for (_, entry) in repos {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Failed to read config directory entry: {e}");
+                healthy = false;
+                continue;
+            }
+        };
+        let dir_name = entry.file_name().to_string_lossy().into_owned();
+        if repo_name.as_ref().is_some_and(|name| *name != dir_name) {
+            continue;
+        }
+        checked += 1;
+
+        let problems = match Config::from_dir(&entry.path()).and_then(Config::with_pipeline) {
+            Ok(config) => {
+                let mut problems = config.validate();
+                if config.name != dir_name {
+                    problems.push(Diagnostic::warning(
+                        "name",
+                        format!(
+                            "is {:?} but the config lives in {dir_name:?}, the service goes by the directory",
+                            config.name
+                        ),
+                    ));
+                }
+                problems
+            }
+            Err(e) => vec![Diagnostic::error("config", e.to_string())],
+        };
+        healthy &= report(&dir_name, &problems);
+    }
+
+    if let Some(name) = repo_name {
+        if checked == 0 {
+            println!("Theres no config found with name: {name}");
+            return false;
+        }
+        return healthy;
+    }
+    healthy & check_service_config()
+}
+
+fn check_service_config() -> bool {
+    let path = match ServiceConfig::path() {
+        Ok(path) => path,
+        Err(e) => {
+            error!("{e}");
+            return false;
+        }
+    };
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    if !path.exists() {
+        println!("{file_name}: missing, the service writes the defaults when it starts");
+        return true;
+    }
+    let problems = match ServiceConfig::from_file(&path) {
+        Ok(config) => config.validate(),
+        Err(e) => vec![Diagnostic::error("config", e.to_string())],
+    };
+    report(&file_name, &problems)
+}
+
+/// Prints `problems` under `name`, returning false if any of them is an error
+fn report(name: &str, problems: &[Diagnostic]) -> bool {
+    if problems.is_empty() {
+        println!("{name}: ok");
+        return true;
+    }
+    println!("{name}:");
+    for problem in problems {
+        println!("  {problem}");
+    }
+    !problems
+        .iter()
+        .any(|problem| problem.severity == Severity::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use zlorbrs_lib::config::BuildCommand;
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
+
+    struct TestEnv {
+        home_dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.home_dir);
+        }
+    }
+
+    fn setup_test_env(test_name: &str) -> TestEnv {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push(format!("zlorbrs_check_{}", test_name));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        TestEnv {
+            home_dir,
+            _lock: lock,
+        }
+    }
+
+    fn write_config(env: &TestEnv, remote: &str) {
+        write_config_with(env, remote, "true");
+    }
+
+    fn write_config_with(env: &TestEnv, remote: &str, build_command: &str) {
+        let repo_dir = env.home_dir.join("project");
+        let repo = git2::Repository::init(&repo_dir).unwrap();
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        // the branch has to exist, so it needs a commit
+        let signature = git2::Signature::now("Test User", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+
+        let config = Config {
+            name: String::from("test_repo"),
+            path: repo_dir.to_str().unwrap().to_string(),
+            branch,
+            remote: String::from(remote),
+            build_command: BuildCommand::from(build_command),
+            ..Default::default()
+        };
+        let config_dir = env.home_dir.join(".config/zlorbrs/configs/test_repo");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(
+            config_dir.join("config.json"),
+            serde_json::to_string(&config).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_check_existing_remote() {
+        let env = setup_test_env("existing_remote");
+        write_config(&env, "origin");
+        assert!(check(None));
+        assert!(check(Some(String::from("test_repo"))));
+    }
+
+    #[test]
+    fn test_check_missing_remote() {
+        let env = setup_test_env("missing_remote");
+        write_config(&env, "upstream");
+        assert!(!check(None));
+    }
+
+    #[test]
+    fn test_check_unknown_repo() {
+        let env = setup_test_env("unknown_repo");
+        write_config(&env, "origin");
+        assert!(!check(Some(String::from("nope"))));
+    }
+
+    #[test]
+    fn test_check_missing_program() {
+        let env = setup_test_env("missing_program");
+        write_config_with(&env, "origin", "zlorbrs-command-that-does-not-exist");
+        assert!(!check(Some(String::from("test_repo"))));
+    }
+
+    #[test]
+    fn test_check_malformed_config() {
+        let env = setup_test_env("malformed_config");
+        let config_dir = env.home_dir.join(".config/zlorbrs/configs/test_repo");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(
+            config_dir.join("config.json"),
+            r#"{ "name": "test_repo", "brnch": "main" }"#,
+        )
+        .unwrap();
+        assert!(!check(Some(String::from("test_repo"))));
+    }
+
+    #[test]
+    fn test_check_service_config() {
+        let env = setup_test_env("service_config");
+        write_config(&env, "origin");
+        let service_config = env.home_dir.join(".config/zlorbrs/service-config.json");
+
+        // warnings alone don't fail the check
+        fs::write(&service_config, r#"{ "sleep_time": 0 }"#).unwrap();
+        assert!(check(None));
+
+        fs::write(&service_config, r#"{ "sleep_time": "soon" }"#).unwrap();
+        assert!(!check(None));
+        // checking a single repo leaves the service config alone
+        assert!(check(Some(String::from("test_repo"))));
+    }
+}
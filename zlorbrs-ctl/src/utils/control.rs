@@ -0,0 +1,157 @@
+use chrono::{DateTime, Local, Utc};
+use log::{debug, error};
+use zlorbrs_lib::{
+    Error, Result,
+    ipc::{self, Request, Response, ServiceStatus},
+};
+
+/// Asks the service to check `repo_name` right now, or to build it whether or
+/// not anything changed when `build` is set
+pub(crate) fn trigger(repo_name: String, build: bool) -> Result<()> {
+    let request = if build {
+        Request::Build { repo: repo_name }
+    } else {
+        Request::Check { repo: repo_name }
+    };
+    send(request)
+}
+
+pub(crate) fn pause(repo_name: String) -> Result<()> {
+    send(Request::Pause { repo: repo_name })
+}
+
+pub(crate) fn resume(repo_name: String) -> Result<()> {
+    send(Request::Resume { repo: repo_name })
+}
+
+/// Asks the service to stop the build of `repo_name` that is running
+pub(crate) fn cancel(repo_name: String) -> Result<()> {
+    send(Request::Cancel { repo: repo_name })
+}
+
+pub(crate) fn reload() -> Result<()> {
+    send(Request::Reload)
+}
+
+/// Tells a running service that configs changed. Not having a service to
+/// tell is fine, it reads them when it starts
+pub(crate) fn notify_reload() {
+    match ipc::send(&Request::Reload) {
+        Ok(Response::Ok { .. }) => println!("The running service will pick up the change"),
+        Ok(other) => error!("The service didn't take the reload: {other:?}"),
+        Err(e) => debug!("Not notifying the service: {e}"),
+    }
+}
+
+fn send(request: Request) -> Result<()> {
+    match ipc::send(&request)? {
+        Response::Ok { message } => {
+            println!("{message}");
+            Ok(())
+        }
+        Response::Error { message } => Err(Error::Ipc(message)),
+        other => Err(Error::Ipc(format!("Unexpected answer {other:?}"))),
+    }
+}
+
+/// .
+///
+/// # Formats the service status
+///
+/// A header with the pid, uptime and config directory followed by one line
+/// per repo
+pub(crate) fn format_status(status: &ServiceStatus, now: DateTime<Utc>) -> String {
+    let uptime = (now - status.started_at).num_seconds().max(0);
+    let mut out = format!(
+        "zlorbrs-service running (pid {}, up {}h {}m {}s)\nconfigs: {}\n",
+        status.pid,
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60,
+        status.config_dir
+    );
+
+    for repo in &status.repos {
+        let state = if repo.checking {
+            "checking"
+        } else if repo.paused {
+            "paused"
+        } else {
+            "idle"
+        };
+        let last_checked = repo.last_checked.map_or(String::from("never"), |at| {
+            at.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        });
+        out.push_str(&format!(
+            "{:<20} {:<9} last checked {}",
+            repo.name, state, last_checked
+        ));
+        if let Some(error) = &repo.last_error {
+            out.push_str(&format!(" ({error})"));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::env;
+    use std::fs;
+    use zlorbrs_lib::ipc::RepoStatus;
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
+
+    #[test]
+    fn test_format_status() {
+        let started_at = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+        let status = ServiceStatus {
+            pid: 42,
+            started_at,
+            config_dir: String::from("/home/me/.config/zlorbrs/configs"),
+            repos: vec![
+                RepoStatus {
+                    name: String::from("site"),
+                    paused: true,
+                    ..Default::default()
+                },
+                RepoStatus {
+                    name: String::from("api"),
+                    last_checked: Some(started_at),
+                    last_error: Some(String::from("git error: boom")),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let out = format_status(&status, Utc.with_ymd_and_hms(2025, 1, 1, 13, 2, 3).unwrap());
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "zlorbrs-service running (pid 42, up 1h 2m 3s)");
+        assert_eq!(lines[1], "configs: /home/me/.config/zlorbrs/configs");
+        assert!(lines[2].starts_with("site"));
+        assert!(lines[2].contains("paused"));
+        assert!(lines[2].ends_with("last checked never"));
+        assert!(lines[3].contains("idle"));
+        assert!(lines[3].ends_with("(git error: boom)"));
+    }
+
+    #[test]
+    fn test_without_service() {
+        let _lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push("zlorbrs_ctl_control_no_service");
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        assert!(matches!(reload(), Err(Error::Ipc(_))));
+        assert!(matches!(pause(String::from("my-repo")), Err(Error::Ipc(_))));
+
+        let _ = fs::remove_dir_all(&home_dir);
+    }
+}
@@ -1,6 +1,336 @@
-use log::error;
+use std::{
+    env,
+    fs::{self, File},
+    io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
 
-pub(crate) fn start() {
-    error!("Starting daemon from ctl isnt supported yet");
-    unimplemented!();
+use chrono::Utc;
+use log::{debug, info};
+use zlorbrs_lib::{
+    Error, Result,
+    ipc::{self, Request, Response},
+    paths,
+};
+
+use crate::utils::control;
+
+/// Name of the unit installed by the justfile
+const UNIT: &str = "zlorbrs.service";
+
+/// How long start and stop wait for the service to come up or go away
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Who keeps the service running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Manager {
+    Systemd { user: bool },
+    Pidfile, // no unit installed, zlorbrs-ctl runs the service detached
+}
+
+impl Manager {
+    /// The user unit, or with `--system` the system unit, otherwise running
+    /// detached. Isolated instances always run detached, the units know
+    /// nothing of them
+    fn detect() -> Self {
+        if paths::root().is_some() {
+            return Manager::Pidfile;
+        }
+        let user = !paths::system();
+        if systemctl(user, &["cat", UNIT]).is_ok_and(|output| output.status.success()) {
+            return Manager::Systemd { user };
+        }
+        Manager::Pidfile
+    }
+}
+
+fn systemctl(user: bool, args: &[&str]) -> io::Result<std::process::Output> {
+    let mut command = Command::new("systemctl");
+    if user {
+        command.arg("--user");
+    }
+    command.args(args).stdin(Stdio::null()).output()
+}
+
+fn run_systemctl(user: bool, action: &str) -> Result<()> {
+    let output = systemctl(user, &[action, UNIT])?;
+    if !output.status.success() {
+        return Err(Error::Config(format!(
+            "systemctl {action} {UNIT} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+fn pid_path() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("zlorbrs-service.pid"))
+}
+
+fn service_log_path() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("zlorbrs-service.log"))
+}
+
+/// The service binary installed next to zlorbrs-ctl, or the one on PATH
+fn service_program() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("zlorbrs-service"))
+        .filter(|program| program.exists())
+        .unwrap_or_else(|| PathBuf::from("zlorbrs-service"))
+}
+
+/// .
+///
+/// # Starts the service
+///
+/// Through systemd when its unit is installed, otherwise detached from the
+/// terminal with its pid in `zlorbrs-service.pid` in the state directory and
+/// its output in `zlorbrs-service.log` next to it
+pub(crate) fn start() -> Result<()> {
+    match Manager::detect() {
+        Manager::Systemd { user } => {
+            run_systemctl(user, "start")?;
+            println!("Started {UNIT} through systemd");
+        }
+        Manager::Pidfile => {
+            let pid_path = pid_path()?;
+            if let Some(pid) = running_pid(&pid_path)? {
+                return Err(Error::Config(format!(
+                    "zlorbrs-service is already running with pid {pid}"
+                )));
+            }
+            let log_path = service_log_path()?;
+            let mut command = Command::new(service_program());
+            if let Some(root) = paths::root_from_flag() {
+                command.arg("--config-dir").arg(root);
+            } else if paths::system() {
+                command.arg("--system");
+            }
+            command.env(
+                "RUST_LOG",
+                env::var("RUST_LOG").unwrap_or_else(|_| String::from("zlorbrs_service")),
+            );
+            let pid = spawn_detached(command, &pid_path, &log_path)?;
+            wait_until_answering(pid, &log_path)?;
+            println!(
+                "Started zlorbrs-service with pid {pid}, logging to {}",
+                log_path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Stops the service, whichever way it was started
+pub(crate) fn stop() -> Result<()> {
+    match Manager::detect() {
+        Manager::Systemd { user } => {
+            run_systemctl(user, "stop")?;
+            println!("Stopped {UNIT} through systemd");
+        }
+        Manager::Pidfile => match stop_pid(&pid_path()?)? {
+            Some(pid) => println!("Stopped zlorbrs-service with pid {pid}"),
+            None => println!("zlorbrs-service isn't running"),
+        },
+    }
+    Ok(())
+}
+
+/// .
+///
+/// # Reports whether the service is running
+///
+/// Asks the service itself for its pid, uptime and config directory. When it
+/// doesn't answer, falls back to what systemd or the pidfile say. Returns
+/// `false` when the service isn't running
+pub(crate) fn status() -> Result<bool> {
+    match ipc::send(&Request::Status) {
+        Ok(Response::Status(status)) => {
+            print!("{}", control::format_status(&status, Utc::now()));
+            return Ok(true);
+        }
+        Ok(Response::Error { message }) => return Err(Error::Ipc(message)),
+        Ok(other) => return Err(Error::Ipc(format!("Unexpected answer {other:?}"))),
+        Err(e) => debug!("{e}"),
+    }
+
+    match Manager::detect() {
+        Manager::Systemd { user } => {
+            let output = systemctl(user, &["is-active", UNIT])?;
+            let state = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            println!("{UNIT} is {state} but isn't answering on its control socket");
+            Ok(output.status.success())
+        }
+        Manager::Pidfile => match running_pid(&pid_path()?)? {
+            Some(pid) => {
+                println!("zlorbrs-service is running with pid {pid} but isn't answering");
+                Ok(true)
+            }
+            None => {
+                println!("zlorbrs-service isn't running");
+                Ok(false)
+            }
+        },
+    }
+}
+
+/// .
+///
+/// # Runs `command` in the background
+///
+/// The child gets its own process group so it outlives the terminal, writes
+/// its output to `log_path` and has its pid recorded in `pid_path`
+fn spawn_detached(mut command: Command, pid_path: &Path, log_path: &Path) -> Result<u32> {
+    if let Some(dir) = pid_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let log = File::options().create(true).append(true).open(log_path)?;
+
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0)
+        .spawn()
+        .map_err(|e| Error::Config(format!("Failed to start {:?}: {e}", command.get_program())))?;
+
+    fs::write(pid_path, format!("{}\n", child.id()))?;
+    info!(
+        "Spawned {:?} with pid {}",
+        command.get_program(),
+        child.id()
+    );
+    Ok(child.id())
+}
+
+fn wait_until_answering(pid: u32, log_path: &Path) -> Result<()> {
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    while Instant::now() < deadline {
+        if !is_alive(pid) {
+            return Err(Error::Config(format!(
+                "zlorbrs-service exited right away, see {}",
+                log_path.display()
+            )));
+        }
+        if ipc::send(&Request::Status).is_ok() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Err(Error::Config(format!(
+        "zlorbrs-service (pid {pid}) didn't open its control socket in time, see {}",
+        log_path.display()
+    )))
+}
+
+/// Pid from `pid_path` if that process is still alive. A stale pidfile is removed
+fn running_pid(pid_path: &Path) -> Result<Option<u32>> {
+    let contents = match fs::read_to_string(pid_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match contents.trim().parse::<u32>() {
+        Ok(pid) if is_alive(pid) => Ok(Some(pid)),
+        _ => {
+            debug!("Removing stale pidfile {}", pid_path.display());
+            fs::remove_file(pid_path)?;
+            Ok(None)
+        }
+    }
+}
+
+/// .
+///
+/// # Stops the process recorded in `pid_path`
+///
+/// Sends SIGTERM and waits for the process to go away. Returns the pid that
+/// was stopped, or `None` when nothing was running
+fn stop_pid(pid_path: &Path) -> Result<Option<u32>> {
+    let Some(pid) = running_pid(pid_path)? else {
+        return Ok(None);
+    };
+
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    while is_alive(pid) {
+        if Instant::now() >= deadline {
+            return Err(Error::Config(format!(
+                "zlorbrs-service (pid {pid}) didn't stop within {WAIT_TIMEOUT:?}"
+            )));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    fs::remove_file(pid_path)?;
+    Ok(Some(pid))
+}
+
+fn is_alive(pid: u32) -> bool {
+    let pid = pid as libc::pid_t;
+    // reap the process if it happens to be our own child, otherwise it would
+    // linger as a zombie that still accepts signals
+    unsafe { libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) };
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(test_name: &str) -> PathBuf {
+        let mut dir = env::temp_dir();
+        dir.push(format!("zlorbrs_ctl_daemon_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_spawn_and_stop_detached() {
+        let dir = test_dir("spawn_stop");
+        let pid_path = dir.join("service.pid");
+        let log_path = dir.join("service.log");
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo started; exec sleep 30"]);
+        let pid = spawn_detached(command, &pid_path, &log_path).unwrap();
+        while fs::read_to_string(&log_path).unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(running_pid(&pid_path).unwrap(), Some(pid));
+        assert_eq!(stop_pid(&pid_path).unwrap(), Some(pid));
+        assert!(!pid_path.exists());
+        assert_eq!(stop_pid(&pid_path).unwrap(), None);
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "started\n");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stale_pidfile() {
+        let dir = test_dir("stale");
+        let pid_path = dir.join("service.pid");
+
+        // a process that already exited leaves a pid nobody is using
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        fs::write(&pid_path, child.id().to_string()).unwrap();
+
+        assert_eq!(running_pid(&pid_path).unwrap(), None);
+        assert!(!pid_path.exists());
+
+        fs::write(&pid_path, "not a pid").unwrap();
+        assert_eq!(running_pid(&pid_path).unwrap(), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,402 @@
+use serde_json::Value;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    process::{self, Command},
+};
+use zlorbrs_lib::{Error, Result, config::Config, format::Format};
+
+use crate::utils::control;
+
+/// .
+///
+/// # Edits a repo config in `$EDITOR`
+///
+/// Opens a copy of the config in `$VISUAL`, `$EDITOR` or `vi` and only
+/// saves it once it is a valid config, offering to edit again otherwise
+pub(crate) fn edit(repo_name: String) -> Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from("vi"));
+    let stdin = io::stdin();
+    if edit_with(&repo_name, &editor, &mut stdin.lock(), &mut io::stdout())? {
+        control::notify_reload();
+    }
+    Ok(())
+}
+
+/// .
+///
+/// # Changes repo config fields from the command line
+///
+/// Each assignment is `key=value`, where nested keys are joined with dots,
+/// e.g. `env.NODE_ENV=production` or `steps.0.shell=true`. Values are read
+/// as JSON when they parse, e.g. `true` or `["make", "dist"]`, and as plain
+/// strings otherwise
+pub(crate) fn set(repo_name: String, assignments: Vec<String>) -> Result<()> {
+    let config = apply(&repo_name, &assignments)?;
+    config.write()?;
+    println!("Updated {repo_name}");
+    control::notify_reload();
+    Ok(())
+}
+
+/// Runs the edit session, returning whether the config was changed
+fn edit_with(
+    repo_name: &str,
+    editor: &str,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<bool> {
+    let file_path = Config::file_path(repo_name)?;
+    if !file_path.exists() {
+        return Err(Error::Config(format!(
+            "Theres no config found with name: {repo_name}"
+        )));
+    }
+    let format = Format::from_path(&file_path).unwrap_or_default();
+    let original = fs::read_to_string(&file_path)?;
+    // JSON is pretty printed so it is pleasant to edit, TOML and YAML are
+    // edited as they are so their comments survive
+    let contents = match format {
+        Format::Json => match serde_json::from_str::<Value>(&original) {
+            Ok(value) => format.to_string_pretty(&value)?,
+            Err(_) => original,
+        },
+        _ => original,
+    };
+
+    // next to the config rather than in a shared temp directory, and only
+    // ever a new file of our own, so nobody can slip a symlink or their own
+    // file in there
+    let scratch_path =
+        file_path.with_file_name(format!(".edit-{}.{}", process::id(), format.extension()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&scratch_path)?
+        .write_all(contents.as_bytes())?;
+    let session = Session {
+        repo_name,
+        editor,
+        file_path: &file_path,
+        scratch_path: &scratch_path,
+        format,
+    };
+    let result = edit_until_valid(&session, &contents, input, output);
+    let _ = fs::remove_file(&scratch_path);
+    result
+}
+
+/// What an edit session works on
+struct Session<'a> {
+    repo_name: &'a str,
+    editor: &'a str,
+    file_path: &'a Path,    // the config itself
+    scratch_path: &'a Path, // the copy being edited
+    format: Format,
+}
+
+fn edit_until_valid(
+    session: &Session,
+    contents: &str,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<bool> {
+    loop {
+        run_editor(session.editor, session.scratch_path)?;
+        let edited = fs::read_to_string(session.scratch_path)?;
+        if edited == contents {
+            writeln!(output, "No changes made")?;
+            return Ok(false);
+        }
+
+        let problem = match parse(session.repo_name, session.format, &edited) {
+            Ok(_) => {
+                // saved as written, comments and all
+                fs::write(session.file_path, &edited)?;
+                writeln!(output, "Saved {}", session.file_path.display())?;
+                return Ok(true);
+            }
+            Err(e) => e,
+        };
+        writeln!(output, "{problem}")?;
+        write!(output, "Edit again? [Y/n] ")?;
+        output.flush()?;
+        let mut answer = String::new();
+        input.read_line(&mut answer)?;
+        if answer.trim().eq_ignore_ascii_case("n") {
+            writeln!(output, "Discarded the changes")?;
+            return Ok(false);
+        }
+    }
+}
+
+/// Runs `editor` on `path`. The editor may carry arguments, e.g. `code --wait`
+fn run_editor(editor: &str, path: &Path) -> Result<()> {
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("zlorbrs-ctl")
+        .arg(path)
+        .status()
+        .map_err(|e| Error::Config(format!("Failed to run {editor}: {e}")))?;
+    if !status.success() {
+        return Err(Error::Config(format!(
+            "{editor} exited with {status}, the config was left as it was"
+        )));
+    }
+    Ok(())
+}
+
+/// Parses an edited config, making sure it still describes `repo_name`
+fn parse(repo_name: &str, format: Format, contents: &str) -> Result<Config> {
+    let config = Config::parse(format, contents)
+        .map_err(|e| Error::Config(format!("Invalid config: {e}")))?;
+    check(repo_name, config)
+}
+
+fn check(repo_name: &str, config: Config) -> Result<Config> {
+    if config.name != repo_name {
+        return Err(Error::Config(format!(
+            "The name can't be changed, it has to stay {repo_name:?}"
+        )));
+    }
+    config.build_dir()?;
+    config
+        .build_steps()
+        .iter()
+        .try_for_each(|step| step.command.to_command(step.shell).map(|_| ()))?;
+    Ok(config)
+}
+
+fn apply(repo_name: &str, assignments: &[String]) -> Result<Config> {
+    let config = Config::from_name(repo_name)?;
+    let mut value = serde_json::to_value(&config)?;
+
+    for assignment in assignments {
+        let (key, raw) = assignment
+            .split_once('=')
+            .ok_or_else(|| Error::Config(format!("Expected key=value, got {assignment:?}")))?;
+        let new_value =
+            serde_json::from_str(raw).unwrap_or_else(|_| Value::String(String::from(raw)));
+        set_key(&mut value, key, new_value)?;
+    }
+
+    let config: Config =
+        serde_json::from_value(value).map_err(|e| Error::Config(format!("Invalid config: {e}")))?;
+    check(repo_name, config)
+}
+
+/// Sets the dotted `key` inside `value`, creating objects along the way
+fn set_key(value: &mut Value, key: &str, new_value: Value) -> Result<()> {
+    let mut current = value;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        let last = parts.peek().is_none();
+        current = match current {
+            Value::Object(map) if last => {
+                map.insert(String::from(part), new_value);
+                return Ok(());
+            }
+            Value::Object(map) => map
+                .entry(part)
+                .or_insert_with(|| Value::Object(Default::default())),
+            Value::Array(items) => {
+                let index: usize = part
+                    .parse()
+                    .map_err(|_| Error::Config(format!("{key}: {part:?} is not a list index")))?;
+                let len = items.len();
+                let item = items.get_mut(index).ok_or_else(|| {
+                    Error::Config(format!(
+                        "{key}: index {index} is out of range, there are {len}"
+                    ))
+                })?;
+                if last {
+                    *item = new_value;
+                    return Ok(());
+                }
+                item
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "{key}: {part:?} is inside something that isn't an object or list"
+                )));
+            }
+        };
+    }
+    Err(Error::Config(String::from(
+        "Expected key=value, got an empty key",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use zlorbrs_lib::config::{BuildCommand, BuildStep};
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
+
+    struct TestEnv {
+        home_dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.home_dir);
+        }
+    }
+
+    fn setup_test_env(test_name: &str) -> TestEnv {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push(format!("zlorbrs_ctl_edit_{}", test_name));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        Config {
+            name: String::from("test_repo"),
+            path: home_dir.to_str().unwrap().to_string(),
+            branch: String::from("main"),
+            remote: String::from("origin"),
+            build_command: BuildCommand::from("bun run build"),
+            steps: vec![BuildStep {
+                name: Some(String::from("install")),
+                command: BuildCommand::from("bun install"),
+                shell: false,
+            }],
+            ..Default::default()
+        }
+        .write()
+        .unwrap();
+
+        TestEnv {
+            home_dir,
+            _lock: lock,
+        }
+    }
+
+    #[test]
+    fn test_set() {
+        let _env = setup_test_env("set");
+        set(
+            String::from("test_repo"),
+            vec![
+                String::from("branch=release"),
+                String::from("env.NODE_ENV=production"),
+                String::from("steps.0.shell=true"),
+                String::from(r#"build_command=["make","dist"]"#),
+            ],
+        )
+        .unwrap();
+
+        let config = Config::from_name("test_repo").unwrap();
+        assert_eq!(config.branch, "release");
+        assert_eq!(config.env["NODE_ENV"], "production");
+        assert!(config.steps[0].shell);
+        assert_eq!(
+            config.build_command,
+            BuildCommand::Args(vec![String::from("make"), String::from("dist")])
+        );
+    }
+
+    #[test]
+    fn test_set_rejects_invalid() {
+        let _env = setup_test_env("set_invalid");
+        let before = fs::read_to_string(Config::file_path("test_repo").unwrap()).unwrap();
+
+        for assignment in [
+            "branch",             // no value
+            "buidl_command=make", // typo
+            "shell=maybe",        // wrong type
+            "name=other",         // renaming
+            "working_dir=../outside",
+            "steps.3.shell=true",
+            "branch.x=y",
+        ] {
+            let result = set(String::from("test_repo"), vec![String::from(assignment)]);
+            assert!(matches!(result, Err(Error::Config(_))), "{assignment}");
+        }
+        assert!(set(String::from("nope"), vec![String::from("branch=x")]).is_err());
+
+        let after = fs::read_to_string(Config::file_path("test_repo").unwrap()).unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_edit() {
+        let _env = setup_test_env("edit");
+        let mut output = Vec::new();
+        let changed = edit_with(
+            "test_repo",
+            "sed -i 's/\"main\"/\"trunk\"/'",
+            &mut io::empty(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert!(changed);
+        assert_eq!(Config::from_name("test_repo").unwrap().branch, "trunk");
+
+        let changed = edit_with("test_repo", "true", &mut io::empty(), &mut output).unwrap();
+        assert!(!changed);
+        assert!(
+            String::from_utf8(output)
+                .unwrap()
+                .ends_with("No changes made\n")
+        );
+    }
+
+    #[test]
+    fn test_edit_invalid_is_discarded() {
+        let _env = setup_test_env("edit_invalid");
+        let before = fs::read_to_string(Config::file_path("test_repo").unwrap()).unwrap();
+
+        // the editor breaks the file twice, the first time we ask for another go
+        let mut input = io::Cursor::new("y\nn\n");
+        let mut output = Vec::new();
+        let changed = edit_with("test_repo", "echo '{ broken' >", &mut input, &mut output).unwrap();
+
+        assert!(!changed);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("Invalid config").count(), 2);
+        assert!(output.ends_with("Discarded the changes\n"));
+        let after = fs::read_to_string(Config::file_path("test_repo").unwrap()).unwrap();
+        assert_eq!(before, after);
+
+        assert!(edit_with("test_repo", "false", &mut io::empty(), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_edit_keeps_format() {
+        let env = setup_test_env("edit_toml");
+        let config = Config::from_name("test_repo").unwrap();
+        config.write_as(Format::Toml).unwrap();
+        let file_path = Config::file_path("test_repo").unwrap();
+        assert_eq!(file_path, env.home_dir.join(".config/zlorbrs/configs/test_repo/config.toml"));
+        let contents = fs::read_to_string(&file_path).unwrap();
+        fs::write(&file_path, format!("# deployed by hand\n{contents}")).unwrap();
+
+        let mut output = Vec::new();
+        let changed = edit_with(
+            "test_repo",
+            "sed -i 's/\"main\"/\"trunk\"/'",
+            &mut io::empty(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert!(changed);
+        assert_eq!(Config::from_name("test_repo").unwrap().branch, "trunk");
+        assert!(fs::read_to_string(&file_path).unwrap().starts_with("# deployed by hand\n"));
+    }
+}
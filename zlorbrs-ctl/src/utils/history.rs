@@ -0,0 +1,95 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use zlorbrs_lib::{
+    Result,
+    history::{self, BuildRecord, Filter},
+};
+
+/// .
+///
+/// # Prints a repo's build history
+///
+/// Newest build first, limited to the builds matching `filter`
+pub(crate) fn show(repo_name: String, filter: Filter) -> Result<()> {
+    let records = history::query(&repo_name, &filter)?;
+    if records.is_empty() {
+        println!("No builds recorded for {repo_name}");
+        return Ok(());
+    }
+
+    println!(
+        "{:<6} {:<20} {:<9} {:<18} {:>9}  COMMITS",
+        "BUILD", "STARTED", "STATUS", "TRIGGER", "DURATION"
+    );
+    for record in records {
+        println!("{}", format_record(&record));
+    }
+    Ok(())
+}
+
+fn format_record(record: &BuildRecord) -> String {
+    let short = |oid: &Option<String>| match oid {
+        Some(oid) => oid.chars().take(7).collect(),
+        None => String::from("-"),
+    };
+    format!(
+        "{:<6} {:<20} {:<9} {:<18} {:>8.1}s  {}..{}",
+        format!("#{}", record.id),
+        record.started_at.format("%Y-%m-%d %H:%M:%S"),
+        record.status.to_string(),
+        record.trigger.to_string(),
+        record.duration_ms as f64 / 1000.0,
+        short(&record.old_oid),
+        short(&record.new_oid),
+    )
+}
+
+/// Parses `--since` as an RFC 3339 timestamp or a plain `YYYY-MM-DD` date
+pub(crate) fn parse_since(value: &str) -> std::result::Result<DateTime<Utc>, String> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        .map_err(|_| format!("expected YYYY-MM-DD or an RFC 3339 timestamp, got {value:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use zlorbrs_lib::history::{BuildStatus, Trigger};
+
+    #[test]
+    fn test_format_record() {
+        let record = BuildRecord {
+            id: 12,
+            repo: String::from("test_repo"),
+            trigger: Trigger::NewCommit,
+            old_oid: Some(String::from("0123456789abcdef")),
+            new_oid: None,
+            started_at: Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap(),
+            duration_ms: 1500,
+            status: BuildStatus::Failed,
+            exit_code: Some(1),
+            failed_step: None,
+            error: None,
+        };
+
+        let line = format_record(&record);
+        assert!(line.starts_with("#12    2025-01-02 03:04:05  failed    new_commit"));
+        assert!(line.ends_with("1.5s  0123456..-"));
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(
+            parse_since("2025-01-02").unwrap(),
+            Utc.with_ymd_and_hms(2025, 1, 2, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_since("2025-01-02T10:00:00+02:00").unwrap(),
+            Utc.with_ymd_and_hms(2025, 1, 2, 8, 0, 0).unwrap()
+        );
+        assert!(parse_since("yesterday").is_err());
+    }
+}
@@ -0,0 +1,388 @@
+use chrono::{DateTime, Local, Utc};
+use git2::{BranchType, Repository};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+use zlorbrs_lib::{
+    Error, Result,
+    config::Config,
+    history::{self, BuildStatus},
+    ipc::{self, Request, Response, ServiceStatus},
+};
+
+use crate::utils::repo;
+
+/// Everything `zlorbrs-ctl list` knows about one repo. Also the shape of
+/// `--json` and the placeholders `--format` accepts
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct RepoSummary {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) branch: String,
+    pub(crate) remote: String,
+    pub(crate) last_checked: Option<DateTime<Utc>>, // only known while the service runs
+    pub(crate) last_build: Option<u64>,
+    pub(crate) last_build_status: Option<BuildStatus>,
+    pub(crate) last_build_at: Option<DateTime<Utc>>,
+    pub(crate) local_commit: Option<String>,
+    pub(crate) remote_commit: Option<String>, // as of the last fetch
+    pub(crate) error: Option<String>,         // why the config or repository couldn't be read
+}
+
+/// .
+///
+/// # Lists the configured repos
+///
+/// As a table by default, as a JSON array with `json`, or one line per repo
+/// following `format`, e.g. `"{name} {last_build_status}"`
+pub(crate) fn list(json: bool, format: Option<String>) -> Result<()> {
+    let service = match ipc::send(&Request::Status) {
+        Ok(Response::Status(status)) => Some(status),
+        _ => None,
+    };
+
+    let mut config_dirs = Vec::new();
+    for (_, entry) in repo::get_all()? {
+        config_dirs.push(entry?.path());
+    }
+    config_dirs.sort();
+    let summaries: Vec<RepoSummary> = config_dirs
+        .iter()
+        .map(|dir| summarize(dir, service.as_ref()))
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+    } else if let Some(format) = format {
+        for summary in &summaries {
+            println!("{}", render(&format, summary)?);
+        }
+    } else if summaries.is_empty() {
+        println!("No repos configured yet, add one with `zlorbrs-ctl add`");
+    } else {
+        print!("{}", format_table(&summaries));
+    }
+    Ok(())
+}
+
+/// Gathers what is known about the repo configured in `config_dir`. Problems
+/// end up in `error` so one broken repo doesn't hide the others
+fn summarize(config_dir: &Path, service: Option<&ServiceStatus>) -> RepoSummary {
+    let name = config_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut summary = RepoSummary {
+        last_checked: service
+            .and_then(|status| status.repos.iter().find(|repo| repo.name == name))
+            .and_then(|repo| repo.last_checked),
+        name,
+        ..Default::default()
+    };
+
+    match history::read(&summary.name) {
+        Ok(records) => {
+            if let Some(record) = records.last() {
+                summary.last_build = Some(record.id);
+                summary.last_build_status = Some(record.status);
+                summary.last_build_at = Some(record.started_at);
+            }
+        }
+        Err(e) => summary.error = Some(e.to_string()),
+    }
+
+    let config = match Config::from_dir(config_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            summary.error = Some(e.to_string());
+            return summary;
+        }
+    };
+    summary.path = config.path.clone();
+    summary.branch = config.branch.clone();
+    summary.remote = config.remote.clone();
+
+    let repo = match Repository::open(&config.path) {
+        Ok(repo) => repo,
+        Err(e) => {
+            summary.error = Some(Error::from(e).to_string());
+            return summary;
+        }
+    };
+    summary.local_commit = repo
+        .find_branch(&config.branch, BranchType::Local)
+        .ok()
+        .and_then(|branch| branch.get().target())
+        .map(|oid| oid.to_string());
+    summary.remote_commit = repo
+        .resolve_reference_from_short_name(&format!("{}/{}", config.remote, config.branch))
+        .ok()
+        .and_then(|reference| reference.target())
+        .map(|oid| oid.to_string());
+    summary
+}
+
+/// .
+///
+/// # Fills in a `--format` template
+///
+/// `{field}` is replaced with that field of the summary, or `-` when it is
+/// unknown. Errors on fields that don't exist
+pub(crate) fn render(format: &str, summary: &RepoSummary) -> Result<String> {
+    let fields = match serde_json::to_value(summary)? {
+        Value::Object(fields) => fields,
+        _ => return Err(Error::Config(String::from("Repo summary isn't an object"))),
+    };
+
+    let mut out = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| Error::Config(format!("Unclosed {{ in format {format:?}")))?
+            + start;
+        let key = &rest[start + 1..end];
+        match fields.get(key) {
+            Some(Value::String(value)) => out.push_str(value),
+            Some(Value::Null) => out.push('-'),
+            Some(value) => out.push_str(&value.to_string()),
+            None => {
+                let known: Vec<&str> = fields.keys().map(String::as_str).collect();
+                return Err(Error::Config(format!(
+                    "Unknown field {{{key}}} in format, expected one of {}",
+                    known.join(", ")
+                )));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn format_table(summaries: &[RepoSummary]) -> String {
+    let short = |oid: &Option<String>| match oid {
+        Some(oid) => oid.chars().take(7).collect(),
+        None => String::from("-"),
+    };
+    let time = |at: &Option<DateTime<Utc>>| match at {
+        Some(at) => at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+        None => String::from("-"),
+    };
+
+    let header = [
+        "NAME",
+        "PATH",
+        "BRANCH",
+        "REMOTE",
+        "LAST CHECKED",
+        "LAST BUILD",
+        "LOCAL",
+        "REMOTE HEAD",
+    ]
+    .map(String::from);
+    let mut rows = vec![(header, None)];
+    for summary in summaries {
+        let last_build = match (summary.last_build, summary.last_build_status) {
+            (Some(id), Some(status)) => format!("#{id} {status}"),
+            _ => String::from("-"),
+        };
+        let row = [
+            summary.name.clone(),
+            summary.path.clone(),
+            summary.branch.clone(),
+            summary.remote.clone(),
+            time(&summary.last_checked),
+            last_build,
+            short(&summary.local_commit),
+            short(&summary.remote_commit),
+        ];
+        rows.push((row, summary.error.as_ref()));
+    }
+
+    let mut widths = [0; 8];
+    for (row, _) in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for (row, error) in &rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        if let Some(error) = error {
+            out.push_str(&format!("  ({error})"));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use zlorbrs_lib::config::BuildCommand;
+    use zlorbrs_lib::history::{BuildRecord, Trigger};
+    use zlorbrs_lib::ipc::RepoStatus;
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
+
+    struct TestEnv {
+        home_dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.home_dir);
+        }
+    }
+
+    fn setup_test_env(test_name: &str) -> TestEnv {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push(format!("zlorbrs_ctl_list_{}", test_name));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        TestEnv {
+            home_dir,
+            _lock: lock,
+        }
+    }
+
+    fn summary() -> RepoSummary {
+        RepoSummary {
+            name: String::from("site"),
+            path: String::from("/srv/site"),
+            branch: String::from("main"),
+            remote: String::from("origin"),
+            last_build: Some(7),
+            last_build_status: Some(BuildStatus::Failed),
+            local_commit: Some(String::from("0123456789abcdef")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let line = render(
+            "{name}@{branch}: #{last_build} {last_build_status} {remote_commit}",
+            &summary(),
+        )
+        .unwrap();
+        assert_eq!(line, "site@main: #7 failed -");
+
+        assert!(matches!(
+            render("{nope}", &summary()),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(render("{name", &summary()), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_format_table() {
+        let mut broken = RepoSummary {
+            name: String::from("broken"),
+            ..Default::default()
+        };
+        broken.error = Some(String::from("config error: nope"));
+
+        let table = format_table(&[summary(), broken]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(
+            lines[0].starts_with("NAME    PATH       BRANCH  REMOTE  LAST CHECKED  LAST BUILD")
+        );
+        assert!(lines[1].starts_with(
+            "site    /srv/site  main    origin  -             #7 failed   0123456  -"
+        ));
+        assert!(lines[2].starts_with("broken"));
+        assert!(lines[2].ends_with("-  (config error: nope)"));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let env = setup_test_env("summarize");
+        let repo_dir = env.home_dir.join("site");
+        let repo = Repository::init(&repo_dir).unwrap();
+        let signature = git2::Signature::now("Test User", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let head = repo
+            .commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+        repo.reference(
+            &format!("refs/remotes/origin/{branch}"),
+            head,
+            false,
+            "test",
+        )
+        .unwrap();
+
+        Config {
+            name: String::from("site"),
+            path: repo_dir.to_str().unwrap().to_string(),
+            branch,
+            remote: String::from("origin"),
+            build_command: BuildCommand::from("true"),
+            ..Default::default()
+        }
+        .write()
+        .unwrap();
+        let started_at = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        history::append(&BuildRecord {
+            id: 3,
+            repo: String::from("site"),
+            trigger: Trigger::Manual,
+            old_oid: None,
+            new_oid: None,
+            started_at,
+            duration_ms: 10,
+            status: BuildStatus::Success,
+            exit_code: Some(0),
+            failed_step: None,
+            error: None,
+        })
+        .unwrap();
+        let service = ServiceStatus {
+            pid: 1,
+            started_at,
+            config_dir: String::new(),
+            repos: vec![RepoStatus {
+                name: String::from("site"),
+                last_checked: Some(started_at),
+                ..Default::default()
+            }],
+        };
+
+        let config_dir = env.home_dir.join(".config/zlorbrs/configs/site");
+        let summary = summarize(&config_dir, Some(&service));
+        assert_eq!(summary.error, None);
+        assert_eq!(summary.local_commit, Some(head.to_string()));
+        assert_eq!(summary.remote_commit, Some(head.to_string()));
+        assert_eq!(summary.last_build, Some(3));
+        assert_eq!(summary.last_build_status, Some(BuildStatus::Success));
+        assert_eq!(summary.last_checked, Some(started_at));
+
+        let missing = summarize(&env.home_dir.join(".config/zlorbrs/configs/gone"), None);
+        assert_eq!(missing.name, "gone");
+        assert!(missing.error.is_some());
+    }
+}
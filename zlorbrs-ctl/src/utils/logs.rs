@@ -0,0 +1,133 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+use zlorbrs_lib::{Error, Result, logs};
+
+/// How often `--follow` checks the log for new output
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// .
+///
+/// # Prints a build log
+///
+/// Shows build `build` of `repo_name`, or its latest build when omitted.
+/// With `follow` keeps printing new output as it is written, like `tail -f`,
+/// until the build is done with its log
+pub(crate) fn show(repo_name: String, build: Option<u64>, follow: bool) -> Result<()> {
+    let logs = logs::list(&repo_name)?;
+    let path = match build {
+        Some(id) => logs.into_iter().find(|(log_id, _)| *log_id == id),
+        None => logs.into_iter().last(),
+    };
+
+    let (_, path) = match path {
+        Some(found) => found,
+        None => {
+            return Err(Error::Config(match build {
+                Some(id) => format!("There is no log for build {id} of {repo_name}"),
+                None => format!("{repo_name} has no build logs yet"),
+            }));
+        }
+    };
+    tail(&path, &mut io::stdout(), follow)
+}
+
+fn tail(path: &Path, out: &mut dyn Write, follow: bool) -> Result<()> {
+    let mut file = File::open(path)?;
+    loop {
+        // asked before copying, so what was written right before the log
+        // closed is still printed
+        let open = follow && logs::is_being_written(path)?;
+        // picks up where the previous copy stopped
+        io::copy(&mut file, out)?;
+        out.flush()?;
+        if !open {
+            return Ok(());
+        }
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use zlorbrs_lib::logs::{BuildLog, Retention};
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
+
+    struct TestEnv {
+        home_dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.home_dir);
+        }
+    }
+
+    fn setup_test_env(test_name: &str) -> TestEnv {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push(format!("zlorbrs_ctl_logs_{}", test_name));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        TestEnv {
+            home_dir,
+            _lock: lock,
+        }
+    }
+
+    #[test]
+    fn test_tail_follows_until_the_log_is_closed() {
+        let _env = setup_test_env("tail");
+        let mut log = BuildLog::create("test_repo", Retention::default()).unwrap();
+        log.write_all(b"first\n").unwrap();
+
+        let path = log.path.clone();
+        let (done, followed) = mpsc::channel();
+        thread::spawn(move || {
+            let mut out = Vec::new();
+            tail(&path, &mut out, true).unwrap();
+            done.send(out).unwrap();
+        });
+
+        // still following while the build writes
+        thread::sleep(FOLLOW_INTERVAL * 2);
+        log.write_all(b"second\n").unwrap();
+        assert!(followed.recv_timeout(FOLLOW_INTERVAL * 2).is_err());
+
+        // and done once the build is
+        drop(log);
+        let out = followed.recv_timeout(FOLLOW_INTERVAL * 4).unwrap();
+        assert_eq!(out, b"first\nsecond\n");
+
+        let path = logs::log_path("test_repo", 1).unwrap();
+        let mut out = Vec::new();
+        tail(&path, &mut out, false).unwrap();
+        assert_eq!(out, b"first\nsecond\n");
+    }
+
+    #[test]
+    fn test_show_missing_logs() {
+        let _env = setup_test_env("missing");
+        assert!(show(String::from("test_repo"), None, false).is_err());
+
+        BuildLog::create("test_repo", Retention::default()).unwrap();
+        assert!(show(String::from("test_repo"), None, false).is_ok());
+        assert!(show(String::from("test_repo"), Some(1), false).is_ok());
+        assert!(show(String::from("test_repo"), Some(7), false).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{self, Write};
+
+use log::error;
+use zlorbrs_lib::{Error, Result, migrate};
+
+use crate::utils::control;
+
+/// .
+///
+/// # Upgrades configs written by an older zlorbrs
+///
+/// Every repo config and the service config is brought up to the current
+/// version, keeping a backup of each file next to it. With `dry_run` it only
+/// shows what would change
+pub(crate) fn migrate(dry_run: bool) -> Result<()> {
+    if migrate_with(dry_run, &mut io::stdout())? && !dry_run {
+        control::notify_reload();
+    }
+    Ok(())
+}
+
+/// Runs the migration, returning whether any config needed one
+fn migrate_with(dry_run: bool, output: &mut impl Write) -> Result<bool> {
+    let mut migrated = 0;
+    let mut failed = 0;
+    for migration in migrate::migrate_all(dry_run)? {
+        let migration = match migration {
+            Ok(migration) => migration,
+            Err(e) => {
+                error!("{e}");
+                failed += 1;
+                continue;
+            }
+        };
+        migrated += 1;
+        match &migration.backup {
+            Some(backup) => writeln!(
+                output,
+                "Upgraded {}, the old file is at {}",
+                migration.path.display(),
+                backup.display()
+            )?,
+            None => writeln!(output, "Would upgrade {}", migration.path.display())?,
+        }
+        for change in &migration.changes {
+            writeln!(output, "  {change}")?;
+        }
+        if dry_run {
+            writeln!(output, "  new contents: {}", migration.contents)?;
+        }
+    }
+
+    if failed > 0 {
+        return Err(Error::Config(format!(
+            "{failed} config(s) couldn't be migrated"
+        )));
+    }
+    if migrated == 0 {
+        writeln!(output, "All configs are up to date")?;
+    }
+    Ok(migrated > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use zlorbrs_lib::config::{CONFIG_VERSION, Config};
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
+
+    #[test]
+    fn test_migrate_dry_run() {
+        let _lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push("zlorbrs_ctl_migrate_dry_run");
+        let _ = fs::remove_dir_all(&home_dir);
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+        let config_dir = home_dir.join(".config/zlorbrs/configs/my-repo");
+        fs::create_dir_all(&config_dir).unwrap();
+        let old_contents = r#"{"name":"my-repo","path":"/srv/my-repo","branch":"main","remote":"origin","build_command":"make"}"#;
+        fs::write(config_dir.join("config.json"), old_contents).unwrap();
+
+        let mut output = Vec::new();
+        assert!(migrate_with(true, &mut output).unwrap());
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Would upgrade"));
+        assert!(output.contains("version: 0 -> 1"));
+        assert_eq!(
+            fs::read_to_string(config_dir.join("config.json")).unwrap(),
+            old_contents
+        );
+
+        let mut output = Vec::new();
+        assert!(migrate_with(false, &mut output).unwrap());
+        assert!(String::from_utf8(output).unwrap().contains("Upgraded"));
+        assert_eq!(
+            Config::from_name("my-repo").unwrap().version,
+            CONFIG_VERSION
+        );
+
+        let mut output = Vec::new();
+        assert!(!migrate_with(false, &mut output).unwrap());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "All configs are up to date\n"
+        );
+
+        let _ = fs::remove_dir_all(&home_dir);
+    }
+}
@@ -1,2 +1,10 @@
+pub mod build;
+pub mod check;
+pub mod control;
 pub mod daemon;
+pub mod edit;
+pub mod history;
+pub mod list;
+pub mod logs;
+pub mod migrate;
 pub mod repo;
@@ -2,9 +2,17 @@ use log::{error, info};
 use std::{
     env,
     fs::{self, ReadDir},
+    io::{self, BufRead, Write},
     iter::Enumerate,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use zlorbrs_lib::{
+    Error, Result,
+    config::{self, BuildCommand, Config},
+    format::Format,
+    paths, users,
 };
-use zlorbrs_lib::{config::Config, get_home_dir};
 
 /// .
 ///
@@ -12,137 +20,217 @@ use zlorbrs_lib::{config::Config, get_home_dir};
 ///
 /// Looks in the repo config directory and if `repo_name` is
 /// found, will attempt to delete  the repo config
-pub(crate) fn remove(repo_name: String) {
-    let repos = self::get_all();
-    if repos.is_none() {
-        error!("There are no repos to remove");
-        return;
-    }
-    let repos = repos.unwrap();
-    let mut mapped_repos = repos.map(|item| {
-        let x = item.1;
-        if x.is_err() {
-            error!("Failed to map repos to good type.. aborting");
-            panic!("Panic due to previous error");
-        }
-        let x = x.unwrap();
-        x.path()
-    });
-
-    let found = mapped_repos.find(|item| {
-        let file_name = item.file_name();
-        if file_name.is_none() {
-            return false;
-        }
-        let file_name = file_name.unwrap();
-        let file_name = file_name.to_str();
-        if file_name.is_none() {
-            return false;
-        }
-        let file_name = file_name.unwrap();
-        return file_name == &repo_name;
-    });
-
-    if found.is_none() {
-        error!("Theres no config found with name: {}", repo_name);
-        return;
-    }
-    let found = found.unwrap();
-    match std::fs::remove_dir_all(found) {
-        Ok(_) => {
-            info!("Removed config for: {}", repo_name);
+pub(crate) fn remove(repo_name: String) -> Result<()> {
+    let mut found = None;
+    for (_, entry) in self::get_all()? {
+        let path = entry?.path();
+        if path.file_name().and_then(|name| name.to_str()) == Some(repo_name.as_str()) {
+            found = Some(path);
+            break;
         }
-        Err(e) => {
-            error!("Unable to remove config for {} because: {:?}", repo_name, e);
+    }
+
+    let found = match found {
+        Some(found) => found,
+        None => {
+            return Err(Error::Config(format!(
+                "Theres no config found with name: {repo_name}"
+            )));
         }
     };
+    fs::remove_dir_all(found)?;
+    info!("Removed config for: {}", repo_name);
+    Ok(())
 }
 
 /// .
 ///
-/// # Panics
+/// # Lists the repo config directories
 ///
-/// Panics if .
-pub(crate) fn get_all() -> Option<Enumerate<ReadDir>> {
-    let home_dir = get_home_dir();
+/// Creates the config directory first if it doesn't exist yet
+pub(crate) fn get_all() -> Result<Enumerate<ReadDir>> {
+    let config_dir = paths::configs_dir()?;
 
-    let config_dir = format!("{}/.config/zlorbrs/configs", home_dir);
-
-    if let Ok(dir) = fs::read_dir(config_dir.clone()) {
-        return Some(dir.enumerate());
+    if let Ok(dir) = fs::read_dir(&config_dir) {
+        return Ok(dir.enumerate());
     }
 
     error!("Config directory doesnt exist. Creating it now...");
-    let create_dir_results = fs::create_dir_all(config_dir.clone());
-    if let Ok(_) = create_dir_results {
-        let files = fs::read_dir(config_dir);
-        if files.is_err() {
-            error!(
-                "Failed to create config directory for reason: {}",
-                files.err().unwrap()
-            );
-            panic!("Exiting due to previous error")
-        }
-        let files_unwrapped = files.unwrap();
-        return Some(files_unwrapped.enumerate());
-    }
+    fs::create_dir_all(&config_dir)?;
+    Ok(fs::read_dir(config_dir)?.enumerate())
+}
 
-    error!(
-        "Failed to create config directory for reason: {}",
-        create_dir_results.err().unwrap()
-    );
-    panic!("Exiting due to previous error")
+/// What `zlorbrs-ctl add` was told. Anything left out is detected from
+/// the repository
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AddOptions {
+    pub(crate) name: Option<String>, // defaults to the repository's directory name
+    pub(crate) path: Option<PathBuf>, // defaults to the current directory
+    pub(crate) branch: Option<String>,
+    pub(crate) remote: Option<String>,
+    pub(crate) build_command: Option<String>,
+    pub(crate) interactive: bool, // ask for every field, suggesting the above
+    pub(crate) config_format: Format, // what the config file is written as
 }
 
 /// .
 ///
-/// # Panics
+/// # Adds a repo
 ///
-/// Panics if .
-pub(crate) fn add() {
-    let current_dir_pathbuf = env::current_dir().unwrap();
+/// Fills in whatever `options` leaves out from the repository itself, asks
+/// for every field when interactive and saves the config once it checks out
+pub(crate) fn add(options: AddOptions) -> Result<()> {
+    let stdin = io::stdin();
+    let config_format = options.config_format;
+    let config = build_config(options, &mut stdin.lock(), &mut io::stdout())?;
+    validate_new(&config)?;
+
+    let file_path = config.write_as(config_format)?;
+    println!("Added {} at {}", config.name, file_path.display());
+    Ok(())
+}
 
-    let dir_name = current_dir_pathbuf.file_name();
-    if dir_name.is_none() {
-        error!("Failed to get current directory name");
-        return;
+/// Works out the config `add` would save, prompting through `input` and
+/// `output` when interactive
+fn build_config(
+    options: AddOptions,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<Config> {
+    let mut path = match options.path {
+        Some(path) => path,
+        None => env::current_dir()?,
+    };
+    if options.interactive {
+        path = PathBuf::from(prompt(input, output, "Path", &path.to_string_lossy())?);
     }
+    let path = path
+        .canonicalize()
+        .map_err(|e| Error::Config(format!("{}: {e}", path.display())))?;
+    config::trust_configured_repos(Some(&path))?;
+    let repo = git2::Repository::open(&path)
+        .map_err(|_| Error::Config(format!("{} is not a git repository", path.display())))?;
+
+    let name = match options.name {
+        Some(name) => name,
+        None => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| Error::Config(format!("{} has no directory name", path.display())))?,
+    };
+    let remote = match options.remote {
+        Some(remote) => remote,
+        None => config::detect_remote(&repo)?,
+    };
+    let branch = match options.branch {
+        Some(branch) => branch,
+        // when asking anyway, an undetectable branch just has no suggestion
+        None if options.interactive => config::detect_branch(&repo, &remote).unwrap_or_default(),
+        None => config::detect_branch(&repo, &remote)?,
+    };
+    let build_command = options
+        .build_command
+        .unwrap_or_else(|| String::from(config::DEFAULT_BUILD_COMMAND));
+
+    let mut config = Config {
+        name,
+        path: path.to_string_lossy().into_owned(),
+        branch,
+        remote,
+        build_command: BuildCommand::from(build_command.as_str()),
+        build_user: default_build_user(&path, paths::system())?,
+        ..Default::default()
+    };
 
-    let current_configs = self::get_all();
-    if current_configs.is_none() {
-        error!("The configs directory contains nothing");
-        return;
+    if options.interactive {
+        config.name = prompt(input, output, "Name", &config.name)?;
+        config.remote = prompt(input, output, "Remote", &config.remote)?;
+        config.branch = prompt(input, output, "Branch", &config.branch)?;
+        let build_command = prompt(
+            input,
+            output,
+            "Build command",
+            &config.build_command.to_string(),
+        )?;
+        config.build_command = BuildCommand::from(build_command.as_str());
     }
+    Ok(config)
+}
 
-    let found_config = current_configs
-        .unwrap()
-        .find(|x| x.1.as_ref().unwrap().file_name() == dir_name.unwrap());
-
-    if found_config.is_some() {
-        error!(
-            "{:?} is already configured. If you want to edit the configuration file, you can find it at HOME/zlorbrs/configs/{:?}",
-            dir_name, dir_name
-        );
-        return;
+/// Who builds of the repository at `path` run as. With `--system` that is
+/// whoever owns it, as the service itself runs as root, unless that is root
+/// too
+fn default_build_user(path: &Path, system: bool) -> Result<Option<String>> {
+    if !system {
+        return Ok(None);
+    }
+    let uid = fs::metadata(path)?.uid();
+    if uid == 0 {
+        return Ok(None);
     }
+    // a uid without a name still works as build_user
+    Ok(Some(match users::user(&uid.to_string()) {
+        Ok(account) => account.name,
+        Err(_) => uid.to_string(),
+    }))
+}
 
-    let _ = Config::load(String::from(dir_name.unwrap().to_str().unwrap()));
+/// Asks for `label`, taking `default` when the answer is empty
+fn prompt(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    label: &str,
+    default: &str,
+) -> Result<String> {
+    write!(output, "{label} [{default}]: ")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(String::from(if answer.is_empty() {
+        default
+    } else {
+        answer
+    }))
 }
 
 /// .
 ///
-/// # Panics
+/// # Checks a config before it is added
 ///
-/// Panics if .
-pub(crate) fn list() {
-    let repos = self::get_all();
-    if repos.is_none() {
-        error!("No configurations found");
-        return;
+/// The name has to be unique and usable as a directory name, and the branch
+/// and remote have to exist in the repository. Reports every problem at once
+fn validate_new(config: &Config) -> Result<()> {
+    let mut problems = Vec::new();
+
+    if config.name.is_empty() || config.name.contains('/') || config.name.starts_with('.') {
+        problems.push(format!("{:?} is not a valid name", config.name));
+    } else if Config::exists(&config.name)? {
+        problems.push(format!(
+            "{:?} is already configured. Change it with `zlorbrs-ctl edit {}` or in {}",
+            config.name,
+            config.name,
+            Config::file_path(&config.name)?.display()
+        ));
+    }
+    if !config.branch_exists()? {
+        problems.push(format!(
+            "Branch {:?} doesn't exist in {}",
+            config.branch, config.path
+        ));
+    }
+    if !config.remote_exists()? {
+        problems.push(format!(
+            "Remote {:?} doesn't exist in {}",
+            config.remote, config.path
+        ));
     }
 
-    let mapped_repos = repos.unwrap().map(|item| item.1.unwrap().path());
-    println!("{:#?}", Vec::from_iter(mapped_repos));
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(Error::Config(problems.join("\n")))
 }
 
 #[cfg(test)]
@@ -151,14 +239,11 @@ mod tests {
     use std::env;
     use std::fs;
     use std::path::PathBuf;
-    use std::sync::Mutex;
     use zlorbrs_lib::get_home_dir;
-
-    // Mutex to ensure tests that modify HOME environment variable don't conflict.
-    static TEST_MUTEX: Mutex<()> = Mutex::new(());
+    use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
 
     fn setup_test_env(test_name: &str) -> (PathBuf, std::sync::MutexGuard<'static, ()>) {
-        let lock = TEST_MUTEX.lock().unwrap();
+        let lock = ENV_MUTEX.lock().unwrap();
         let mut tmp_dir = env::temp_dir();
         tmp_dir.push(format!("zlorbrs_test_{}", test_name));
 
@@ -180,9 +265,9 @@ mod tests {
     #[test]
     fn test_get_all_empty() {
         let (tmp_dir, _lock) = setup_test_env("get_all_empty");
-        
+
         let all = get_all();
-        assert!(all.is_some());
+        assert!(all.is_ok());
         let all = all.unwrap();
         assert_eq!(all.count(), 0);
 
@@ -192,15 +277,15 @@ mod tests {
     #[test]
     fn test_remove_existing() {
         let (tmp_dir, _lock) = setup_test_env("remove_existing");
-        
-        let home_dir = get_home_dir();
+
+        let home_dir = get_home_dir().unwrap();
         let config_dir = format!("{}/.config/zlorbrs/configs/test_repo", home_dir);
         fs::create_dir_all(&config_dir).unwrap();
         fs::write(format!("{}/config.json", config_dir), "{}").unwrap();
 
         assert!(fs::metadata(&config_dir).is_ok());
 
-        remove(String::from("test_repo"));
+        remove(String::from("test_repo")).unwrap();
 
         assert!(fs::metadata(&config_dir).is_err());
 
@@ -210,9 +295,173 @@ mod tests {
     #[test]
     fn test_remove_non_existent() {
         let (tmp_dir, _lock) = setup_test_env("remove_non_existent");
-        
+
         // This won't panic because remove handles missing configurations
-        remove(String::from("does_not_exist"));
+        assert!(remove(String::from("does_not_exist")).is_err());
+
+        teardown_test_env(tmp_dir);
+    }
+
+    fn init_repo(tmp_dir: &std::path::Path) -> (PathBuf, String) {
+        let repo_dir = tmp_dir.join("my-site");
+        let repo = git2::Repository::init(&repo_dir).unwrap();
+        let signature = git2::Signature::now("Test User", "test@example.com").unwrap();
+        let tree_id = repo.index().unwrap().write_tree().unwrap();
+        let tree = repo.find_tree(tree_id).unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        repo.remote("origin", "https://example.com/my-site.git").unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+        (repo_dir.canonicalize().unwrap(), branch)
+    }
+
+    #[test]
+    fn test_add_with_flags() {
+        let (tmp_dir, _lock) = setup_test_env("add_with_flags");
+        let (repo_dir, branch) = init_repo(&tmp_dir);
+
+        add(AddOptions {
+            name: Some(String::from("site")),
+            path: Some(repo_dir.clone()),
+            build_command: Some(String::from("make dist")),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let config = Config::from_name("site").unwrap();
+        assert_eq!(config.path, repo_dir.to_str().unwrap());
+        assert_eq!(config.branch, branch);
+        assert_eq!(config.remote, "origin");
+        assert_eq!(config.build_command, BuildCommand::from("make dist"));
+
+        // the name is taken now
+        let again = AddOptions {
+            name: Some(String::from("site")),
+            path: Some(repo_dir),
+            ..Default::default()
+        };
+        assert!(matches!(add(again), Err(Error::Config(_))));
+
+        teardown_test_env(tmp_dir);
+    }
+
+    #[test]
+    fn test_add_validation() {
+        let (tmp_dir, _lock) = setup_test_env("add_validation");
+        let (repo_dir, _) = init_repo(&tmp_dir);
+
+        let result = add(AddOptions {
+            path: Some(repo_dir.clone()),
+            branch: Some(String::from("nope")),
+            remote: Some(String::from("mirror")),
+            ..Default::default()
+        });
+        let message = match result {
+            Err(Error::Config(message)) => message,
+            other => panic!("unexpected result {other:?}"),
+        };
+        assert!(message.contains("Branch \"nope\""));
+        assert!(message.contains("Remote \"mirror\""));
+        assert!(!Config::exists("my-site").unwrap());
+
+        let not_a_repo = AddOptions {
+            path: Some(tmp_dir.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(add(not_a_repo), Err(Error::Config(_))));
+
+        teardown_test_env(tmp_dir);
+    }
+
+    #[test]
+    fn test_add_detached_head() {
+        let (tmp_dir, _lock) = setup_test_env("add_detached_head");
+        let (repo_dir, branch) = init_repo(&tmp_dir);
+        let repo = git2::Repository::open(&repo_dir).unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+        repo.set_head_detached(head).unwrap();
+
+        // nothing to detect the branch from, but it can be given
+        let detected = AddOptions {
+            path: Some(repo_dir.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(add(detected), Err(Error::Config(_))));
+        let given = AddOptions {
+            path: Some(repo_dir),
+            branch: Some(branch.clone()),
+            ..Default::default()
+        };
+        add(given).unwrap();
+        assert_eq!(Config::from_name("my-site").unwrap().branch, branch);
+
+        teardown_test_env(tmp_dir);
+    }
+
+    #[test]
+    fn test_add_interactive() {
+        let (tmp_dir, _lock) = setup_test_env("add_interactive");
+        let (repo_dir, branch) = init_repo(&tmp_dir);
+
+        let options = AddOptions {
+            path: Some(repo_dir.clone()),
+            interactive: true,
+            ..Default::default()
+        };
+        // keep the path, rename it, keep remote and branch, change the build
+        let mut input = io::Cursor::new("\nwebsite\n\n\nnpm run build\n");
+        let mut output = Vec::new();
+        let config = build_config(options, &mut input, &mut output).unwrap();
+
+        assert_eq!(config.name, "website");
+        assert_eq!(config.path, repo_dir.to_str().unwrap());
+        assert_eq!(config.branch, branch);
+        assert_eq!(config.build_command, BuildCommand::from("npm run build"));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Name [my-site]: "));
+        assert!(output.contains("Build command [bun run build]: "));
+
+        teardown_test_env(tmp_dir);
+    }
+
+    #[test]
+    fn test_add_as_toml() {
+        let (tmp_dir, _lock) = setup_test_env("add_as_toml");
+        let (repo_dir, _) = init_repo(&tmp_dir);
+
+        add(AddOptions {
+            name: Some(String::from("site")),
+            path: Some(repo_dir),
+            build_command: Some(String::from("make dist")),
+            config_format: Format::Toml,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let file_path = Config::file_path("site").unwrap();
+        assert_eq!(file_path.extension().unwrap(), "toml");
+        assert!(fs::read_to_string(file_path).unwrap().contains("build_command = \"make dist\""));
+        assert_eq!(Config::from_name("site").unwrap().build_command, BuildCommand::from("make dist"));
+
+        teardown_test_env(tmp_dir);
+    }
+
+    #[test]
+    fn test_add_build_user() {
+        let (tmp_dir, _lock) = setup_test_env("add_build_user");
+        let (repo_dir, _) = init_repo(&tmp_dir);
+        assert_eq!(default_build_user(&repo_dir, false).unwrap(), None);
+
+        // system-wide, builds run as whoever owns the repository
+        let owner = if users::is_root() {
+            assert_eq!(default_build_user(&repo_dir, true).unwrap(), None);
+            let nobody = users::user("nobody").unwrap();
+            std::os::unix::fs::chown(&repo_dir, Some(nobody.uid), None).unwrap();
+            nobody.name
+        } else {
+            users::user(&users::current_ids().0.to_string()).unwrap().name
+        };
+        assert_eq!(default_build_user(&repo_dir, true).unwrap(), Some(owner));
 
         teardown_test_env(tmp_dir);
     }
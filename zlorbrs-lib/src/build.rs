@@ -0,0 +1,684 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    process::{Command, Stdio},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, Result,
+    config::Config,
+    paths,
+    users::{self, Account},
+};
+
+/// The outcome of running every build step of a repo.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildResult {
+    pub exit_code: Option<i32>, // of the last step that ran, None if it was killed by a signal
+    pub failed_step: Option<String>, // e.g. install
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    #[serde(default)]
+    pub stopped: Option<Stopped>, // why the build was stopped before it finished
+}
+
+impl BuildResult {
+    pub fn success(&self) -> bool {
+        self.failed_step.is_none() && self.stopped.is_none() && self.exit_code == Some(0)
+    }
+}
+
+/// Why a build was stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stopped {
+    TimedOut,  // it ran longer than build_timeout
+    Cancelled, // on request
+}
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stopped::TimedOut => write!(f, "timed out"),
+            Stopped::Cancelled => write!(f, "was cancelled"),
+        }
+    }
+}
+
+/// How long a stopped build step gets to exit after SIGTERM before it is
+/// killed
+pub const KILL_GRACE: Duration = Duration::from_secs(10);
+
+/// How long the output of a step that has exited is still read, before
+/// whatever it left running in the background and holding on to the output
+/// is killed
+pub const OUTPUT_DRAIN: Duration = Duration::from_secs(1);
+
+/// How often a running step is looked at to see whether it has to be stopped
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Asks a running build to stop. Clones share the same request
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// .
+///
+/// # Keeps a repo's checkout to one builder at a time
+///
+/// The service holds it while it checks out or builds a repo, and
+/// `zlorbrs-ctl build` while it builds one, so they never work in the same
+/// checkout at once. It is a lock file in the state directory, released
+/// when dropped or when the process holding it exits
+#[derive(Debug)]
+pub struct RepoLock {
+    _file: File,
+}
+
+impl RepoLock {
+    /// Takes the lock of `repo_name`, waiting while someone else has it
+    pub fn acquire(repo_name: &str) -> Result<Self> {
+        let file = Self::open(repo_name)?;
+        while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+        Ok(Self { _file: file })
+    }
+
+    /// Takes the lock of `repo_name` unless someone else has it
+    pub fn try_acquire(repo_name: &str) -> Result<Option<Self>> {
+        let file = Self::open(repo_name)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(Some(Self { _file: file }));
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::WouldBlock => Ok(None),
+            _ => Err(e.into()),
+        }
+    }
+
+    fn open(repo_name: &str) -> Result<File> {
+        let dir = paths::state_dir()?.join("locks");
+        fs::create_dir_all(&dir)?;
+        Ok(OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(format!("{repo_name}.lock")))?)
+    }
+}
+
+/// Cancelled from the SIGINT handler
+static INTERRUPT: OnceLock<Cancel> = OnceLock::new();
+
+/// .
+///
+/// # Cancels `cancel` on Ctrl-C
+///
+/// Build steps run in a process group of their own, so the terminal's SIGINT
+/// never reaches them. A build run in the foreground is stopped this way
+/// instead, the same as one cancelled in the service. Only the first call
+/// takes effect
+pub fn cancel_on_interrupt(cancel: &Cancel) {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        if let Some(cancel) = INTERRUPT.get() {
+            cancel.cancel();
+        }
+    }
+
+    if INTERRUPT.set(cancel.clone()).is_ok() {
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                on_interrupt as *const () as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+/// A line of build output and which stream it came from
+enum Line {
+    Stdout(String),
+    Stderr(String),
+}
+
+/// .
+///
+/// # Runs a repo's build
+///
+/// Runs every build step in the config's build directory with its build
+/// environment, stopping at the first step that fails. Combined stdout and
+/// stderr are streamed to `output` line by line as the build runs. Only
+/// errors when a step can't be started at all, a failing step is reported
+/// in the result. Steps run as `build_user` and `build_group` when set.
+///
+/// Each step runs in a process group of its own. When the build takes longer
+/// than `build_timeout` or `cancel` is cancelled the whole group gets SIGTERM,
+/// and SIGKILL if it is still around [`KILL_GRACE`] later. A step is done
+/// when its own process exits, anything it left behind with the output still
+/// open is killed [`OUTPUT_DRAIN`] later.
+pub fn run(config: &Config, output: &mut dyn Write, cancel: &Cancel) -> Result<BuildResult> {
+    let build_dir = config.build_dir()?;
+    let env = config.build_env()?;
+    let run_as = run_as(config)?;
+    debug!("Running build for: {}", build_dir.display());
+
+    let started_at = SystemTime::now();
+    let timer = Instant::now();
+    let mut exit_code = None;
+    let mut failed_step = None;
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    let mut output = OutputSink::new(output);
+    let mut stop = Stop {
+        deadline: config
+            .build_timeout
+            .map(|seconds| timer + Duration::from_secs(seconds)),
+        cancel,
+        stopped: None,
+    };
+
+    for step in config.build_steps() {
+        info!("Running build step: {step}");
+        output.write_marker(&format!("==> {step}"));
+
+        let mut command = step.command.to_command(step.shell)?;
+        if let Some((account, gid)) = &run_as {
+            switch_user(&mut command, account, *gid);
+        }
+        let mut child = command
+            .process_group(0)
+            .current_dir(&build_dir)
+            .envs(&env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Build(format!("Failed to run {step}: {e}")))?;
+
+        // read both pipes on their own threads so neither can fill up and
+        // stall the build, and interleave their lines as they arrive
+        let (tx, rx) = mpsc::channel();
+        let readers = [
+            child
+                .stdout
+                .take()
+                .map(|pipe| forward_lines(pipe, tx.clone(), Line::Stdout)),
+            child
+                .stderr
+                .take()
+                .map(|pipe| forward_lines(pipe, tx.clone(), Line::Stderr)),
+        ];
+        drop(tx);
+
+        let group = child.id() as libc::pid_t;
+        let mut output_open = true;
+        let mut exited = None;
+        let status = loop {
+            if output_open {
+                match rx.recv_timeout(WATCH_INTERVAL) {
+                    Ok(line) => record_line(line, &mut output, &mut stdout, &mut stderr),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => output_open = false,
+                }
+            } else {
+                thread::sleep(WATCH_INTERVAL);
+            }
+            if exited.is_none() {
+                exited = child.try_wait()?.map(|status| (status, Instant::now()));
+            }
+            if let Some((status, at)) = exited {
+                if !output_open {
+                    break status;
+                }
+                if at.elapsed() >= OUTPUT_DRAIN {
+                    output.write_marker(&format!(
+                        "{step} exited but left something running, killing it"
+                    ));
+                    signal_group(group, libc::SIGKILL);
+                    break status;
+                }
+            }
+
+            match stop.check() {
+                Signal::None => {}
+                Signal::Term(stopped) => {
+                    output.write_marker(&format!("The build {stopped}, stopping {step}"));
+                    signal_group(group, libc::SIGTERM);
+                }
+                Signal::Kill => {
+                    output.write_marker(&format!("{step} is still running, killing it"));
+                    signal_group(group, libc::SIGKILL);
+                    break child.wait()?;
+                }
+            }
+        };
+        // whatever was read before the step got killed or exited, the readers
+        // are left behind in case something outside the group holds the
+        // pipes open
+        for line in rx.try_iter() {
+            record_line(line, &mut output, &mut stdout, &mut stderr);
+        }
+        if !output_open {
+            for reader in readers.into_iter().flatten() {
+                let _ = reader.join();
+            }
+        }
+
+        debug!("got status: {:?}", status);
+        exit_code = status.code();
+
+        if !status.success() || stop.stopped.is_some() {
+            output.write_marker(&format!("{step} failed with exit code {exit_code:?}"));
+            failed_step = Some(step.to_string());
+            break;
+        }
+    }
+
+    let duration = timer.elapsed();
+    output.write_marker(&format!("finished in {duration:?}"));
+    output.flush();
+
+    Ok(BuildResult {
+        exit_code,
+        failed_step,
+        duration,
+        stdout,
+        stderr,
+        started_at,
+        finished_at: SystemTime::now(),
+        stopped: stop.stopped.map(|(stopped, _)| stopped),
+    })
+}
+
+fn record_line(line: Line, output: &mut OutputSink, stdout: &mut String, stderr: &mut String) {
+    match line {
+        Line::Stdout(line) => {
+            output.write_line(&line);
+            stdout.push_str(&line);
+        }
+        Line::Stderr(line) => {
+            output.write_line(&line);
+            stderr.push_str(&line);
+        }
+    }
+}
+
+/// What to send a running step's process group
+enum Signal {
+    None,
+    Term(Stopped),
+    Kill,
+}
+
+/// Decides when a build that was cancelled or ran out of time is stopped
+struct Stop<'a> {
+    deadline: Option<Instant>,
+    cancel: &'a Cancel,
+    stopped: Option<(Stopped, Instant)>, // and when SIGTERM was sent
+}
+
+impl Stop<'_> {
+    fn check(&mut self) -> Signal {
+        match self.stopped {
+            None => {
+                let stopped = if self.cancel.is_cancelled() {
+                    Stopped::Cancelled
+                } else if self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    Stopped::TimedOut
+                } else {
+                    return Signal::None;
+                };
+                self.stopped = Some((stopped, Instant::now()));
+                Signal::Term(stopped)
+            }
+            Some((_, since)) if since.elapsed() >= KILL_GRACE => Signal::Kill,
+            Some(_) => Signal::None,
+        }
+    }
+}
+
+/// Sends `signal` to every process in `group`. It may be gone already
+fn signal_group(group: libc::pid_t, signal: libc::c_int) {
+    unsafe {
+        libc::kill(-group, signal);
+    }
+}
+
+/// .
+///
+/// # The user and group builds run as
+///
+/// From `build_user` and `build_group`, the group defaulting to the user's
+/// primary one. None when neither is set or they are who we are already.
+/// Switching to anyone else takes root, the service has to run system-wide
+fn run_as(config: &Config) -> Result<Option<(Account, u32)>> {
+    if config.build_user.is_none() && config.build_group.is_none() {
+        return Ok(None);
+    }
+    let (uid, current_gid) = users::current_ids();
+    let account = users::user(&config.build_user.clone().unwrap_or_else(|| uid.to_string()))?;
+    let gid = match &config.build_group {
+        Some(group) => users::group(group)?,
+        None => account.gid,
+    };
+
+    if account.uid == uid && gid == current_gid {
+        return Ok(None);
+    }
+    if !users::is_root() {
+        return Err(Error::Build(format!(
+            "Can't build {} as {}, switching users takes zlorbrs-service running as root",
+            config.name, account.name
+        )));
+    }
+    Ok(Some((account, gid)))
+}
+
+/// Makes `command` run as `account`, with its home directory and name in the
+/// environment so tools find their caches and config there rather than in
+/// root's. Since we are root std drops our supplementary groups too
+fn switch_user(command: &mut Command, account: &Account, gid: u32) {
+    command
+        .uid(account.uid)
+        .gid(gid)
+        .env("HOME", &account.home)
+        .env("USER", &account.name)
+        .env("LOGNAME", &account.name);
+}
+
+fn forward_lines<R: Read + Send + 'static>(
+    pipe: R,
+    tx: mpsc::Sender<Line>,
+    wrap: fn(String) -> Line,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf).into_owned();
+                    if tx.send(wrap(line)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Writes build output, giving up after the first failure so a broken log
+/// doesn't fail the build itself
+struct OutputSink<'a> {
+    inner: &'a mut dyn Write,
+    failed: bool,
+    at_line_start: bool,
+}
+
+impl<'a> OutputSink<'a> {
+    fn new(inner: &'a mut dyn Write) -> Self {
+        Self {
+            inner,
+            failed: false,
+            at_line_start: true,
+        }
+    }
+
+    /// Writes `line`, starting it on a fresh line if the previous one was
+    /// cut short, e.g. by a stream ending without a newline
+    fn write_line(&mut self, line: &str) {
+        if self.failed || line.is_empty() {
+            return;
+        }
+        let separator = if self.at_line_start { "" } else { "\n" };
+        if let Err(e) = self
+            .inner
+            .write_all(format!("{separator}{line}").as_bytes())
+        {
+            error!("Failed to write build output, the rest won't be logged: {e}");
+            self.failed = true;
+        }
+        self.at_line_start = line.ends_with('\n');
+    }
+
+    /// Writes a line of our own
+    fn write_marker(&mut self, message: &str) {
+        self.write_line(&format!("[zlorbrs] {message}\n"));
+    }
+
+    fn flush(&mut self) {
+        if !self.failed {
+            let _ = self.inner.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BuildCommand, BuildStep};
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    struct TestDir {
+        dir: PathBuf,
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn setup_test_dir(test_name: &str) -> (TestDir, Config) {
+        let mut dir = env::temp_dir();
+        dir.push(format!("zlorbrs_build_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            name: String::from("test_repo"),
+            path: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        (TestDir { dir }, config)
+    }
+
+    fn step(name: &str, command: &str) -> BuildStep {
+        BuildStep {
+            name: Some(String::from(name)),
+            command: BuildCommand::from(command),
+            shell: true,
+        }
+    }
+
+    #[test]
+    fn test_run_success() {
+        let (test_dir, mut config) = setup_test_dir("success");
+        config.steps = vec![step("hello", "echo hello"), step("where", "pwd")];
+        let cwd_before = env::current_dir().unwrap();
+
+        let result = run(&config, &mut io::sink(), &Cancel::default()).unwrap();
+        assert!(result.success());
+        assert_eq!(result.exit_code, Some(0));
+        assert!(result.stdout.starts_with("hello\n"));
+        assert!(result.stdout.contains(test_dir.dir.to_str().unwrap()));
+        assert!(result.finished_at >= result.started_at);
+
+        // builds must never move the whole process around
+        assert_eq!(env::current_dir().unwrap(), cwd_before);
+    }
+
+    #[test]
+    fn test_run_failing_step() {
+        let (_test_dir, mut config) = setup_test_dir("failing_step");
+        config.steps = vec![
+            step("test", "echo broken >&2; exit 3"),
+            step("build", "echo never"),
+        ];
+
+        let result = run(&config, &mut io::sink(), &Cancel::default()).unwrap();
+        assert!(!result.success());
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.failed_step.as_deref(), Some("test"));
+        assert_eq!(result.stderr, "broken\n");
+        assert!(!result.stdout.contains("never"));
+    }
+
+    #[test]
+    fn test_run_missing_program() {
+        let (_test_dir, mut config) = setup_test_dir("missing_program");
+        config.build_command = BuildCommand::from("zlorbrs-command-that-does-not-exist");
+
+        assert!(matches!(
+            run(&config, &mut io::sink(), &Cancel::default()),
+            Err(Error::Build(_))
+        ));
+    }
+
+    #[test]
+    fn test_run_as_build_user() {
+        let (_test_dir, mut config) = setup_test_dir("build_user");
+        config.steps = vec![step("whoami", "id -u; id -G; echo $HOME")];
+
+        // as ourselves nothing has to switch
+        config.build_user = Some(users::current_ids().0.to_string());
+        assert!(
+            run(&config, &mut io::sink(), &Cancel::default())
+                .unwrap()
+                .success()
+        );
+
+        config.build_user = Some(String::from("nobody"));
+        if !users::is_root() {
+            assert!(matches!(
+                run(&config, &mut io::sink(), &Cancel::default()),
+                Err(Error::Build(_))
+            ));
+            return;
+        }
+        let nobody = users::user("nobody").unwrap();
+        let result = run(&config, &mut io::sink(), &Cancel::default()).unwrap();
+        assert!(result.success());
+        assert_eq!(
+            result.stdout,
+            format!(
+                "{}\n{}\n{}\n",
+                nobody.uid,
+                nobody.gid,
+                nobody.home.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_run_streams_combined_output() {
+        let (_test_dir, mut config) = setup_test_dir("combined_output");
+        config.steps = vec![step("build", "echo out; echo err >&2; printf 'no newline'")];
+
+        let mut output = Vec::new();
+        let result = run(&config, &mut output, &Cancel::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("[zlorbrs] ==> build\n"));
+        assert!(output.contains("out\n"));
+        assert!(output.contains("err\n"));
+        // stdout and stderr may interleave either way, but a line cut short
+        // never runs into the next one
+        assert!(output.contains("no newline\n"));
+        assert!(output.lines().last().unwrap().starts_with("[zlorbrs] finished in"));
+        assert_eq!(result.stdout, "out\nno newline");
+        assert_eq!(result.stderr, "err\n");
+    }
+
+    #[test]
+    fn test_run_timeout() {
+        let (_test_dir, mut config) = setup_test_dir("timeout");
+        // the background sleep would keep the output open if only the
+        // shell itself was stopped
+        config.steps = vec![
+            step("hang", "echo started; sleep 30 & sleep 30"),
+            step("after", "echo never"),
+        ];
+        config.build_timeout = Some(1);
+
+        let mut output = Vec::new();
+        let result = run(&config, &mut output, &Cancel::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(!result.success());
+        assert_eq!(result.stopped, Some(Stopped::TimedOut));
+        assert_eq!(result.failed_step.as_deref(), Some("hang"));
+        assert_eq!(result.stdout, "started\n");
+        assert!(result.duration < KILL_GRACE);
+        assert!(output.contains("[zlorbrs] The build timed out, stopping hang\n"));
+    }
+
+    #[test]
+    fn test_run_background_leftover() {
+        let (_test_dir, mut config) = setup_test_dir("background_leftover");
+        config.steps = vec![step("spawn", "echo started; sleep 100 & exit 0")];
+
+        let mut output = Vec::new();
+        let result = run(&config, &mut output, &Cancel::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(result.success());
+        assert_eq!(result.stdout, "started\n");
+        assert!(result.duration < KILL_GRACE);
+        assert!(
+            output.contains("[zlorbrs] spawn exited but left something running, killing it\n")
+        );
+    }
+
+    #[test]
+    fn test_run_cancelled() {
+        let (_test_dir, mut config) = setup_test_dir("cancelled");
+        config.steps = vec![step("hang", "sleep 30")];
+
+        let cancel = Cancel::default();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            canceller.cancel();
+        });
+        let result = run(&config, &mut io::sink(), &cancel).unwrap();
+        assert_eq!(result.stopped, Some(Stopped::Cancelled));
+        assert_eq!(result.exit_code, None);
+        assert!(result.duration < KILL_GRACE);
+    }
+}
@@ -1,83 +1,743 @@
-use std::{fs, io};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
 
+use chrono::{DateTime, Local, Utc};
+use croner::Cron;
 use log::info;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::{
+    Error, Result,
+    format::{self, Format},
+    migrate::{self, Schema},
+    paths,
+    pipeline::Pipeline,
+    users,
+};
+
+/// What new configs build with unless told otherwise
+pub const DEFAULT_BUILD_COMMAND: &str = "bun run build";
+
+/// The build output looked for when a config doesn't list its `artifacts`
+pub const DEFAULT_ARTIFACTS: &str = "dist";
+
+/// The config version written by this zlorbrs, see [`migrate`]
+pub const CONFIG_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub name: String,          // repo identifier
-    pub path: String,          // absolute path to repo
-    pub branch: String,        // e.g. main
-    pub remote: String,        // e.g. origin
-    pub build_command: String, // e.g. npm run build
+    #[serde(default)]
+    pub version: u32, // schema version, 0 for configs from before versioning
+    pub name: String,   // repo identifier
+    pub path: String,   // absolute path to repo
+    pub branch: String, // e.g. main
+    #[serde(default = "default_remote")]
+    pub remote: String, // e.g. origin
+    #[serde(default = "default_build_command")]
+    pub build_command: BuildCommand, // e.g. "npm run build" or ["npm", "run", "build"]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shell: bool, // run build_command through /bin/sh -c
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<BuildStep>, // e.g. install, test, build. Replaces build_command when set
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>, // e.g. NODE_ENV=production, wins over env_file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<String>, // KEY=VALUE lines, relative to the repo or absolute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>, // relative to the repo, e.g. packages/web
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_interval: Option<u64>, // seconds between checks, defaults to the service's sleep_time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>, // cron expression to build on even without changes, e.g. "0 3 * * *"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_timeout: Option<u64>, // seconds a build may take before it is stopped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifacts: Option<Vec<String>>, // build output that is rebuilt when missing, e.g. build/*.html. Defaults to dist
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watch_paths: Vec<String>, // only changes to these build, e.g. packages/web. Everything when empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_user: Option<String>, // unix user builds run as when the service is root, e.g. www-data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_group: Option<String>, // defaults to build_user's primary group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>, // user for ssh/token auth, defaults to the remote url's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key_path: Option<String>, // e.g. ~/.ssh/id_ed25519
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>, // file holding an access token for https remotes
 }
 
 impl Config {
-    pub fn new(repo_name: String) -> Self {
-        Self {
-            name: repo_name,
-            path: String::from(std::env::current_dir().unwrap().to_str().unwrap()),
-            branch: String::from(
-                git2::Branch::wrap(
-                    git2::Repository::open(std::env::current_dir().unwrap().to_str().unwrap())
-                        .unwrap()
-                        .references()
-                        .unwrap()
-                        .next()
-                        .unwrap()
-                        .unwrap(),
-                )
-                .name()
-                .unwrap()
-                .unwrap(),
-            ),
-            remote: String::from("origin"),
-            build_command: String::from("bun run build"),
+    /// .
+    ///
+    /// # Reads a config from its directory
+    ///
+    /// Parses the `config.json`, `config.toml` or `config.yaml` inside `dir`,
+    /// e.g. one of the entries in the configs directory
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        let file_path = format::find_existing(dir, "config")?;
+        let contents = fs::read_to_string(&file_path)?;
+        let format = Format::from_path(&file_path).unwrap_or_default();
+        Self::parse(format, &contents).map_err(|e| match e {
+            Error::Config(message) => Error::Config(format!("{}: {message}", file_path.display())),
+            e => e,
+        })
+    }
+
+    /// .
+    ///
+    /// # Parses a config written in `format`
+    ///
+    /// Configs written by an older zlorbrs are upgraded on the way, without
+    /// touching their file. Errors for configs from a newer zlorbrs
+    pub fn parse(format: Format, contents: &str) -> Result<Self> {
+        let mut value = format.parse(contents)?;
+        migrate::upgrade(Schema::Repo, &mut value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Reads the config of the repo called `repo_name`
+    pub fn from_name(repo_name: &str) -> Result<Self> {
+        Self::from_dir(&paths::repo_config_dir(repo_name)?)
+    }
+
+    /// .
+    ///
+    /// # Adds the repository's own build settings
+    ///
+    /// Fills in this config from the `.zlorbrs.toml` the repository has
+    /// checked out right now, see [`Pipeline::apply`]. This is what builds
+    /// run with, so changes to the file apply to the next build
+    pub fn with_pipeline(mut self) -> Result<Self> {
+        if let Some((file_path, pipeline)) = Pipeline::read(Path::new(&self.path))? {
+            info!("Using build settings from {}", file_path.display());
+            pipeline.apply(&mut self);
         }
+        Ok(self)
     }
 
-    pub fn load(repo_name: String) -> Result<String, io::Error> {
-        info!("Loading config for {}", repo_name);
-        let mut contents = fs::read_to_string(format!(
-            "{}/.config/zlorbrs/configs/{}",
-            std::env::home_dir().unwrap().to_str().unwrap(),
-            repo_name
-        ));
-        if contents.is_err() {
-            info!("Theres no config so we need to create one");
-            contents = Ok(Self::save(repo_name));
+    /// What has to exist after a build, `dist` unless `artifacts` says
+    /// otherwise
+    pub fn artifact_patterns(&self) -> Vec<String> {
+        self.artifacts
+            .clone()
+            .unwrap_or_else(|| vec![String::from(DEFAULT_ARTIFACTS)])
+    }
+
+    /// Whether `remote` is configured in the repository at `path`
+    pub fn remote_exists(&self) -> Result<bool> {
+        let repo = git2::Repository::open(&self.path)?;
+        match repo.find_remote(&self.remote) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
         }
-        info!("Found contents: {:#?}", contents);
-        contents
     }
 
-    pub fn save(repo_name: String) -> String {
-        info!("Generating configuration file. System assumes Bun build script");
-        let directory_path = format!(
-            "{}/.config/zlorbrs/configs/{}",
-            std::env::home_dir().unwrap().to_str().unwrap(),
-            repo_name
-        );
-        let file_path = format!("{directory_path}/config.json");
+    /// Whether `branch` exists as a local branch in the repository at `path`
+    pub fn branch_exists(&self) -> Result<bool> {
+        let repo = git2::Repository::open(&self.path)?;
+        match repo.find_branch(&self.branch, git2::BranchType::Local) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The config file of the repo called `repo_name`, `config.json` when
+    /// there is none yet
+    pub fn file_path(repo_name: &str) -> Result<PathBuf> {
+        let directory_path = paths::repo_config_dir(repo_name)?;
+        Ok(format::find(&directory_path, "config")?
+            .unwrap_or_else(|| directory_path.join("config.json")))
+    }
+
+    /// Whether a config called `repo_name` exists already
+    pub fn exists(repo_name: &str) -> Result<bool> {
+        Ok(fs::exists(paths::repo_config_dir(repo_name)?)?)
+    }
+
+    /// Writes this config into the directory named after it, in the format
+    /// its file already has or JSON for a new one. Returns the path of the file
+    pub fn write(&self) -> Result<PathBuf> {
+        let format = Format::from_path(&Self::file_path(&self.name)?).unwrap_or_default();
+        self.write_as(format)
+    }
 
-        // first create directory
-        match std::fs::create_dir_all(directory_path.clone()) {
-            Ok(_) => {
-                println!("Created config directory at: {directory_path}")
+    /// .
+    ///
+    /// # Writes this config as `format`
+    ///
+    /// Pretty printed so it is pleasant to edit by hand. A file in another
+    /// format is replaced. It is written in the current version's shape, so
+    /// it is marked as such
+    pub fn write_as(&self, format: Format) -> Result<PathBuf> {
+        let directory_path = paths::repo_config_dir(&self.name)?;
+        fs::create_dir_all(&directory_path)?;
+        let file_path = directory_path.join(format!("config.{}", format.extension()));
+        let config = Config {
+            version: CONFIG_VERSION,
+            ..self.clone()
+        };
+        fs::write(&file_path, format.to_string_pretty(&config)?)?;
+
+        for extension in ["json", "toml", "yaml", "yml"] {
+            let other = directory_path.join(format!("config.{extension}"));
+            if other != file_path && other.exists() {
+                fs::remove_file(other)?;
             }
-            Err(e) => panic!("{e}"),
+        }
+        Ok(file_path)
+    }
+
+    /// .
+    ///
+    /// # The build steps to run, in order
+    ///
+    /// `steps` when any are declared, otherwise `build_command` as a single step
+    pub fn build_steps(&self) -> Vec<BuildStep> {
+        if !self.steps.is_empty() {
+            return self.steps.clone();
+        }
+        vec![BuildStep {
+            name: None,
+            command: self.build_command.clone(),
+            shell: self.shell,
+        }]
+    }
+
+    /// .
+    ///
+    /// # The directory builds run in
+    ///
+    /// `working_dir` inside the repo when set, otherwise the repo itself.
+    /// Errors if `working_dir` would leave the repo, also through symlinks,
+    /// like `env_file`, and resolves them so the build doesn't follow one
+    /// swapped in later. One that isn't there yet can't lead anywhere, builds
+    /// can't start in it
+    pub fn build_dir(&self) -> Result<PathBuf> {
+        let working_dir = match &self.working_dir {
+            Some(working_dir) => Path::new(working_dir),
+            None => return Ok(PathBuf::from(&self.path)),
         };
+        if !is_inside(working_dir) {
+            return Err(Error::Config(format!(
+                "working_dir must be relative to the repo: {}",
+                working_dir.display()
+            )));
+        }
+        let build_dir = Path::new(&self.path).join(working_dir);
+        match (fs::canonicalize(&self.path), fs::canonicalize(&build_dir)) {
+            (Ok(repo_path), Ok(resolved)) if !resolved.starts_with(&repo_path) => {
+                Err(Error::Config(format!(
+                    "working_dir must stay inside the repo: {} leads to {}",
+                    working_dir.display(),
+                    resolved.display()
+                )))
+            }
+            (Ok(_), Ok(resolved)) => Ok(resolved),
+            _ => Ok(build_dir),
+        }
+    }
+
+    /// .
+    ///
+    /// # The extra environment for builds
+    ///
+    /// Variables from `env_file` first, then `env` so inline values win
+    pub fn build_env(&self) -> Result<BTreeMap<String, String>> {
+        let mut vars = BTreeMap::new();
+        if let Some(env_file) = &self.env_file {
+            let env_path = self.env_path(env_file)?;
+            let contents = fs::read_to_string(&env_path).map_err(|e| {
+                Error::Config(format!("Failed to read {}: {e}", env_path.display()))
+            })?;
+            vars.extend(
+                parse_env_file(&contents)
+                    .map_err(|e| Error::Config(format!("{}: {e}", env_path.display())))?,
+            );
+        }
+        vars.extend(self.env.clone());
+        Ok(vars)
+    }
+
+    /// .
+    ///
+    /// # Where `env_file` is
+    ///
+    /// An absolute path is taken as it is. A relative one has to stay inside
+    /// the repo, also through symlinks, since whoever owns the repo decides
+    /// what is in there and the file is read before the build drops its
+    /// privileges
+    fn env_path(&self, env_file: &str) -> Result<PathBuf> {
+        let env_file = Path::new(env_file);
+        if env_file.is_absolute() {
+            return Ok(env_file.to_path_buf());
+        }
+        let outside = || {
+            Error::Config(format!(
+                "env_file must stay inside the repo: {}",
+                env_file.display()
+            ))
+        };
+        if !is_inside(env_file) {
+            return Err(outside());
+        }
+        let env_path = Path::new(&self.path).join(env_file);
+        let resolved = fs::canonicalize(&self.path).and_then(|repo_path| {
+            fs::canonicalize(&env_path).map(|env_path| (repo_path, env_path))
+        });
+        match resolved {
+            Ok((repo_path, env_path)) if env_path.starts_with(&repo_path) => Ok(env_path),
+            Ok(_) => Err(outside()),
+            Err(e) => Err(Error::Config(format!(
+                "Failed to read {}: {e}",
+                env_path.display()
+            ))),
+        }
+    }
+
+    /// .
+    ///
+    /// # Checks that this config can actually be used
+    ///
+    /// The repository has to exist with the branch and remote, every build
+    /// step has to be runnable and the files it points at have to be there.
+    /// Returns every problem found, an empty list means it's fine
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut problems = Vec::new();
+        let mut error = |field: &str, message: String| {
+            problems.push(Diagnostic::error(field, message));
+        };
+
+        if self.name.is_empty() {
+            error("name", String::from("is empty"));
+        }
+
+        let path = Path::new(&self.path);
+        if !path.is_absolute() {
+            error(
+                "path",
+                format!("{:?} has to be an absolute path", self.path),
+            );
+        }
+        if !path.is_dir() {
+            error("path", format!("{:?} is not a directory", self.path));
+            return problems;
+        }
+        match git2::Repository::open(path) {
+            Ok(repo) => {
+                if repo.find_remote(&self.remote).is_err() {
+                    error(
+                        "remote",
+                        format!("{:?} doesn't exist in {}", self.remote, self.path),
+                    );
+                }
+                if repo
+                    .find_branch(&self.branch, git2::BranchType::Local)
+                    .is_err()
+                {
+                    error(
+                        "branch",
+                        format!("{:?} doesn't exist in {}", self.branch, self.path),
+                    );
+                }
+            }
+            Err(e) => error(
+                "path",
+                format!("{:?} is not a git repository: {}", self.path, e.message()),
+            ),
+        }
+
+        let build_dir = match self.build_dir() {
+            Ok(build_dir) if build_dir.is_dir() => Some(build_dir),
+            Ok(build_dir) => {
+                error(
+                    "working_dir",
+                    format!("{} doesn't exist", build_dir.display()),
+                );
+                None
+            }
+            Err(e) => {
+                error("working_dir", e.to_string());
+                None
+            }
+        };
+        let env = match self.build_env() {
+            Ok(env) => env,
+            Err(e) => {
+                error("env_file", e.to_string());
+                BTreeMap::new()
+            }
+        };
+
+        let field = if self.steps.is_empty() {
+            "build_command"
+        } else {
+            "steps"
+        };
+        for step in self.build_steps() {
+            let program = match step.command.to_command(step.shell) {
+                Ok(command) => PathBuf::from(command.get_program()),
+                Err(e) => {
+                    error(field, format!("{step}: {e}"));
+                    continue;
+                }
+            };
+            let path_var = env
+                .get("PATH")
+                .cloned()
+                .or_else(|| std::env::var("PATH").ok())
+                .unwrap_or_default();
+            if !program_exists(&program, build_dir.as_deref(), &path_var) {
+                error(
+                    field,
+                    format!("{step}: {} isn't on PATH", program.display()),
+                );
+            }
+        }
 
-        // then write file
-        let data = serde_json::to_string(&Config::new(repo_name)).unwrap();
-        let raw = data.clone();
-        match std::fs::write(file_path.clone(), raw) {
-            Ok(_) => {
-                println!("Created configuration file at: {file_path}")
+        if let Some(build_user) = &self.build_user
+            && let Err(e) = users::user(build_user)
+        {
+            error("build_user", e.to_string());
+        }
+        if let Some(build_group) = &self.build_group
+            && let Err(e) = users::group(build_group)
+        {
+            error("build_group", e.to_string());
+        }
+
+        for (field, file) in [
+            ("ssh_key_path", &self.ssh_key_path),
+            ("token_file", &self.token_file),
+        ] {
+            let Some(file) = file else { continue };
+            match crate::credentials::expand_home(file) {
+                Ok(expanded) if expanded.is_file() => {}
+                _ => error(field, format!("{file} doesn't exist")),
             }
-            Err(e) => panic!("{e}"),
+        }
+        if let Some(schedule) = &self.schedule
+            && let Err(e) = next_occurrence(schedule, Utc::now())
+        {
+            problems.push(Diagnostic::error("schedule", e.to_string()));
+        }
+        if self.poll_interval == Some(0) {
+            problems.push(Diagnostic::warning(
+                "poll_interval",
+                String::from("is 0, the repo is fetched back to back without a pause"),
+            ));
+        }
+        if self.build_timeout == Some(0) {
+            problems.push(Diagnostic::warning(
+                "build_timeout",
+                String::from("is 0, every build is stopped right away"),
+            ));
+        }
+        if paths::system() && self.build_user.is_none() {
+            problems.push(Diagnostic::warning(
+                "build_user",
+                String::from("isn't set, so builds run as root"),
+            ));
+        }
+
+        problems
+    }
+}
+
+/// .
+///
+/// # When a cron `schedule` next comes due after `after`
+///
+/// Takes the usual five fields, minute to day of week, in local time, e.g.
+/// `0 3 * * *` for every night at three
+pub fn next_occurrence(schedule: &str, after: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let cron: Cron = schedule
+        .parse()
+        .map_err(|e| Error::Config(format!("{schedule:?} isn't a cron expression: {e}")))?;
+    let next = cron
+        .find_next_occurrence(&after.with_timezone(&Local), false)
+        .map_err(|e| Error::Config(format!("{schedule:?} never comes due: {e}")))?;
+    Ok(next.with_timezone(&Utc))
+}
+
+fn default_remote() -> String {
+    String::from("origin")
+}
+
+fn default_build_command() -> BuildCommand {
+    BuildCommand::from(DEFAULT_BUILD_COMMAND)
+}
+
+/// Whether `program` can be started from `build_dir`: paths are taken as
+/// they are, bare names are looked up on `path_var`
+fn program_exists(program: &Path, build_dir: Option<&Path>, path_var: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    let is_executable = |candidate: &Path| {
+        candidate
+            .metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+
+    if program.components().count() > 1 {
+        return match build_dir {
+            Some(build_dir) => is_executable(&build_dir.join(program)),
+            // without a build directory relative paths can't be told apart
+            None => program.is_relative() || is_executable(program),
+        };
+    }
+    std::env::split_paths(path_var).any(|dir| is_executable(&dir.join(program)))
+}
+
+/// How bad a problem found while validating a config is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,   // the config can't work like this
+    Warning, // it works, but probably not as intended
+}
+
+/// A problem with one field of a config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub field: String, // e.g. branch
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(field: &str, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            field: String::from(field),
+            message,
+        }
+    }
+
+    pub fn warning(field: &str, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            field: String::from(field),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}: {}", self.field, self.message)
+    }
+}
+
+/// `origin`, or else the first remote the repository has
+pub fn detect_remote(repo: &git2::Repository) -> Result<String> {
+    let remotes = repo.remotes()?;
+    if remotes.iter().flatten().any(|name| name == "origin") {
+        return Ok(String::from("origin"));
+    }
+    Ok(remotes
+        .iter()
+        .flatten()
+        .next()
+        .map_or(String::from("origin"), String::from))
+}
+
+/// .
+///
+/// # Works out which branch to follow
+///
+/// The checked out branch, or when HEAD is detached or has no commits yet
+/// the default branch of `remote` (`refs/remotes/<remote>/HEAD`). A new
+/// repository without one follows the branch its first commit will create
+pub fn detect_branch(repo: &git2::Repository, remote: &str) -> Result<String> {
+    let unborn = match repo.head() {
+        Ok(head) if head.is_branch() => {
+            return head
+                .shorthand()
+                .map(String::from)
+                .ok_or_else(|| Error::Config(String::from("Branch name is not utf-8")));
+        }
+        Ok(_) => None, // detached
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch => repo
+            .find_reference("HEAD")?
+            .symbolic_target()
+            .and_then(|target| target.strip_prefix("refs/heads/"))
+            .map(String::from),
+        Err(e) => return Err(e.into()),
+    };
+
+    let remote_head = format!("refs/remotes/{remote}/HEAD");
+    let remote_prefix = format!("refs/remotes/{remote}/");
+    let default_branch = match repo.find_reference(&remote_head) {
+        Ok(reference) => reference
+            .symbolic_target()
+            .and_then(|target| target.strip_prefix(&remote_prefix))
+            .map(String::from),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    default_branch.or(unborn).ok_or_else(|| {
+        Error::Config(format!(
+            "HEAD is detached and {remote} has no default branch ({remote_head}), pick a branch explicitly"
+        ))
+    })
+}
+
+/// .
+///
+/// # Lets a system-wide zlorbrs open every configured repository
+///
+/// Takes the path of every config that can be read, and `adding` for a
+/// repository that is about to be configured, see [`paths::trust_repos`].
+/// Does nothing without `--system`
+pub fn trust_configured_repos(adding: Option<&Path>) -> Result<()> {
+    if !paths::system() {
+        return Ok(());
+    }
+    let mut repo_paths: Vec<PathBuf> = match fs::read_dir(paths::configs_dir()?) {
+        Ok(dirs) => dirs
+            .flatten()
+            .filter_map(|entry| Config::from_dir(&entry.path()).ok())
+            .map(|config| PathBuf::from(config.path))
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    repo_paths.extend(adding.map(Path::to_path_buf));
+    paths::trust_repos(&repo_paths)
+}
+
+/// Whether the relative `path` stays below where it starts, i.e. has no
+/// root and no `..`
+pub(crate) fn is_inside(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Parses dotenv style `KEY=VALUE` lines. Blank lines, `#` comments and an
+/// `export ` prefix are ignored and values may be wrapped in quotes.
+fn parse_env_file(contents: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => return Err(format!("line {} is not KEY=VALUE", number + 1)),
+        };
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|(open, close)| {
+                value
+                    .strip_prefix(*open)
+                    .and_then(|v| v.strip_suffix(*close))
+            })
+            .unwrap_or(value);
+        vars.push((String::from(key), String::from(value)));
+    }
+    Ok(vars)
+}
+
+/// A build command, either as a single command line or as an argument list.
+///
+/// Without `shell` a command line is split into arguments like a shell would
+/// (quotes are honoured) but no expansion or piping happens.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum BuildCommand {
+    Line(String),      // e.g. "bun run build"
+    Args(Vec<String>), // e.g. ["bun", "run", "build"]
+}
+
+impl Default for BuildCommand {
+    fn default() -> Self {
+        BuildCommand::Line(String::new())
+    }
+}
+
+impl From<&str> for BuildCommand {
+    fn from(line: &str) -> Self {
+        BuildCommand::Line(String::from(line))
+    }
+}
+
+impl fmt::Display for BuildCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildCommand::Line(line) => write!(f, "{line}"),
+            BuildCommand::Args(args) => write!(
+                f,
+                "{}",
+                shlex::try_join(args.iter().map(String::as_str)).unwrap_or_else(|_| args.join(" "))
+            ),
+        }
+    }
+}
+
+impl BuildCommand {
+    /// .
+    ///
+    /// # Builds the process for this command
+    ///
+    /// With `shell` the command runs through `/bin/sh -c`, otherwise the
+    /// first argument is the program to execute
+    pub fn to_command(&self, shell: bool) -> Result<Command> {
+        let args = match self {
+            BuildCommand::Line(line) if shell => vec![line.clone()],
+            BuildCommand::Line(line) => shlex::split(line).ok_or_else(|| {
+                Error::Config(format!("Build command has unbalanced quotes: {line}"))
+            })?,
+            BuildCommand::Args(_) if shell => vec![self.to_string()],
+            BuildCommand::Args(args) => args.clone(),
         };
-        data
+
+        if shell {
+            let mut command = Command::new("/bin/sh");
+            command.arg("-c").args(args);
+            return Ok(command);
+        }
+
+        match args.split_first() {
+            Some((program, rest)) => {
+                let mut command = Command::new(program);
+                command.args(rest);
+                Ok(command)
+            }
+            None => Err(Error::Config(String::from("Build command is empty"))),
+        }
+    }
+}
+
+/// One step of a repo's build, e.g. installing dependencies or running tests
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BuildStep {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>, // e.g. install
+    pub command: BuildCommand,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shell: bool, // run command through /bin/sh -c
+}
+
+impl fmt::Display for BuildStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{}", self.command),
+        }
     }
 }
 
@@ -88,6 +748,7 @@ mod tests {
     use std::fs;
     use std::path::PathBuf;
     // use std::sync::Mutex; // removed
+    use chrono::Timelike;
     use git2::Repository;
     use crate::shared_test_utils::ENV_MUTEX; // added
 
@@ -158,19 +819,37 @@ mod tests {
         }
     }
 
+    /// A config for the test project, like `zlorbrs-ctl add` writes it
+    fn project_config(env: &TestEnv) -> Config {
+        let repo = Repository::open(&env.project_dir).unwrap();
+        Config {
+            name: String::from("test_repo"),
+            path: env.project_dir.to_str().unwrap().to_string(),
+            branch: detect_branch(&repo, "origin").unwrap(),
+            remote: String::from("origin"),
+            build_command: BuildCommand::from(DEFAULT_BUILD_COMMAND),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_config_new() {
         let env = setup_test_env("config_new");
         let repo_name = String::from("test_repo");
 
-        let config = Config::new(repo_name.clone());
+        let config = project_config(&env);
 
         assert_eq!(config.name, repo_name);
         assert_eq!(config.path, env.project_dir.to_str().unwrap());
         // Git branch name depends on global config (master vs main), just ensure it's not empty
         assert!(!config.branch.is_empty());
         assert_eq!(config.remote, "origin");
-        assert_eq!(config.build_command, "bun run build");
+        // everything else a new config leaves out has working defaults
+        assert_eq!(config.build_steps().len(), 1);
+        assert_eq!(config.build_steps()[0].command, BuildCommand::from("bun run build"));
+        assert_eq!(config.build_dir().unwrap(), env.project_dir);
+        assert_eq!(config.artifact_patterns(), ["dist"]);
+        assert!(config.build_env().unwrap().is_empty());
     }
 
     #[test]
@@ -178,19 +857,19 @@ mod tests {
         let env = setup_test_env("config_save");
         let repo_name = String::from("test_repo");
 
-        let saved_json = Config::save(repo_name.clone());
+        let file_path = project_config(&env).write().unwrap();
 
         let expected_config_dir = env.home_dir.join(".config/zlorbrs/configs").join(&repo_name);
         let expected_file_path = expected_config_dir.join("config.json");
+        assert_eq!(file_path, expected_file_path);
 
         assert!(fs::metadata(&expected_config_dir).is_ok(), "Config directory was not created");
         assert!(fs::metadata(&expected_file_path).is_ok(), "config.json was not created");
 
         let file_contents = fs::read_to_string(&expected_file_path).unwrap();
-        assert_eq!(saved_json, file_contents);
-
         let parsed_config: Config = serde_json::from_str(&file_contents).unwrap();
         assert_eq!(parsed_config.name, repo_name);
+        assert_eq!(parsed_config.version, CONFIG_VERSION);
     }
 
     #[test]
@@ -199,14 +878,13 @@ mod tests {
         let repo_name = String::from("test_repo");
 
         // Manually save a config first
-        let _ = Config::save(repo_name.clone());
+        project_config(&env).write().unwrap();
 
         // Then try loading it
-        let load_result = Config::load(repo_name);
+        let load_result = Config::from_name(&repo_name);
         assert!(load_result.is_ok());
-        
-        let loaded_json = load_result.unwrap();
-        let config: Config = serde_json::from_str(&loaded_json).unwrap();
+
+        let config = load_result.unwrap();
         assert_eq!(config.name, "test_repo");
         assert_eq!(config.path, env.project_dir.to_str().unwrap());
     }
@@ -222,15 +900,374 @@ mod tests {
         // Ensure missing before load
         assert!(fs::metadata(&expected_file_path).is_err());
 
-        // Loading a missing config should automatically create and return it
-        let load_result = Config::load(repo_name.clone());
-        assert!(load_result.is_ok());
+        // A missing config is an error, configs are only created by `add`
+        let load_result = Config::from_name(&repo_name);
+        assert!(matches!(load_result, Err(Error::Io(_))));
+        assert!(!Config::exists(&repo_name).unwrap());
+        assert!(fs::metadata(&expected_config_dir).is_err(), "loading shouldn't create anything");
+    }
+
+    #[test]
+    fn test_config_from_dir() {
+        let env = setup_test_env("config_from_dir");
+        let repo_name = String::from("test_repo");
+        project_config(&env).write().unwrap();
 
-        // Verify it was created
-        assert!(fs::metadata(&expected_file_path).is_ok(), "config.json should be created by load()");
+        let config_dir = env.home_dir.join(".config/zlorbrs/configs").join(&repo_name);
+        let config = Config::from_dir(&config_dir).unwrap();
+        assert_eq!(config.name, repo_name);
 
-        let loaded_json = load_result.unwrap();
-        let config: Config = serde_json::from_str(&loaded_json).unwrap();
-        assert_eq!(config.name, "test_repo");
+        fs::write(config_dir.join("config.json"), "{ not json").unwrap();
+        assert!(Config::from_dir(&config_dir).is_err());
+    }
+
+    #[test]
+    fn test_config_from_name() {
+        let env = setup_test_env("config_from_name");
+        project_config(&env).write().unwrap();
+
+        assert_eq!(Config::from_name("test_repo").unwrap().name, "test_repo");
+        assert!(matches!(Config::from_name("nope"), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_config_remote_exists() {
+        let env = setup_test_env("config_remote_exists");
+        let mut config = project_config(&env);
+
+        // a fresh repo has no remotes at all
+        assert!(!config.remote_exists().unwrap());
+
+        let repo = Repository::open(&env.project_dir).unwrap();
+        repo.remote("upstream", "https://example.com/repo.git").unwrap();
+        config.remote = String::from("upstream");
+        assert!(config.remote_exists().unwrap());
+    }
+
+    #[test]
+    fn test_config_detect_remote() {
+        let env = setup_test_env("config_detect_remote");
+        let repo = Repository::open(&env.project_dir).unwrap();
+        repo.remote("upstream", "https://example.com/repo.git").unwrap();
+
+        // without an origin the only remote there is gets picked
+        assert_eq!(detect_remote(&repo).unwrap(), "upstream");
+
+        repo.remote("origin", "https://example.com/fork.git").unwrap();
+        assert_eq!(detect_remote(&repo).unwrap(), "origin");
+    }
+
+    #[test]
+    fn test_detect_branch() {
+        let env = setup_test_env("detect_branch");
+        let repo = Repository::open(&env.project_dir).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+
+        // tags and other branches sorting first don't matter, HEAD does
+        repo.tag_lightweight("0-release", head.as_object(), false).unwrap();
+        repo.branch("0-other", &head, false).unwrap();
+        repo.branch("zz-feature", &head, false).unwrap();
+        repo.set_head("refs/heads/zz-feature").unwrap();
+        assert_eq!(detect_branch(&repo, "origin").unwrap(), "zz-feature");
+
+        // detached without a remote default there's nothing to go on
+        repo.set_head_detached(head.id()).unwrap();
+        assert!(matches!(
+            detect_branch(&repo, "origin"),
+            Err(Error::Config(_))
+        ));
+
+        repo.reference("refs/remotes/origin/trunk", head.id(), false, "test").unwrap();
+        repo.reference_symbolic("refs/remotes/origin/HEAD", "refs/remotes/origin/trunk", false, "test")
+            .unwrap();
+        assert_eq!(detect_branch(&repo, "origin").unwrap(), "trunk");
+        assert!(detect_branch(&repo, "upstream").is_err());
+    }
+
+    #[test]
+    fn test_detect_branch_unborn() {
+        let env = setup_test_env("detect_branch_unborn");
+        let fresh_dir = env.project_dir.join("fresh");
+        let repo = Repository::init(&fresh_dir).unwrap();
+        repo.set_head("refs/heads/develop").unwrap();
+
+        assert_eq!(detect_branch(&repo, "origin").unwrap(), "develop");
+    }
+
+    #[test]
+    fn test_config_validate() {
+        let env = setup_test_env("config_validate");
+        let repo = Repository::open(&env.project_dir).unwrap();
+        repo.remote("origin", "https://example.com/repo.git").unwrap();
+
+        let mut config = project_config(&env);
+        config.build_command = BuildCommand::from("sh -c true");
+        assert_eq!(config.validate(), vec![]);
+
+        config.branch = String::from("nope");
+        config.remote = String::from("upstream");
+        config.build_command = BuildCommand::from("zlorbrs-command-that-does-not-exist --flag");
+        config.working_dir = Some(String::from("missing"));
+        config.token_file = Some(String::from("~/no-token"));
+        config.schedule = Some(String::from("every night"));
+        let fields: Vec<String> = config.validate().into_iter().map(|d| d.field).collect();
+        assert_eq!(
+            fields,
+            ["remote", "branch", "working_dir", "build_command", "token_file", "schedule"]
+        );
+
+        // scripts inside the repo are found relative to the build directory
+        config = project_config(&env);
+        config.steps = vec![BuildStep {
+            name: Some(String::from("deploy")),
+            command: BuildCommand::from("./deploy.sh"),
+            shell: false,
+        }];
+        assert_eq!(config.validate()[0].to_string(), "error: steps: deploy: ./deploy.sh isn't on PATH");
+        fs::write(env.project_dir.join("deploy.sh"), "#!/bin/sh\n").unwrap();
+        let mut perms = fs::metadata(env.project_dir.join("deploy.sh")).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o755);
+        fs::set_permissions(env.project_dir.join("deploy.sh"), perms).unwrap();
+        assert_eq!(config.validate(), vec![]);
+
+        config.path = env.home_dir.join("nowhere").to_str().unwrap().to_string();
+        assert_eq!(config.validate().len(), 1);
     }
+
+    #[test]
+    fn test_next_occurrence() {
+        let after = Utc::now();
+        let next = next_occurrence("*/5 * * * *", after).unwrap();
+        assert!(next > after);
+        assert!(next - after <= chrono::Duration::minutes(5));
+        assert_eq!(next.with_timezone(&Local).minute() % 5, 0);
+
+        let nightly = next_occurrence("0 3 * * *", after).unwrap();
+        assert_eq!(nightly.with_timezone(&Local).hour(), 3);
+        assert!(nightly - after <= chrono::Duration::days(1));
+
+        assert!(matches!(next_occurrence("0 25 * * *", after), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_config_rejects_unknown_fields() {
+        let result: serde_json::Result<Config> = serde_json::from_str(
+            r#"{"name":"a","path":"/a","branch":"main","remote":"origin","build_command":"make","buidl_dir":"x"}"#,
+        );
+        assert!(result.unwrap_err().to_string().contains("buidl_dir"));
+    }
+
+    #[test]
+    fn test_config_parse() {
+        // the oldest configs have no version and may leave out optional fields
+        let config = Config::parse(Format::Json, r#"{"name":"a","path":"/a","branch":"main"}"#).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.remote, "origin");
+        assert_eq!(config.build_command, BuildCommand::from(DEFAULT_BUILD_COMMAND));
+
+        let newer = format!(r#"{{"version":{},"name":"a","path":"/a","branch":"main"}}"#, CONFIG_VERSION + 1);
+        assert!(Config::parse(Format::Json, &newer).unwrap_err().to_string().contains("newer"));
+    }
+
+    #[test]
+    fn test_config_write() {
+        let env = setup_test_env("config_write");
+        let mut config = project_config(&env);
+        assert!(!Config::exists("test_repo").unwrap());
+        assert!(config.branch_exists().unwrap());
+
+        config.build_command = BuildCommand::from("make");
+        let path = config.write().unwrap();
+        assert_eq!(path, env.home_dir.join(".config/zlorbrs/configs/test_repo/config.json"));
+        assert!(Config::exists("test_repo").unwrap());
+        assert_eq!(Config::from_name("test_repo").unwrap().build_command, BuildCommand::from("make"));
+
+        config.branch = String::from("does-not-exist");
+        assert!(!config.branch_exists().unwrap());
+    }
+
+    #[test]
+    fn test_build_command_forms() {
+        let line: Config = serde_json::from_str(
+            r#"{ "name": "a", "path": "/a", "branch": "main", "remote": "origin",
+                 "build_command": "bun run build" }"#,
+        )
+        .unwrap();
+        assert_eq!(line.build_command, BuildCommand::from("bun run build"));
+        assert!(!line.shell);
+
+        let args: Config = serde_json::from_str(
+            r#"{ "name": "a", "path": "/a", "branch": "main", "remote": "origin",
+                 "build_command": ["bun", "run", "build"], "shell": true }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            args.build_command,
+            BuildCommand::Args(vec![
+                String::from("bun"),
+                String::from("run"),
+                String::from("build")
+            ])
+        );
+        assert!(args.shell);
+    }
+
+    #[test]
+    fn test_build_command_to_command() {
+        let command = BuildCommand::from("bun run 'my build'")
+            .to_command(false)
+            .unwrap();
+        assert_eq!(command.get_program(), "bun");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["run", "my build"]);
+
+        let command = BuildCommand::from("bun run build && echo done")
+            .to_command(true)
+            .unwrap();
+        assert_eq!(command.get_program(), "/bin/sh");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-c", "bun run build && echo done"]);
+
+        let command = BuildCommand::Args(vec![String::from("echo"), String::from("a b")])
+            .to_command(true)
+            .unwrap();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-c", "echo 'a b'"]);
+
+        assert!(BuildCommand::from("").to_command(false).is_err());
+        assert!(BuildCommand::from("echo 'oops").to_command(false).is_err());
+    }
+
+    #[test]
+    fn test_build_steps() {
+        let config: Config = serde_json::from_str(
+            r#"{ "name": "a", "path": "/a", "branch": "main", "remote": "origin",
+                 "build_command": "bun run build",
+                 "steps": [
+                    { "name": "install", "command": "bun install" },
+                    { "command": ["bun", "test"] },
+                    { "name": "build", "command": "bun run build | tee out.log", "shell": true }
+                 ] }"#,
+        )
+        .unwrap();
+
+        let steps = config.build_steps();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].to_string(), "install");
+        assert_eq!(steps[1].to_string(), "bun test");
+        assert!(steps[2].shell);
+
+        let single = Config {
+            build_command: BuildCommand::from("make"),
+            ..Default::default()
+        };
+        assert_eq!(
+            single.build_steps(),
+            vec![BuildStep {
+                name: None,
+                command: BuildCommand::from("make"),
+                shell: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let vars = parse_env_file(
+            "# comment\n\nNODE_ENV=production\nexport API_KEY=\"abc=123\"\nEMPTY=\nQUOTED='a b'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                (String::from("NODE_ENV"), String::from("production")),
+                (String::from("API_KEY"), String::from("abc=123")),
+                (String::from("EMPTY"), String::new()),
+                (String::from("QUOTED"), String::from("a b")),
+            ]
+        );
+
+        assert!(parse_env_file("NODE_ENV").is_err());
+        assert!(parse_env_file("=value").is_err());
+    }
+
+    #[test]
+    fn test_build_env() {
+        let env = setup_test_env("build_env");
+        fs::write(env.project_dir.join(".env"), "NODE_ENV=development\nAPI=1\n").unwrap();
+
+        let mut config = project_config(&env);
+        config.env_file = Some(String::from(".env"));
+        config
+            .env
+            .insert(String::from("NODE_ENV"), String::from("production"));
+
+        let vars = config.build_env().unwrap();
+        assert_eq!(vars.get("NODE_ENV").unwrap(), "production");
+        assert_eq!(vars.get("API").unwrap(), "1");
+
+        config.env_file = Some(String::from("missing.env"));
+        assert!(matches!(config.build_env(), Err(Error::Config(_))));
+
+        // nothing outside the repo, not even through a symlink
+        let outside = env.home_dir.join("other.env");
+        fs::write(&outside, "SECRET=1\n").unwrap();
+        std::os::unix::fs::symlink(&outside, env.project_dir.join("link.env")).unwrap();
+        for env_file in ["link.env", "../zlorbrs_home_build_env/other.env"] {
+            config.env_file = Some(String::from(env_file));
+            let message = config.build_env().unwrap_err().to_string();
+            assert!(message.contains("inside the repo"), "{message}");
+        }
+        // unless the central config asks for it by absolute path
+        config.env_file = Some(outside.to_str().unwrap().to_string());
+        assert_eq!(config.build_env().unwrap().get("SECRET").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_build_dir() {
+        let mut config = Config {
+            path: String::from("/srv/repo"),
+            ..Default::default()
+        };
+        assert_eq!(config.build_dir().unwrap(), PathBuf::from("/srv/repo"));
+
+        config.working_dir = Some(String::from("packages/web"));
+        assert_eq!(
+            config.build_dir().unwrap(),
+            PathBuf::from("/srv/repo/packages/web")
+        );
+
+        config.working_dir = Some(String::from("../other"));
+        assert!(config.build_dir().is_err());
+        config.working_dir = Some(String::from("/etc"));
+        assert!(config.build_dir().is_err());
+    }
+
+    #[test]
+    fn test_build_dir_symlinks() {
+        let dir = env::temp_dir().join("zlorbrs_config_build_dir_symlinks");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = dir.join("repo");
+        fs::create_dir_all(repo.join("packages/web")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), repo.join("escape")).unwrap();
+        std::os::unix::fs::symlink("packages/web", repo.join("web")).unwrap();
+
+        let mut config = Config {
+            path: repo.to_str().unwrap().to_string(),
+            working_dir: Some(String::from("web")),
+            ..Default::default()
+        };
+        // a symlink to somewhere in the repo is fine
+        assert_eq!(config.build_dir().unwrap(), repo.join("packages/web"));
+
+        config.working_dir = Some(String::from("escape"));
+        assert!(matches!(config.build_dir(), Err(Error::Config(_))));
+        config.working_dir = Some(String::from("escape/deeper"));
+        fs::create_dir_all(dir.join("outside/deeper")).unwrap();
+        assert!(matches!(config.build_dir(), Err(Error::Config(_))));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
 }
@@ -0,0 +1,329 @@
+use std::{fs, path::PathBuf};
+
+use git2::{Cred, CredentialType, RemoteCallbacks, Repository};
+use log::{debug, error};
+
+use crate::{config::Config, get_home_dir, paths};
+
+/// Username used for token authentication when neither the config nor the
+/// remote url provide one. Most forges accept any non-empty name with a token.
+const TOKEN_USERNAME: &str = "x-access-token";
+
+/// Username used for ssh authentication when neither the config nor the
+/// remote url provide one.
+const SSH_USERNAME: &str = "git";
+
+/// The ways we know how to authenticate against a remote, in the order
+/// they are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Strategy {
+    Username,
+    SshKeyFile,
+    SshAgent,
+    TokenFile,
+    CredentialHelper,
+    Default,
+}
+
+const STRATEGIES: [Strategy; 6] = [
+    Strategy::Username,
+    Strategy::SshKeyFile,
+    Strategy::SshAgent,
+    Strategy::TokenFile,
+    Strategy::CredentialHelper,
+    Strategy::Default,
+];
+
+/// .
+///
+/// # Resolves credentials for a repo
+///
+/// git2 calls the credentials callback again every time the previous
+/// credential is rejected, so the resolver remembers what it already handed
+/// out and moves on to the next strategy instead of looping forever.
+pub struct CredentialResolver<'a> {
+    config: &'a Config,
+    git_config: Option<git2::Config>,
+    tried: Vec<Strategy>,
+}
+
+impl<'a> CredentialResolver<'a> {
+    pub fn new(repo: &Repository, config: &'a Config) -> Self {
+        let git_config = match helper_config(repo, paths::system()) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                error!("Failed to read git config, credential helpers are disabled: {e}");
+                None
+            }
+        };
+        Self {
+            config,
+            git_config,
+            tried: Vec::new(),
+        }
+    }
+
+    /// Returns the next credential to try for `url`, or an error once every
+    /// strategy allowed by `allowed` has been used up.
+    pub fn resolve(
+        &mut self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        for strategy in STRATEGIES {
+            if self.tried.contains(&strategy) || !Self::is_allowed(strategy, allowed) {
+                continue;
+            }
+            self.tried.push(strategy);
+
+            match self.attempt(strategy, url, username_from_url) {
+                Some(Ok(cred)) => {
+                    debug!("Trying {strategy:?} credentials for {url}");
+                    return Ok(cred);
+                }
+                Some(Err(e)) => debug!("{strategy:?} credentials unavailable for {url}: {e}"),
+                None => {}
+            }
+        }
+
+        Err(git2::Error::from_str(&format!(
+            "No usable credentials left for {url}"
+        )))
+    }
+
+    fn is_allowed(strategy: Strategy, allowed: CredentialType) -> bool {
+        match strategy {
+            Strategy::Username => allowed.contains(CredentialType::USERNAME),
+            Strategy::SshKeyFile | Strategy::SshAgent => allowed.contains(CredentialType::SSH_KEY),
+            Strategy::TokenFile | Strategy::CredentialHelper => {
+                allowed.contains(CredentialType::USER_PASS_PLAINTEXT)
+            }
+            Strategy::Default => allowed.contains(CredentialType::DEFAULT),
+        }
+    }
+
+    /// Builds the credential for a single strategy. `None` means the strategy
+    /// isn't configured for this repo and should be skipped silently.
+    fn attempt(
+        &self,
+        strategy: Strategy,
+        url: &str,
+        username_from_url: Option<&str>,
+    ) -> Option<Result<Cred, git2::Error>> {
+        let username = self.config.username.as_deref().or(username_from_url);
+
+        match strategy {
+            Strategy::Username => Some(Cred::username(username.unwrap_or(SSH_USERNAME))),
+            Strategy::SshKeyFile => {
+                let key = match expand_home(self.config.ssh_key_path.as_ref()?) {
+                    Ok(key) => key,
+                    Err(e) => return Some(Err(e)),
+                };
+                Some(Cred::ssh_key(
+                    username.unwrap_or(SSH_USERNAME),
+                    None,
+                    &key,
+                    None,
+                ))
+            }
+            Strategy::SshAgent => Some(Cred::ssh_key_from_agent(username.unwrap_or(SSH_USERNAME))),
+            Strategy::TokenFile => {
+                let token_file = match expand_home(self.config.token_file.as_ref()?) {
+                    Ok(token_file) => token_file,
+                    Err(e) => return Some(Err(e)),
+                };
+                Some(match fs::read_to_string(&token_file) {
+                    Ok(token) => {
+                        Cred::userpass_plaintext(username.unwrap_or(TOKEN_USERNAME), token.trim())
+                    }
+                    Err(e) => Err(git2::Error::from_str(&format!(
+                        "failed to read token file {}: {e}",
+                        token_file.display()
+                    ))),
+                })
+            }
+            Strategy::CredentialHelper => Some(Cred::credential_helper(
+                self.git_config.as_ref()?,
+                url,
+                username,
+            )),
+            Strategy::Default => Some(Cred::default()),
+        }
+    }
+}
+
+/// .
+///
+/// # The git config credential helpers are taken from
+///
+/// Normally the repo's own. A system-wide service runs as root in
+/// repositories that belong to other users, who could set up a helper that
+/// runs anything, so there it only takes the system and global configs
+fn helper_config(repo: &Repository, system: bool) -> Result<git2::Config, git2::Error> {
+    if system {
+        git2::Config::open_default()
+    } else {
+        repo.config()
+    }
+}
+
+/// .
+///
+/// # Builds remote callbacks for a repo
+///
+/// Wires a [`CredentialResolver`] into a fresh set of callbacks so fetches
+/// honour the repo's ssh key, token file, ssh agent and git credential helpers
+pub fn remote_callbacks<'a>(repo: &Repository, config: &'a Config) -> RemoteCallbacks<'a> {
+    let mut resolver = CredentialResolver::new(repo, config);
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username_from_url, allowed| {
+        resolver.resolve(url, username_from_url, allowed)
+    });
+    callbacks
+}
+
+pub(crate) fn expand_home(path: &str) -> Result<PathBuf, git2::Error> {
+    match path.strip_prefix("~/") {
+        Some(rest) => match get_home_dir() {
+            Ok(home) => Ok(PathBuf::from(home).join(rest)),
+            Err(e) => Err(git2::Error::from_str(&e.to_string())),
+        },
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const URL: &str = "https://example.com/repo.git";
+
+    struct TestRepo {
+        dir: PathBuf,
+        repo: Repository,
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn setup_repo(test_name: &str) -> TestRepo {
+        let mut dir = env::temp_dir();
+        dir.push(format!("zlorbrs_credentials_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+        TestRepo { dir, repo }
+    }
+
+    fn credtype(cred: &Cred) -> u32 {
+        cred.credtype()
+    }
+
+    #[test]
+    fn test_token_file_used_for_plaintext() {
+        let test_repo = setup_repo("token_file");
+        let token_path = test_repo.dir.join("token");
+        fs::write(&token_path, "secret-token\n").unwrap();
+
+        let config = Config {
+            token_file: Some(token_path.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let mut resolver = CredentialResolver::new(&test_repo.repo, &config);
+
+        let cred = resolver
+            .resolve(URL, None, CredentialType::USER_PASS_PLAINTEXT)
+            .unwrap();
+        assert_eq!(credtype(&cred), CredentialType::USER_PASS_PLAINTEXT.bits());
+        assert!(cred.has_username());
+    }
+
+    #[test]
+    fn test_ssh_key_file_then_agent() {
+        let test_repo = setup_repo("ssh_key");
+        let config = Config {
+            ssh_key_path: Some(
+                test_repo
+                    .dir
+                    .join("id_ed25519")
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let mut resolver = CredentialResolver::new(&test_repo.repo, &config);
+
+        let first = resolver
+            .resolve(URL, Some("git"), CredentialType::SSH_KEY)
+            .unwrap();
+        assert_eq!(credtype(&first), CredentialType::SSH_KEY.bits());
+        assert_eq!(resolver.tried, vec![Strategy::SshKeyFile]);
+
+        let second = resolver
+            .resolve(URL, Some("git"), CredentialType::SSH_KEY)
+            .unwrap();
+        assert_eq!(credtype(&second), CredentialType::SSH_KEY.bits());
+        assert_eq!(
+            resolver.tried,
+            vec![Strategy::SshKeyFile, Strategy::SshAgent]
+        );
+
+        // everything allowed has been tried, so we must give up instead of looping
+        assert!(
+            resolver
+                .resolve(URL, Some("git"), CredentialType::SSH_KEY)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_unconfigured_strategies_are_skipped() {
+        let test_repo = setup_repo("unconfigured");
+        let config = Config::default();
+        let mut resolver = CredentialResolver::new(&test_repo.repo, &config);
+
+        // no token file and no key path, so only the agent is left for ssh
+        let cred = resolver
+            .resolve(URL, None, CredentialType::SSH_KEY)
+            .unwrap();
+        assert_eq!(credtype(&cred), CredentialType::SSH_KEY.bits());
+        assert_eq!(
+            resolver.tried,
+            vec![Strategy::SshKeyFile, Strategy::SshAgent]
+        );
+    }
+
+    #[test]
+    fn test_helper_config_without_repo_with_system() {
+        let test_repo = setup_repo("helper_config");
+        let mut local = test_repo.repo.config().unwrap();
+        local.set_str("zlorbrs.test", "local").unwrap();
+
+        let config = helper_config(&test_repo.repo, false).unwrap();
+        assert_eq!(config.get_string("zlorbrs.test").unwrap(), "local");
+        // whoever owns the repository doesn't get to pick what root runs
+        let config = helper_config(&test_repo.repo, true).unwrap();
+        assert!(config.get_string("zlorbrs.test").is_err());
+    }
+
+    #[test]
+    fn test_missing_token_file_falls_through() {
+        let test_repo = setup_repo("missing_token");
+        let config = Config {
+            token_file: Some(test_repo.dir.join("nope").to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let mut resolver = CredentialResolver::new(&test_repo.repo, &config);
+
+        // the token file can't be read, so we move on to the credential helper
+        let _ = resolver.resolve(URL, None, CredentialType::USER_PASS_PLAINTEXT);
+        assert!(resolver.tried.contains(&Strategy::TokenFile));
+        assert!(resolver.tried.contains(&Strategy::CredentialHelper));
+    }
+}
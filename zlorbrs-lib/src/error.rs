@@ -0,0 +1,84 @@
+use std::{fmt, io};
+
+/// Everything that can go wrong while loading configs, talking to git or
+/// running builds.
+#[derive(Debug)]
+pub enum Error {
+    /// A config file is missing, malformed or describes something impossible
+    Config(String),
+    Git(git2::Error),
+    Io(io::Error),
+    /// The build command couldn't be started or didn't succeed
+    Build(String),
+    /// The service couldn't be reached or answered with something unexpected
+    Ipc(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "config error: {msg}"),
+            Error::Git(e) => write!(f, "git error: {e}"),
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Build(msg) => write!(f, "build error: {msg}"),
+            Error::Ipc(msg) => write!(f, "ipc error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Git(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Config(_) | Error::Build(_) | Error::Ipc(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(e: git2::Error) -> Self {
+        Error::Git(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::Config(String::from("missing branch"));
+        assert_eq!(err.to_string(), "config error: missing branch");
+
+        let err = Error::Build(String::from("exited with 2"));
+        assert_eq!(err.to_string(), "build error: exited with 2");
+    }
+
+    #[test]
+    fn test_error_from_io() {
+        let err: Error = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+        assert!(matches!(err, Error::Io(_)));
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn test_error_from_serde() {
+        let err: Error = serde_json::from_str::<u32>("nope").unwrap_err().into();
+        assert!(matches!(err, Error::Config(_)));
+    }
+}
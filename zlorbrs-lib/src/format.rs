@@ -0,0 +1,155 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{Error, Result};
+
+/// The file formats configs can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    Toml,
+    Yaml, // .yaml or .yml
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Json, Format::Toml, Format::Yaml];
+
+    /// Tells the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+        }
+    }
+
+    /// Parses `contents` into a JSON value so every format goes through the
+    /// same migrations and checks
+    pub fn parse(self, contents: &str) -> Result<Value> {
+        match self {
+            Format::Json => Ok(serde_json::from_str(contents)?),
+            Format::Toml => toml::from_str(contents).map_err(|e| Error::Config(e.to_string())),
+            Format::Yaml => {
+                serde_yaml::from_str(contents).map_err(|e| Error::Config(e.to_string()))
+            }
+        }
+    }
+
+    /// Serializes `value` the way a person would write it by hand
+    pub fn to_string_pretty<T: Serialize>(self, value: &T) -> Result<String> {
+        match self {
+            Format::Json => Ok(serde_json::to_string_pretty(value)? + "\n"),
+            Format::Toml => toml::to_string_pretty(value).map_err(|e| Error::Config(e.to_string())),
+            Format::Yaml => serde_yaml::to_string(value).map_err(|e| Error::Config(e.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Format::from_path(Path::new(&format!("config.{s}")))
+            .ok_or_else(|| format!("unknown format {s:?}, expected json, toml or yaml"))
+    }
+}
+
+/// .
+///
+/// # Finds the config called `stem` in `dir`, whatever its format
+///
+/// Looks for `<stem>.json`, `.toml`, `.yaml` and `.yml`. Errors when there
+/// is more than one, since it isn't clear which is meant
+pub fn find(dir: &Path, stem: &str) -> Result<Option<PathBuf>> {
+    let found: Vec<PathBuf> = ["json", "toml", "yaml", "yml"]
+        .iter()
+        .map(|extension| dir.join(format!("{stem}.{extension}")))
+        .filter(|path| path.is_file())
+        .collect();
+    match found.as_slice() {
+        [] => Ok(None),
+        [path] => Ok(Some(path.clone())),
+        [first, second, ..] => Err(Error::Config(format!(
+            "both {} and {} exist, remove one of them",
+            first.display(),
+            second.display()
+        ))),
+    }
+}
+
+/// Like [`find`], but a missing config is an error
+pub fn find_existing(dir: &Path, stem: &str) -> Result<PathBuf> {
+    find(dir, stem)?.ok_or_else(|| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no {stem}.json, .toml or .yaml in {}", dir.display()),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn test_formats_round_trip() {
+        let value: Value = serde_json::from_str(
+            r#"{"name":"a","env":{"NODE_ENV":"production"},"steps":[{"command":["make"],"shell":false}]}"#,
+        )
+        .unwrap();
+        for format in Format::ALL {
+            let contents = format.to_string_pretty(&value).unwrap();
+            assert_eq!(format.parse(&contents).unwrap(), value, "{format}");
+        }
+        assert!(Format::Toml.parse("name = ").is_err());
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("yml".parse::<Format>().unwrap(), Format::Yaml);
+        assert_eq!("toml".parse::<Format>().unwrap(), Format::Toml);
+        assert!("ini".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_find() {
+        let mut dir = env::temp_dir();
+        dir.push("zlorbrs_format_find");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        assert!(find(&dir, "config").unwrap().is_none());
+        assert!(matches!(find_existing(&dir, "config"), Err(Error::Io(_))));
+
+        fs::write(dir.join("config.yml"), "name: a\n").unwrap();
+        assert_eq!(find(&dir, "config").unwrap(), Some(dir.join("config.yml")));
+
+        fs::write(dir.join("config.toml"), "name = \"a\"\n").unwrap();
+        assert!(find(&dir, "config").is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, Result,
+    build::{BuildResult, Stopped},
+    paths,
+};
+
+/// Why a build was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    NewCommit,        // the remote branch moved
+    MissingArtifacts, // the build output is missing
+    Manual,           // asked for through zlorbrs-ctl
+    Scheduled,        // the repo's schedule came due
+}
+
+/// How a build ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStatus {
+    Success,
+    Failed,    // a build step exited unsuccessfully
+    Error,     // the build couldn't be run at all
+    TimedOut,  // stopped for taking longer than build_timeout
+    Cancelled, // stopped on request
+}
+
+/// One line of a repo's build history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub id: u64, // same as the build log's id
+    pub repo: String,
+    pub trigger: Trigger,
+    pub old_oid: Option<String>, // local branch before fetching
+    pub new_oid: Option<String>, // remote branch that got built
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub status: BuildStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_step: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>, // why the build couldn't be run
+}
+
+impl BuildRecord {
+    /// Records a build that ran, successfully or not
+    pub fn from_result(
+        id: u64,
+        repo: &str,
+        trigger: Trigger,
+        old_oid: Option<String>,
+        new_oid: Option<String>,
+        result: &BuildResult,
+    ) -> Self {
+        Self {
+            id,
+            repo: String::from(repo),
+            trigger,
+            old_oid,
+            new_oid,
+            started_at: result.started_at.into(),
+            duration_ms: result.duration.as_millis() as u64,
+            status: match result.stopped {
+                Some(Stopped::TimedOut) => BuildStatus::TimedOut,
+                Some(Stopped::Cancelled) => BuildStatus::Cancelled,
+                None if result.success() => BuildStatus::Success,
+                None => BuildStatus::Failed,
+            },
+            exit_code: result.exit_code,
+            failed_step: result.failed_step.clone(),
+            error: None,
+        }
+    }
+
+    /// Records a build that couldn't be run, e.g. because its command is missing
+    pub fn from_error(
+        id: u64,
+        repo: &str,
+        trigger: Trigger,
+        old_oid: Option<String>,
+        new_oid: Option<String>,
+        started_at: SystemTime,
+        error: &Error,
+    ) -> Self {
+        Self {
+            id,
+            repo: String::from(repo),
+            trigger,
+            old_oid,
+            new_oid,
+            started_at: started_at.into(),
+            duration_ms: started_at.elapsed().map_or(0, |d| d.as_millis() as u64),
+            status: BuildStatus::Error,
+            exit_code: None,
+            failed_step: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::NewCommit => write!(f, "new_commit"),
+            Trigger::MissingArtifacts => write!(f, "missing_artifacts"),
+            Trigger::Manual => write!(f, "manual"),
+            Trigger::Scheduled => write!(f, "scheduled"),
+        }
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "new_commit" => Ok(Trigger::NewCommit),
+            "missing_artifacts" => Ok(Trigger::MissingArtifacts),
+            "manual" => Ok(Trigger::Manual),
+            "scheduled" => Ok(Trigger::Scheduled),
+            _ => Err(format!(
+                "unknown trigger {s:?}, expected new_commit, missing_artifacts, manual or scheduled"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildStatus::Success => write!(f, "success"),
+            BuildStatus::Failed => write!(f, "failed"),
+            BuildStatus::Error => write!(f, "error"),
+            BuildStatus::TimedOut => write!(f, "timed_out"),
+            BuildStatus::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl FromStr for BuildStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(BuildStatus::Success),
+            "failed" => Ok(BuildStatus::Failed),
+            "error" => Ok(BuildStatus::Error),
+            "timed_out" => Ok(BuildStatus::TimedOut),
+            "cancelled" => Ok(BuildStatus::Cancelled),
+            _ => Err(format!(
+                "unknown status {s:?}, expected success, failed, error, timed_out or cancelled"
+            )),
+        }
+    }
+}
+
+/// Which records [`query`] returns. Empty fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub status: Option<BuildStatus>,
+    pub trigger: Option<Trigger>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    fn matches(&self, record: &BuildRecord) -> bool {
+        self.status.is_none_or(|status| record.status == status)
+            && self.trigger.is_none_or(|trigger| record.trigger == trigger)
+            && self.since.is_none_or(|since| record.started_at >= since)
+    }
+}
+
+/// File holding the build history of `repo_name`, one JSON record per line
+pub fn history_path(repo_name: &str) -> Result<PathBuf> {
+    Ok(paths::state_dir()?
+        .join("history")
+        .join(format!("{repo_name}.jsonl")))
+}
+
+/// Appends `record` to its repo's history
+pub fn append(record: &BuildRecord) -> Result<()> {
+    let path = history_path(&record.repo)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    // a single write keeps lines whole even if two writers race
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// .
+///
+/// # Reads a repo's build history
+///
+/// Oldest build first. Lines that can't be parsed, e.g. from a write cut
+/// short by a crash, are logged and skipped
+pub fn read(repo_name: &str) -> Result<Vec<BuildRecord>> {
+    let path = history_path(repo_name)?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut records = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(e) => error!("Skipping line {} of {}: {e}", number + 1, path.display()),
+        }
+    }
+    Ok(records)
+}
+
+/// Records of `repo_name` matching `filter`, newest build first
+pub fn query(repo_name: &str, filter: &Filter) -> Result<Vec<BuildRecord>> {
+    let records = read(repo_name)?
+        .into_iter()
+        .rev()
+        .filter(|record| filter.matches(record))
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .collect();
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_test_utils::ENV_MUTEX;
+    use chrono::TimeZone;
+    use std::env;
+
+    struct TestEnv {
+        home_dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.home_dir);
+        }
+    }
+
+    fn setup_test_env(test_name: &str) -> TestEnv {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push(format!("zlorbrs_history_{}", test_name));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        TestEnv {
+            home_dir,
+            _lock: lock,
+        }
+    }
+
+    fn record(id: u64, day: u32, trigger: Trigger, status: BuildStatus) -> BuildRecord {
+        BuildRecord {
+            id,
+            repo: String::from("test_repo"),
+            trigger,
+            old_oid: Some(String::from("aaaa")),
+            new_oid: Some(String::from("bbbb")),
+            started_at: Utc.with_ymd_and_hms(2025, 1, day, 12, 0, 0).unwrap(),
+            duration_ms: 1500,
+            status,
+            exit_code: Some(0),
+            failed_step: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let env = setup_test_env("append_read");
+        let first = record(1, 1, Trigger::MissingArtifacts, BuildStatus::Success);
+        let second = record(2, 2, Trigger::NewCommit, BuildStatus::Failed);
+        append(&first).unwrap();
+        append(&second).unwrap();
+
+        assert_eq!(read("test_repo").unwrap(), vec![first, second]);
+        assert!(read("other_repo").unwrap().is_empty());
+
+        let path = env.home_dir.join(".config/zlorbrs/history/test_repo.jsonl");
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_read_skips_broken_lines() {
+        let _env = setup_test_env("broken_lines");
+        append(&record(1, 1, Trigger::NewCommit, BuildStatus::Success)).unwrap();
+
+        let path = history_path("test_repo").unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"id\": 2, \"repo\"").unwrap();
+
+        assert_eq!(read("test_repo").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let _env = setup_test_env("query");
+        append(&record(
+            1,
+            1,
+            Trigger::MissingArtifacts,
+            BuildStatus::Success,
+        ))
+        .unwrap();
+        append(&record(2, 2, Trigger::NewCommit, BuildStatus::Failed)).unwrap();
+        append(&record(3, 3, Trigger::NewCommit, BuildStatus::Success)).unwrap();
+
+        let ids = |filter: Filter| -> Vec<u64> {
+            query("test_repo", &filter)
+                .unwrap()
+                .iter()
+                .map(|r| r.id)
+                .collect()
+        };
+
+        assert_eq!(ids(Filter::default()), [3, 2, 1]);
+        assert_eq!(
+            ids(Filter {
+                status: Some(BuildStatus::Success),
+                ..Default::default()
+            }),
+            [3, 1]
+        );
+        assert_eq!(
+            ids(Filter {
+                trigger: Some(Trigger::NewCommit),
+                limit: Some(1),
+                ..Default::default()
+            }),
+            [3]
+        );
+        assert_eq!(
+            ids(Filter {
+                since: Some(Utc.with_ymd_and_hms(2025, 1, 2, 0, 0, 0).unwrap()),
+                ..Default::default()
+            }),
+            [3, 2]
+        );
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!("failed".parse::<BuildStatus>(), Ok(BuildStatus::Failed));
+        assert_eq!("new_commit".parse::<Trigger>(), Ok(Trigger::NewCommit));
+        assert!("nope".parse::<BuildStatus>().is_err());
+        assert_eq!(BuildStatus::Error.to_string(), "error");
+        assert_eq!(
+            "timed_out".parse::<BuildStatus>(),
+            Ok(BuildStatus::TimedOut)
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result, paths};
+
+/// How long the client waits for the service to answer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something zlorbrs-ctl asks the service to do. Sent as one JSON object per
+/// line, e.g. `{"command":"pause","repo":"my-repo"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Check { repo: String }, // fetch and build if needed, right now
+    Build { repo: String }, // build right now, whether or not anything changed
+    Pause { repo: String }, // skip the repo until it is resumed
+    Resume { repo: String },
+    Cancel { repo: String }, // stop the repo's running build
+    Reload,                  // re-read the service config
+}
+
+/// The service's answer to a [`Request`], also one JSON object per line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok { message: String },
+    Status(ServiceStatus),
+    Error { message: String },
+}
+
+/// A snapshot of the running service.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub pid: u32,
+    pub started_at: DateTime<Utc>,
+    pub config_dir: String,
+    pub repos: Vec<RepoStatus>,
+}
+
+/// What the service knows about one configured repo.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RepoStatus {
+    pub name: String,
+    pub paused: bool,
+    pub checking: bool, // a check or build is running right now
+    pub last_checked: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+/// Where the service listens for zlorbrs-ctl
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("zlorbrs.sock"))
+}
+
+/// .
+///
+/// # Sends a request to the service
+///
+/// Connects to the socket at `path`, sends `request` and waits for the
+/// answer. Errors with [`Error::Ipc`] when the service isn't running
+pub fn send_to(path: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(path).map_err(|e| {
+        Error::Ipc(format!(
+            "Couldn't reach the service at {}, is it running? {e}",
+            path.display()
+        ))
+    })?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    write_message(&stream, request)?;
+    match read_message(&mut BufReader::new(&stream))? {
+        Some(response) => Ok(response),
+        None => Err(Error::Ipc(String::from(
+            "The service closed the connection without answering",
+        ))),
+    }
+}
+
+/// Sends `request` to the service listening on the default socket
+pub fn send(request: &Request) -> Result<Response> {
+    send_to(&socket_path()?, request)
+}
+
+/// Writes `message` as a single JSON line
+pub fn write_message<T: Serialize>(mut stream: &UnixStream, message: &T) -> Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads the next JSON line, `None` once the other side hung up
+pub fn read_message<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    serde_json::from_str(&line)
+        .map(Some)
+        .map_err(|e| Error::Ipc(format!("Malformed message {:?}: {e}", line.trim())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn test_request_wire_format() {
+        let line = serde_json::to_string(&Request::Pause {
+            repo: String::from("my-repo"),
+        })
+        .unwrap();
+        assert_eq!(line, r#"{"command":"pause","repo":"my-repo"}"#);
+
+        let request: Request = serde_json::from_str(r#"{"command":"reload"}"#).unwrap();
+        assert_eq!(request, Request::Reload);
+    }
+
+    #[test]
+    fn test_response_wire_format() {
+        let line = serde_json::to_string(&Response::Error {
+            message: String::from("nope"),
+        })
+        .unwrap();
+        assert_eq!(line, r#"{"result":"error","message":"nope"}"#);
+    }
+
+    #[test]
+    fn test_send_round_trip() {
+        let mut dir = env::temp_dir();
+        dir.push("zlorbrs_ipc_round_trip");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("zlorbrs.sock");
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let request: Request = read_message(&mut BufReader::new(&stream)).unwrap().unwrap();
+            let response = Response::Ok {
+                message: format!("{request:?}"),
+            };
+            write_message(&stream, &response).unwrap();
+        });
+
+        let response = send_to(
+            &path,
+            &Request::Check {
+                repo: String::from("my-repo"),
+            },
+        )
+        .unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            response,
+            Response::Ok {
+                message: String::from(r#"Check { repo: "my-repo" }"#)
+            }
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_send_without_service() {
+        let mut path = env::temp_dir();
+        path.push("zlorbrs_ipc_no_service.sock");
+        let _ = fs::remove_file(&path);
+
+        assert!(matches!(
+            send_to(&path, &Request::Status),
+            Err(Error::Ipc(_))
+        ));
+    }
+}
@@ -1,16 +1,27 @@
+pub mod build;
 pub mod config;
+pub mod credentials;
+pub mod error;
+pub mod format;
+pub mod history;
+pub mod ipc;
+pub mod logs;
+pub mod migrate;
+pub mod paths;
+pub mod pipeline;
+pub mod rebuild;
+pub mod service;
+pub mod users;
 
-use log::error;
+pub use error::{Error, Result};
 
-pub fn get_home_dir() -> String {
-    let home_dir = match std::env::home_dir() {
-        Some(x) => String::from(x.to_str().unwrap()),
-        None => {
-            error!("Failed to get the home directory");
-            panic!("Program exited due to previous error");
-        }
-    };
-    home_dir
+pub fn get_home_dir() -> Result<String> {
+    match std::env::home_dir() {
+        Some(x) => Ok(x.to_string_lossy().into_owned()),
+        None => Err(Error::Config(String::from(
+            "Failed to get the home directory",
+        ))),
+    }
 }
 
 pub mod shared_test_utils {
@@ -36,7 +47,7 @@ mod tests {
             env::set_var("HOME", tmp_dir.to_str().unwrap());
         }
 
-        let home = get_home_dir();
+        let home = get_home_dir().unwrap();
         assert_eq!(home, tmp_dir.to_str().unwrap());
 
         let _ = std::fs::remove_dir_all(tmp_dir);
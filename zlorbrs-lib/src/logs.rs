@@ -0,0 +1,357 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+
+use crate::{Result, paths};
+
+/// How many build logs are kept per repo and how large a single one may get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_logs: usize,
+    pub max_bytes: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_logs: 20,
+            max_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// .
+///
+/// # A build's log file
+///
+/// Writes stop once `max_bytes` is reached so a chatty build can't fill
+/// the disk, a note at the end of the file says it was truncated
+pub struct BuildLog {
+    pub id: u64,
+    pub path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    truncated: bool,
+}
+
+impl BuildLog {
+    /// .
+    ///
+    /// # Starts the next build log for a repo
+    ///
+    /// Build ids count up from 1 per repo and are never handed out twice, see
+    /// [`next_id`]. The log stays locked until it is dropped, which tells
+    /// readers it is still being written, see [`is_being_written`]. Older logs
+    /// beyond the retention limit are removed
+    pub fn create(repo_name: &str, retention: Retention) -> Result<Self> {
+        let dir = logs_dir(repo_name)?;
+        fs::create_dir_all(&dir)?;
+
+        let id = next_id(&dir)?;
+        let path = dir.join(format!("{id}.log"));
+        // never truncates a log someone else is writing
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        lock(&file, libc::LOCK_EX)?;
+        debug!("Writing build log to {}", path.display());
+
+        // never prune the log we just created
+        prune(repo_name, retention.max_logs.max(1))?;
+
+        Ok(Self {
+            id,
+            path,
+            file,
+            written: 0,
+            max_bytes: retention.max_bytes,
+            truncated: false,
+        })
+    }
+}
+
+/// .
+///
+/// # Takes the next build id for the logs in `dir`
+///
+/// The last id handed out is kept in `dir/last_id`, so ids aren't reused once
+/// the newest logs are pruned or deleted. The file is locked meanwhile, so
+/// builds starting at the same time get ids of their own. Logs from before
+/// the counter existed are counted too
+fn next_id(dir: &Path) -> Result<u64> {
+    let mut counter = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join("last_id"))?;
+    lock(&counter, libc::LOCK_EX)?;
+
+    let mut contents = String::new();
+    counter.read_to_string(&mut contents)?;
+    let last_counted = contents.trim().parse::<u64>().unwrap_or(0);
+    let newest_log = list_dir(dir)?.last().map_or(0, |(id, _)| *id);
+    let id = last_counted.max(newest_log) + 1;
+
+    counter.set_len(0)?;
+    counter.rewind()?;
+    counter.write_all(format!("{id}\n").as_bytes())?;
+    Ok(id)
+}
+
+/// Whether the build log at `path` is still being written to
+pub fn is_being_written(path: &Path) -> Result<bool> {
+    let file = File::open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+        return Ok(false);
+    }
+    let e = io::Error::last_os_error();
+    match e.kind() {
+        io::ErrorKind::WouldBlock => Ok(true),
+        _ => Err(e.into()),
+    }
+}
+
+/// Locks `file` for as long as it is open
+fn lock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Write for BuildLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.truncated {
+            return Ok(buf.len());
+        }
+        let remaining = self.max_bytes.saturating_sub(self.written);
+        if buf.len() as u64 > remaining {
+            self.file.write_all(&buf[..remaining as usize])?;
+            self.file
+                .write_all(b"\n[zlorbrs] log truncated, size limit reached\n")?;
+            self.written = self.max_bytes;
+            self.truncated = true;
+            return Ok(buf.len());
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Directory holding the build logs of `repo_name`
+pub fn logs_dir(repo_name: &str) -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("logs").join(repo_name))
+}
+
+/// Path of build `id`'s log for `repo_name`, whether or not it exists
+pub fn log_path(repo_name: &str, id: u64) -> Result<PathBuf> {
+    Ok(logs_dir(repo_name)?.join(format!("{id}.log")))
+}
+
+/// .
+///
+/// # Lists a repo's build logs
+///
+/// Returns `(build id, path)` pairs, oldest build first. A repo that never
+/// built has no logs
+pub fn list(repo_name: &str) -> Result<Vec<(u64, PathBuf)>> {
+    list_dir(&logs_dir(repo_name)?)
+}
+
+fn list_dir(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut logs = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|id| id.parse::<u64>().ok());
+        if let Some(id) = id {
+            logs.push((id, path));
+        }
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+/// Removes all but the newest `keep` build logs of `repo_name`
+pub fn prune(repo_name: &str, keep: usize) -> Result<()> {
+    let logs = list(repo_name)?;
+    let excess = logs.len().saturating_sub(keep);
+    for (id, path) in logs.into_iter().take(excess) {
+        info!("Removing old build log {id} of {repo_name}");
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_test_utils::ENV_MUTEX;
+    use std::env;
+
+    struct TestEnv {
+        home_dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.home_dir);
+        }
+    }
+
+    fn setup_test_env(test_name: &str) -> TestEnv {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push(format!("zlorbrs_logs_{}", test_name));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+
+        TestEnv {
+            home_dir,
+            _lock: lock,
+        }
+    }
+
+    #[test]
+    fn test_create_counts_up() {
+        let env = setup_test_env("counts_up");
+
+        let first = BuildLog::create("test_repo", Retention::default()).unwrap();
+        let second = BuildLog::create("test_repo", Retention::default()).unwrap();
+        assert_eq!(first.id, 1);
+        assert_eq!(second.id, 2);
+        assert_eq!(
+            second.path,
+            env.home_dir.join(".config/zlorbrs/logs/test_repo/2.log")
+        );
+
+        let ids: Vec<u64> = list("test_repo")
+            .unwrap()
+            .into_iter()
+            .map(|l| l.0)
+            .collect();
+        assert_eq!(ids, [1, 2]);
+        assert!(list("other_repo").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ids_are_never_reused() {
+        let env = setup_test_env("never_reused");
+        let logs = env.home_dir.join(".config/zlorbrs/logs/test_repo");
+
+        // a repo that built before there was a counter continues after its
+        // newest log
+        fs::create_dir_all(&logs).unwrap();
+        fs::write(logs.join("4.log"), "").unwrap();
+        let log = BuildLog::create("test_repo", Retention::default()).unwrap();
+        assert_eq!(log.id, 5);
+
+        // and the newest log going away doesn't give its id to the next build
+        fs::remove_file(&log.path).unwrap();
+        let log = BuildLog::create("test_repo", Retention::default()).unwrap();
+        assert_eq!(log.id, 6);
+    }
+
+    #[test]
+    fn test_create_at_once() {
+        let _env = setup_test_env("at_once");
+
+        let builds: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let mut log = BuildLog::create("test_repo", Retention::default()).unwrap();
+                    let id = log.id;
+                    write!(log, "build {id}").unwrap();
+                    log.id
+                })
+            })
+            .collect();
+        let mut ids: Vec<u64> = builds.into_iter().map(|b| b.join().unwrap()).collect();
+        ids.sort();
+        assert_eq!(ids, (1..=8).collect::<Vec<_>>());
+
+        // nobody's log was truncated by another build's
+        for (id, path) in list("test_repo").unwrap() {
+            assert_eq!(fs::read_to_string(path).unwrap(), format!("build {id}"));
+        }
+    }
+
+    #[test]
+    fn test_is_being_written() {
+        let _env = setup_test_env("being_written");
+
+        let log = BuildLog::create("test_repo", Retention::default()).unwrap();
+        let path = log.path.clone();
+        assert!(is_being_written(&path).unwrap());
+        drop(log);
+        assert!(!is_being_written(&path).unwrap());
+    }
+
+    #[test]
+    fn test_retention_keeps_newest() {
+        let _env = setup_test_env("retention");
+        let retention = Retention {
+            max_logs: 2,
+            ..Default::default()
+        };
+
+        for _ in 0..4 {
+            BuildLog::create("test_repo", retention).unwrap();
+        }
+
+        // ids keep counting even though old logs are gone
+        let ids: Vec<u64> = list("test_repo")
+            .unwrap()
+            .into_iter()
+            .map(|l| l.0)
+            .collect();
+        assert_eq!(ids, [3, 4]);
+    }
+
+    #[test]
+    fn test_size_limit_truncates() {
+        let _env = setup_test_env("size_limit");
+        let retention = Retention {
+            max_bytes: 8,
+            ..Default::default()
+        };
+
+        let mut log = BuildLog::create("test_repo", retention).unwrap();
+        log.write_all(b"12345").unwrap();
+        log.write_all(b"67890").unwrap();
+        log.write_all(b"more").unwrap();
+        log.flush().unwrap();
+
+        let contents = fs::read_to_string(&log.path).unwrap();
+        assert!(contents.starts_with("12345678\n"));
+        assert!(contents.contains("log truncated"));
+        assert!(!contents.contains("more"));
+    }
+}
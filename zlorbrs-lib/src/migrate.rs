@@ -0,0 +1,309 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    Error, Result,
+    config::{CONFIG_VERSION, Config},
+    format::{self, Format},
+    paths,
+    service::{SERVICE_CONFIG_VERSION, ServiceConfig},
+};
+
+/// Upgrades the fields of a config by one version, returning what it changed
+type Step = fn(&mut Map<String, Value>) -> Vec<String>;
+
+/// Which kind of config file is being migrated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schema {
+    Repo,    // configs/<name>/config.json, .toml or .yaml
+    Service, // service-config.json, .toml or .yaml
+}
+
+impl Schema {
+    /// The version this zlorbrs reads and writes
+    pub fn current(self) -> u32 {
+        match self {
+            Schema::Repo => CONFIG_VERSION,
+            Schema::Service => SERVICE_CONFIG_VERSION,
+        }
+    }
+
+    /// `steps()[n]` takes a config from version n to n + 1
+    fn steps(self) -> &'static [Step] {
+        match self {
+            Schema::Repo => &[versioned],
+            Schema::Service => &[versioned],
+        }
+    }
+
+    /// Serializes an upgraded config the way zlorbrs writes it, which also
+    /// makes sure it still parses
+    fn render(self, value: Value, format: Format) -> Result<String> {
+        match self {
+            Schema::Repo => format.to_string_pretty(&serde_json::from_value::<Config>(value)?),
+            Schema::Service => {
+                format.to_string_pretty(&serde_json::from_value::<ServiceConfig>(value)?)
+            }
+        }
+    }
+}
+
+/// Version 1 only added the `version` field itself
+fn versioned(_: &mut Map<String, Value>) -> Vec<String> {
+    Vec::new()
+}
+
+/// The version a config was written with, 0 for configs from before
+/// versioning
+fn version_of(map: &Map<String, Value>) -> Result<u32> {
+    match map.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| {
+                Error::Config(format!("version has to be a whole number, got {version}"))
+            }),
+    }
+}
+
+/// .
+///
+/// # Brings a parsed config up to the current version
+///
+/// Runs every step between the config's version and the current one and
+/// returns what changed, nothing when it was current already. Errors for
+/// configs written by a newer zlorbrs
+pub fn upgrade(schema: Schema, value: &mut Value) -> Result<Vec<String>> {
+    let Value::Object(map) = value else {
+        return Err(Error::Config(String::from("expected a JSON object")));
+    };
+    let version = version_of(map)?;
+    let current = schema.current();
+    if version > current {
+        return Err(Error::Config(format!(
+            "version {version} is newer than this zlorbrs knows ({current}), upgrade zlorbrs to use it"
+        )));
+    }
+
+    let mut changes = Vec::new();
+    if version < current {
+        changes.push(format!("version: {version} -> {current}"));
+    }
+    for (from, step) in schema.steps().iter().enumerate().skip(version as usize) {
+        changes.extend(step(map));
+        map.insert(String::from("version"), Value::from(from + 1));
+    }
+    Ok(changes)
+}
+
+/// A config file that is behind the current version.
+#[derive(Debug)]
+pub struct Migration {
+    pub path: PathBuf,
+    pub changes: Vec<String>,    // e.g. version: 0 -> 1
+    pub contents: String,        // the upgraded file
+    pub backup: Option<PathBuf>, // where the old file was copied to, None on a dry run
+}
+
+/// .
+///
+/// # Upgrades the config file at `path` in place
+///
+/// The file keeps its format, but is rewritten as zlorbrs would write it so
+/// comments don't survive. The old file is copied to `<path>.v<version>.bak`
+/// first, which keeps them, or to `<path>.v<version>.<n>.bak` when that
+/// backup exists already. With `dry_run`
+/// nothing is written and the result only shows what would change. Returns
+/// `None` for files that are current already
+pub fn migrate_file(schema: Schema, path: &Path, dry_run: bool) -> Result<Option<Migration>> {
+    let in_file = |e: Error| match e {
+        Error::Config(message) => Error::Config(format!("{}: {message}", path.display())),
+        e => e,
+    };
+    let format = Format::from_path(path).unwrap_or_default();
+    let original = fs::read_to_string(path)?;
+    let mut value = format.parse(&original).map_err(in_file)?;
+    let version = match &value {
+        Value::Object(map) => version_of(map).map_err(in_file)?,
+        _ => 0,
+    };
+    let changes = upgrade(schema, &mut value).map_err(in_file)?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    let contents = schema.render(value, format).map_err(in_file)?;
+
+    let backup = if dry_run {
+        None
+    } else {
+        let backup = write_backup(path, version, &original)?;
+        fs::write(path, &contents)?;
+        Some(backup)
+    };
+    Ok(Some(Migration {
+        path: path.to_path_buf(),
+        changes,
+        contents,
+        backup,
+    }))
+}
+
+/// Copies `original`, the file at `path`, to the first free backup name, so
+/// an earlier backup is never replaced
+fn write_backup(path: &Path, version: u32, original: &str) -> Result<PathBuf> {
+    let permissions = fs::metadata(path)?.permissions();
+    for n in 0.. {
+        let mut backup = path.as_os_str().to_owned();
+        match n {
+            0 => backup.push(format!(".v{version}.bak")),
+            n => backup.push(format!(".v{version}.{n}.bak")),
+        }
+        let backup = PathBuf::from(backup);
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&backup)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        };
+        file.set_permissions(permissions)?;
+        file.write_all(original.as_bytes())?;
+        return Ok(backup);
+    }
+    unreachable!("ran out of backup names")
+}
+
+/// .
+///
+/// # Upgrades every repo config and the service config
+///
+/// A config that can't be migrated doesn't stop the others, its error is
+/// returned in its place. Configs that are current already are left out
+pub fn migrate_all(dry_run: bool) -> Result<Vec<Result<Migration>>> {
+    let mut files = Vec::new();
+    let configs_dir = paths::configs_dir()?;
+    if configs_dir.exists() {
+        let mut dirs = fs::read_dir(&configs_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        dirs.sort();
+        files.extend(
+            dirs.into_iter()
+                .map(|dir| (Schema::Repo, format::find(&dir, "config"))),
+        );
+    }
+    files.push((
+        Schema::Service,
+        format::find(&paths::config_dir()?, "service-config"),
+    ));
+
+    Ok(files
+        .into_iter()
+        .filter_map(|(schema, file)| match file {
+            Ok(Some(file)) => migrate_file(schema, &file, dry_run).transpose(),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_test_utils::ENV_MUTEX;
+    use std::env;
+
+    #[test]
+    fn test_steps_reach_current() {
+        for schema in [Schema::Repo, Schema::Service] {
+            assert_eq!(schema.steps().len() as u32, schema.current());
+        }
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut value: Value = serde_json::from_str(r#"{ "sleep_time": 30 }"#).unwrap();
+        let changes = upgrade(Schema::Service, &mut value).unwrap();
+        assert_eq!(changes, vec![String::from("version: 0 -> 1")]);
+        assert_eq!(value["version"], 1);
+
+        // a second run has nothing left to do
+        assert!(upgrade(Schema::Service, &mut value).unwrap().is_empty());
+
+        let mut value: Value = serde_json::from_str(r#"{ "version": 99 }"#).unwrap();
+        let message = upgrade(Schema::Service, &mut value)
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("newer"));
+
+        let mut value: Value = serde_json::from_str(r#"{ "version": "one" }"#).unwrap();
+        assert!(upgrade(Schema::Repo, &mut value).is_err());
+    }
+
+    #[test]
+    fn test_migrate_all() {
+        let _lock = ENV_MUTEX.lock().unwrap();
+        let mut home_dir = env::temp_dir();
+        home_dir.push("zlorbrs_migrate_all");
+        let _ = fs::remove_dir_all(&home_dir);
+        unsafe {
+            env::set_var("HOME", home_dir.to_str().unwrap());
+        }
+        let zlorbrs_dir = home_dir.join(".config/zlorbrs");
+        fs::create_dir_all(zlorbrs_dir.join("configs/old")).unwrap();
+        fs::create_dir_all(zlorbrs_dir.join("configs/broken")).unwrap();
+
+        // what the very first zlorbrs wrote
+        let old = zlorbrs_dir.join("configs/old/config.json");
+        let old_contents = r#"{"name":"old","path":"/srv/old","branch":"main","remote":"origin","build_command":"bun run build"}"#;
+        fs::write(&old, old_contents).unwrap();
+        fs::write(zlorbrs_dir.join("configs/broken/config.json"), "{").unwrap();
+        let service_config = zlorbrs_dir.join("service-config.toml");
+        fs::write(&service_config, "sleep_time = 30\n").unwrap();
+
+        let migrations = migrate_all(true).unwrap();
+        assert_eq!(migrations.len(), 3);
+        let message = migrations[0].as_ref().unwrap_err().to_string();
+        assert!(message.contains("broken/config.json"));
+        let migration = migrations[1].as_ref().unwrap();
+        assert_eq!(migration.path, old);
+        assert!(migration.backup.is_none());
+        assert!(migration.contents.contains(r#""version": 1"#));
+        // a dry run leaves the files alone
+        assert_eq!(fs::read_to_string(&old).unwrap(), old_contents);
+        // and files keep their format
+        let migration = migrations[2].as_ref().unwrap();
+        assert_eq!(migration.path, service_config);
+        assert!(migration.contents.contains("version = 1"));
+
+        let migrations = migrate_all(false).unwrap();
+        let backup = migrations[1].as_ref().unwrap().backup.clone().unwrap();
+        assert_eq!(backup, zlorbrs_dir.join("configs/old/config.json.v0.bak"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), old_contents);
+        let config = Config::from_dir(&zlorbrs_dir.join("configs/old")).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.branch, "main");
+
+        // only the broken config is left
+        assert_eq!(migrate_all(false).unwrap().len(), 1);
+
+        // migrating an old file again keeps the first backup
+        let edited = old_contents.replace("main", "live");
+        fs::write(&old, &edited).unwrap();
+        let migrations = migrate_all(false).unwrap();
+        let second = migrations[1].as_ref().unwrap().backup.clone().unwrap();
+        assert_eq!(second, zlorbrs_dir.join("configs/old/config.json.v0.1.bak"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), old_contents);
+        assert_eq!(fs::read_to_string(&second).unwrap(), edited);
+
+        let _ = fs::remove_dir_all(&home_dir);
+    }
+}
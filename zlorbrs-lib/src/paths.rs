@@ -0,0 +1,251 @@
+use std::{
+    env, fs,
+    path::{self, PathBuf},
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::{Error, Result, get_home_dir};
+
+/// Set from `--config-dir`, wins over the environment
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set from `--system`
+static SYSTEM: AtomicBool = AtomicBool::new(false);
+
+/// Where a system-wide service reads its configs
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/zlorbrs";
+
+/// Where a system-wide service keeps logs, history and runtime files
+pub const SYSTEM_STATE_DIR: &str = "/var/lib/zlorbrs";
+
+/// .
+///
+/// # Keeps everything zlorbrs reads and writes under `dir`
+///
+/// Meant for `--config-dir`, so it can only be set once and wins over
+/// `ZLORBRS_HOME` and the XDG variables. Relative paths are taken from the
+/// current directory
+pub fn set_root(dir: PathBuf) -> Result<()> {
+    let dir = path::absolute(&dir)?;
+    ROOT.set(dir)
+        .map_err(|_| Error::Config(String::from("The config directory was set already")))
+}
+
+/// .
+///
+/// # Switches to the system-wide layout
+///
+/// Configs are read from `/etc/zlorbrs` and state goes to `/var/lib/zlorbrs`,
+/// for a service running as root that builds each repo as its own user.
+/// Repositories then belong to those users rather than to us, which git only
+/// accepts for the ones listed by [`trust_repos`]. Has to be called after
+/// [`set_root`] and before anything else uses git
+pub fn set_system() -> Result<()> {
+    SYSTEM.store(true, Ordering::Relaxed);
+    // git has no use for the ProgramData level outside of Windows, so it can
+    // be pointed at a config only we write
+    let git_dir = state_dir()?.join("git");
+    // SAFETY: called at startup, before any other thread touches libgit2
+    unsafe { git2::opts::set_search_path(git2::ConfigLevel::ProgramData, git_dir)? };
+    Ok(())
+}
+
+/// .
+///
+/// # Lets git open the repositories at `repo_paths` with `--system`
+///
+/// Git refuses repositories that aren't owned by the current user, since
+/// their config could make it run anything. Rather than turning that off for
+/// every repository on the machine, the configured ones are listed as
+/// `safe.directory` in the config [`set_system`] pointed git at. Does nothing
+/// without `--system`
+pub fn trust_repos(repo_paths: &[PathBuf]) -> Result<()> {
+    if !system() {
+        return Ok(());
+    }
+    let git_dir = state_dir()?.join("git");
+    let file_path = git_dir.join("config");
+    let contents = safe_directories(repo_paths);
+    if fs::read_to_string(&file_path).is_ok_and(|current| current == contents) {
+        return Ok(());
+    }
+    fs::create_dir_all(&git_dir)?;
+    let scratch_path = git_dir.join(format!(".config.{}", std::process::id()));
+    fs::write(&scratch_path, contents)?;
+    fs::rename(&scratch_path, &file_path)?;
+    Ok(())
+}
+
+/// The git config marking `repo_paths` as safe. Paths are resolved the way
+/// git compares them, ones that can't be are left out
+fn safe_directories(repo_paths: &[PathBuf]) -> String {
+    let mut contents = String::from("# written by zlorbrs, the repositories it may open\n[safe]\n");
+    for repo_path in repo_paths {
+        let Some(repo_path) = fs::canonicalize(repo_path)
+            .ok()
+            .and_then(|path| path.to_str().map(str::to_owned))
+            .filter(|path| !path.contains('\n'))
+        else {
+            continue;
+        };
+        let escaped = repo_path.replace('\\', "\\\\").replace('"', "\\\"");
+        contents.push_str(&format!("\tdirectory = \"{escaped}\"\n"));
+    }
+    contents
+}
+
+/// Whether `--system` was given
+pub fn system() -> bool {
+    SYSTEM.load(Ordering::Relaxed)
+}
+
+/// The directory everything lives under when running as an isolated
+/// instance, from `--config-dir` or `ZLORBRS_HOME`. `--system` wins over the
+/// latter
+pub fn root() -> Option<PathBuf> {
+    if let Some(root) = ROOT.get() {
+        return Some(root.clone());
+    }
+    if system() {
+        return None;
+    }
+    env_dir("ZLORBRS_HOME")
+}
+
+/// Whether `--config-dir` was given, as opposed to `ZLORBRS_HOME` which child
+/// processes inherit anyway
+pub fn root_from_flag() -> Option<&'static PathBuf> {
+    ROOT.get()
+}
+
+/// An absolute path from `var`. The XDG spec says relative ones are ignored
+fn env_dir(var: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+}
+
+/// .
+///
+/// # Where configs are read from
+///
+/// The first of `--config-dir`, `/etc/zlorbrs` with `--system`,
+/// `$ZLORBRS_HOME`, `$XDG_CONFIG_HOME/zlorbrs` and `~/.config/zlorbrs`
+pub fn config_dir() -> Result<PathBuf> {
+    if let Some(root) = root() {
+        return Ok(root);
+    }
+    if system() {
+        return Ok(PathBuf::from(SYSTEM_CONFIG_DIR));
+    }
+    if let Some(config_home) = env_dir("XDG_CONFIG_HOME") {
+        return Ok(config_home.join("zlorbrs"));
+    }
+    Ok(PathBuf::from(get_home_dir()?).join(".config/zlorbrs"))
+}
+
+/// .
+///
+/// # Where build logs, history and runtime files go
+///
+/// An isolated instance keeps them under its root too and a system-wide one
+/// in `/var/lib/zlorbrs`. Otherwise they go to `$XDG_STATE_HOME/zlorbrs`
+/// when it is set, and next to the configs when not, where they have always
+/// been
+pub fn state_dir() -> Result<PathBuf> {
+    if let Some(root) = root() {
+        return Ok(root);
+    }
+    if system() {
+        return Ok(PathBuf::from(SYSTEM_STATE_DIR));
+    }
+    if let Some(state_home) = env_dir("XDG_STATE_HOME") {
+        return Ok(state_home.join("zlorbrs"));
+    }
+    config_dir()
+}
+
+/// The directory holding one directory per repo config
+pub fn configs_dir() -> Result<PathBuf> {
+    Ok(config_dir()?.join("configs"))
+}
+
+/// The config directory of the repo called `repo_name`
+pub fn repo_config_dir(repo_name: &str) -> Result<PathBuf> {
+    Ok(configs_dir()?.join(repo_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_test_utils::ENV_MUTEX;
+
+    #[test]
+    fn test_safe_directories() {
+        let mut dir = env::temp_dir();
+        dir.push("zlorbrs_paths_safe \"directories\"");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("repo")).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+
+        let contents = safe_directories(&[dir.join("repo/"), dir.join("missing")]);
+        let escaped = dir.to_str().unwrap().replace('"', "\\\"");
+        assert_eq!(
+            contents.lines().skip(1).collect::<Vec<_>>(),
+            ["[safe]", &format!("\tdirectory = \"{escaped}/repo\"")]
+        );
+
+        // git reads it back as the path it compares with
+        let config_path = dir.join("config");
+        fs::write(&config_path, &contents).unwrap();
+        let config = git2::Config::open(&config_path).unwrap();
+        assert_eq!(
+            config.get_string("safe.directory").unwrap(),
+            dir.join("repo").to_str().unwrap()
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dirs_from_env() {
+        let _lock = ENV_MUTEX.lock().unwrap();
+        unsafe {
+            env::set_var("HOME", "/home/me");
+        }
+        assert_eq!(config_dir().unwrap(), PathBuf::from("/home/me/.config/zlorbrs"));
+        assert_eq!(state_dir().unwrap(), config_dir().unwrap());
+        assert_eq!(
+            repo_config_dir("site").unwrap(),
+            PathBuf::from("/home/me/.config/zlorbrs/configs/site")
+        );
+
+        unsafe {
+            env::set_var("XDG_CONFIG_HOME", "/xdg/config");
+            env::set_var("XDG_STATE_HOME", "relative/is/ignored");
+        }
+        assert_eq!(config_dir().unwrap(), PathBuf::from("/xdg/config/zlorbrs"));
+        assert_eq!(state_dir().unwrap(), PathBuf::from("/xdg/config/zlorbrs"));
+
+        unsafe {
+            env::set_var("XDG_STATE_HOME", "/xdg/state");
+        }
+        assert_eq!(state_dir().unwrap(), PathBuf::from("/xdg/state/zlorbrs"));
+
+        // an isolated instance keeps everything in one place
+        unsafe {
+            env::set_var("ZLORBRS_HOME", "/srv/zlorbrs-staging");
+        }
+        assert_eq!(config_dir().unwrap(), PathBuf::from("/srv/zlorbrs-staging"));
+        assert_eq!(state_dir().unwrap(), PathBuf::from("/srv/zlorbrs-staging"));
+
+        unsafe {
+            env::remove_var("ZLORBRS_HOME");
+            env::remove_var("XDG_CONFIG_HOME");
+            env::remove_var("XDG_STATE_HOME");
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    Error, Result,
+    config::{self, BuildCommand, BuildStep, Config, DEFAULT_BUILD_COMMAND},
+    format::{self, Format},
+};
+
+/// The file a repository declares its own build in, without the extension.
+/// It may be TOML, YAML or JSON
+pub const FILE_STEM: &str = ".zlorbrs";
+
+/// .
+///
+/// # Build settings a repository ships in its `.zlorbrs.toml`
+///
+/// They live next to the code so they are versioned with it. The central
+/// config overrides whatever it sets itself, see [`Pipeline::apply`]
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    #[serde(default)]
+    pub build_command: Option<BuildCommand>, // e.g. "make dist"
+    #[serde(default)]
+    pub shell: bool, // run build_command through /bin/sh -c
+    #[serde(default)]
+    pub steps: Vec<BuildStep>, // replaces build_command when set
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub env_file: Option<String>, // relative to the repo
+    #[serde(default)]
+    pub working_dir: Option<String>, // relative to the repo
+    #[serde(default)]
+    pub schedule: Option<String>, // cron expression, e.g. "0 3 * * *"
+    #[serde(default)]
+    pub build_timeout: Option<u64>, // seconds
+    #[serde(default)]
+    pub artifacts: Option<Vec<String>>, // e.g. build/*.html
+    #[serde(default)]
+    pub watch_paths: Vec<String>, // e.g. packages/web
+}
+
+impl Pipeline {
+    /// Reads the pipeline file checked out in `repo_path`, if the repository
+    /// has one
+    pub fn read(repo_path: &Path) -> Result<Option<(PathBuf, Self)>> {
+        let Some(file_path) = format::find(repo_path, FILE_STEM)? else {
+            return Ok(None);
+        };
+        let contents = fs::read_to_string(&file_path)?;
+        let format = Format::from_path(&file_path).unwrap_or_default();
+        let pipeline: Pipeline = format
+            .parse(&contents)
+            .and_then(|value| Ok(serde_json::from_value(value)?))
+            .map_err(|e| match e {
+                Error::Config(message) => {
+                    Error::Config(format!("{}: {message}", file_path.display()))
+                }
+                e => e,
+            })?;
+        // the daemon reads it with its own rights, so it has to come from
+        // the repo itself
+        if let Some(env_file) = &pipeline.env_file
+            && !config::is_inside(Path::new(env_file))
+        {
+            return Err(Error::Config(format!(
+                "{}: env_file must be relative to the repo: {env_file}",
+                file_path.display()
+            )));
+        }
+        Ok(Some((file_path, pipeline)))
+    }
+
+    /// .
+    ///
+    /// # Fills in what `config` leaves out
+    ///
+    /// Fields the central config sets win. `env` is merged, with the central
+    /// entries winning. The build itself (`build_command`, `shell` and
+    /// `steps`) is taken from one side only: from `config` when it sets any
+    /// of them, where a `build_command` left at the default doesn't count,
+    /// otherwise from the pipeline
+    pub fn apply(&self, config: &mut Config) {
+        let central_build = !config.steps.is_empty()
+            || config.shell
+            || config.build_command != BuildCommand::from(DEFAULT_BUILD_COMMAND);
+        if !central_build {
+            if let Some(build_command) = &self.build_command {
+                config.build_command = build_command.clone();
+            }
+            config.shell = self.shell;
+            config.steps = self.steps.clone();
+        }
+
+        for (key, value) in &self.env {
+            config
+                .env
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        if config.env_file.is_none() {
+            config.env_file = self.env_file.clone();
+        }
+        if config.working_dir.is_none() {
+            config.working_dir = self.working_dir.clone();
+        }
+        if config.schedule.is_none() {
+            config.schedule = self.schedule.clone();
+        }
+        if config.build_timeout.is_none() {
+            config.build_timeout = self.build_timeout;
+        }
+        if config.artifacts.is_none() {
+            config.artifacts = self.artifacts.clone();
+        }
+        if config.watch_paths.is_empty() {
+            config.watch_paths = self.watch_paths.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test_dir(test_name: &str) -> PathBuf {
+        let mut dir = env::temp_dir();
+        dir.push(format!("zlorbrs_pipeline_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = test_dir("read");
+        assert!(Pipeline::read(&dir).unwrap().is_none());
+
+        fs::write(
+            dir.join(".zlorbrs.toml"),
+            r#"
+# versioned with the code
+working_dir = "web"
+schedule = "0 3 * * *"
+build_timeout = 600
+artifacts = ["build/*.html"]
+watch_paths = ["packages/web"]
+
+[[steps]]
+name = "install"
+command = "bun install"
+
+[[steps]]
+command = ["bun", "run", "build"]
+
+[env]
+NODE_ENV = "production"
+"#,
+        )
+        .unwrap();
+        let (file_path, pipeline) = Pipeline::read(&dir).unwrap().unwrap();
+        assert_eq!(file_path, dir.join(".zlorbrs.toml"));
+        assert_eq!(pipeline.steps.len(), 2);
+        assert_eq!(pipeline.working_dir.as_deref(), Some("web"));
+        assert_eq!(pipeline.schedule.as_deref(), Some("0 3 * * *"));
+        assert_eq!(pipeline.build_timeout, Some(600));
+        assert_eq!(pipeline.artifacts, Some(vec![String::from("build/*.html")]));
+        assert_eq!(pipeline.watch_paths, ["packages/web"]);
+        assert_eq!(pipeline.env["NODE_ENV"], "production");
+
+        // only build settings belong in there
+        fs::write(dir.join(".zlorbrs.toml"), "branch = \"main\"\n").unwrap();
+        let message = Pipeline::read(&dir).unwrap_err().to_string();
+        assert!(message.contains(".zlorbrs.toml"));
+        assert!(message.contains("branch"));
+
+        // nor secrets from somewhere else
+        for env_file in ["/srv/other/.env", "../other/.env"] {
+            fs::write(
+                dir.join(".zlorbrs.toml"),
+                format!("env_file = \"{env_file}\"\n"),
+            )
+            .unwrap();
+            let message = Pipeline::read(&dir).unwrap_err().to_string();
+            assert!(message.contains("env_file"), "{message}");
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_apply() {
+        let pipeline = Pipeline {
+            build_command: Some(BuildCommand::from("make dist")),
+            env: BTreeMap::from([
+                (String::from("NODE_ENV"), String::from("production")),
+                (String::from("API_URL"), String::from("https://example.com")),
+            ]),
+            working_dir: Some(String::from("web")),
+            ..Default::default()
+        };
+
+        // a config as zlorbrs-ctl add writes it takes the repository's build
+        let mut config = Config {
+            build_command: BuildCommand::from(DEFAULT_BUILD_COMMAND),
+            env: BTreeMap::from([(String::from("NODE_ENV"), String::from("staging"))]),
+            ..Default::default()
+        };
+        pipeline.apply(&mut config);
+        assert_eq!(config.build_command, BuildCommand::from("make dist"));
+        assert_eq!(config.env["NODE_ENV"], "staging");
+        assert_eq!(config.env["API_URL"], "https://example.com");
+        assert_eq!(config.working_dir.as_deref(), Some("web"));
+
+        // a build declared centrally wins as a whole
+        let mut config = Config {
+            build_command: BuildCommand::from(DEFAULT_BUILD_COMMAND),
+            steps: vec![BuildStep {
+                name: None,
+                command: BuildCommand::from("just build"),
+                shell: false,
+            }],
+            working_dir: Some(String::from(".")),
+            ..Default::default()
+        };
+        pipeline.apply(&mut config);
+        assert_eq!(
+            config.build_command,
+            BuildCommand::from(DEFAULT_BUILD_COMMAND)
+        );
+        assert_eq!(config.steps.len(), 1);
+        assert_eq!(config.working_dir.as_deref(), Some("."));
+    }
+}
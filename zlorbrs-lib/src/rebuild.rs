@@ -0,0 +1,242 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use git2::{Oid, Pathspec, PathspecFlags, Repository};
+
+use crate::{Result, config::Config};
+
+/// .
+///
+/// # The `artifacts` of `config` that aren't there
+///
+/// Patterns are relative to the repository unless absolute. A path
+/// component may use `*` and `?`, e.g. `build/*.html`, and a pattern counts
+/// as present when it matches anything at all. Without any `artifacts`, the
+/// default `dist` is looked for where builds run, see [`Config::build_dir`]
+pub fn missing_artifacts(config: &Config) -> Result<Vec<String>> {
+    let (base, patterns) = match &config.artifacts {
+        Some(patterns) => (PathBuf::from(&config.path), patterns.clone()),
+        None => (config.build_dir()?, config.artifact_patterns()),
+    };
+    Ok(patterns
+        .into_iter()
+        .filter(|pattern| expand(&base, pattern).is_empty())
+        .collect())
+}
+
+/// .
+///
+/// # Whether the commits from `old` to `new` touch a watched path
+///
+/// `watch_paths` are git pathspecs, so a directory covers everything below
+/// it and `*.md` matches Markdown files anywhere. Without any, every change
+/// counts
+pub fn touches_watched(repo: &Repository, config: &Config, old: Oid, new: Oid) -> Result<bool> {
+    if config.watch_paths.is_empty() {
+        return Ok(true);
+    }
+    let pathspec = Pathspec::new(&config.watch_paths)?;
+    let old_tree = repo.find_commit(old)?.tree()?;
+    let new_tree = repo.find_commit(new)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+    Ok(diff.deltas().any(|delta| {
+        [delta.old_file().path(), delta.new_file().path()]
+            .into_iter()
+            .flatten()
+            .any(|path| pathspec.matches_path(path, PathspecFlags::DEFAULT))
+    }))
+}
+
+/// Everything below `base` that `pattern` matches
+fn expand(base: &Path, pattern: &str) -> Vec<PathBuf> {
+    let mut found = vec![base.to_path_buf()];
+    for component in Path::new(pattern).components() {
+        found = match component {
+            Component::Normal(part) => {
+                let part = part.to_string_lossy();
+                if !part.contains(['*', '?']) {
+                    found.into_iter().map(|path| path.join(&*part)).collect()
+                } else {
+                    found
+                        .iter()
+                        .filter_map(|dir| fs::read_dir(dir).ok())
+                        .flat_map(|entries| entries.flatten())
+                        .filter(|entry| wildcard_match(&part, &entry.file_name().to_string_lossy()))
+                        .map(|entry| entry.path())
+                        .collect()
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => vec![PathBuf::from(component.as_os_str())],
+            Component::ParentDir => found.into_iter().map(|path| path.join("..")).collect(),
+            Component::CurDir => found,
+        };
+    }
+    found.retain(|path| fs::symlink_metadata(path).is_ok());
+    found
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for exactly one
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was and how much of the name it had taken
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test_dir(test_name: &str) -> PathBuf {
+        let mut dir = env::temp_dir();
+        dir.push(format!("zlorbrs_rebuild_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn commit(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (name, contents) in files {
+            let path = workdir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+            index.add_path(Path::new(name)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test User", "test@example.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "commit",
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.html", "index.html"));
+        assert!(wildcard_match("app-?.js", "app-1.js"));
+        assert!(wildcard_match("*a*b", "xaaab"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("*.html", "index.htm"));
+        assert!(!wildcard_match("app-?.js", "app-12.js"));
+    }
+
+    #[test]
+    fn test_missing_artifacts() {
+        let dir = test_dir("missing_artifacts");
+        let mut config = Config {
+            path: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+
+        // dist/ unless told otherwise
+        assert_eq!(missing_artifacts(&config).unwrap(), ["dist"]);
+        fs::create_dir_all(dir.join("dist")).unwrap();
+        assert!(missing_artifacts(&config).unwrap().is_empty());
+
+        fs::create_dir_all(dir.join("public/assets")).unwrap();
+        fs::write(dir.join("public/index.html"), "").unwrap();
+        config.artifacts = Some(vec![
+            String::from("public/*.html"),
+            String::from("public/*/app-?.js"),
+            dir.join("target").to_str().unwrap().to_string(),
+        ]);
+        assert_eq!(
+            missing_artifacts(&config).unwrap(),
+            [
+                String::from("public/*/app-?.js"),
+                dir.join("target").to_str().unwrap().to_string()
+            ]
+        );
+
+        // nothing to look for, nothing is ever missing
+        config.artifacts = Some(Vec::new());
+        fs::remove_dir_all(dir.join("dist")).unwrap();
+        assert!(missing_artifacts(&config).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_missing_artifacts_working_dir() {
+        let dir = test_dir("missing_artifacts_working_dir");
+        let mut config = Config {
+            path: dir.to_str().unwrap().to_string(),
+            working_dir: Some(String::from("packages/web")),
+            ..Default::default()
+        };
+
+        // the default dist/ is the build's own, so it's in working_dir
+        fs::create_dir_all(dir.join("dist")).unwrap();
+        assert_eq!(missing_artifacts(&config).unwrap(), ["dist"]);
+        fs::create_dir_all(dir.join("packages/web/dist")).unwrap();
+        assert!(missing_artifacts(&config).unwrap().is_empty());
+
+        // listed artifacts stay relative to the repository
+        config.artifacts = Some(vec![String::from("packages/web/build")]);
+        assert_eq!(missing_artifacts(&config).unwrap(), ["packages/web/build"]);
+        fs::create_dir_all(dir.join("packages/web/build")).unwrap();
+        assert!(missing_artifacts(&config).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_touches_watched() {
+        let dir = test_dir("touches_watched");
+        let repo = Repository::init(&dir).unwrap();
+        let first = commit(&repo, &[("README.md", "a"), ("packages/web/index.ts", "a")]);
+        let docs = commit(&repo, &[("README.md", "b"), ("docs/guide.md", "b")]);
+        let web = commit(&repo, &[("packages/web/index.ts", "b")]);
+
+        let mut config = Config::default();
+        assert!(touches_watched(&repo, &config, first, docs).unwrap());
+
+        config.watch_paths = vec![String::from("packages/web"), String::from("bun.lock")];
+        assert!(!touches_watched(&repo, &config, first, docs).unwrap());
+        assert!(touches_watched(&repo, &config, docs, web).unwrap());
+        assert!(touches_watched(&repo, &config, first, web).unwrap());
+
+        config.watch_paths = vec![String::from("*.md")];
+        assert!(touches_watched(&repo, &config, first, docs).unwrap());
+        assert!(!touches_watched(&repo, &config, docs, web).unwrap());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
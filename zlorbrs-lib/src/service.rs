@@ -0,0 +1,258 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, Result,
+    config::Diagnostic,
+    credentials::expand_home,
+    format::{self, Format},
+    logs::Retention,
+    migrate::{self, Schema},
+    paths,
+};
+
+/// The service config version written by this zlorbrs
+pub const SERVICE_CONFIG_VERSION: u32 = 1;
+
+/// Settings of zlorbrs-service itself, read from `service-config.json`,
+/// `.toml` or `.yaml`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    #[serde(default)]
+    pub version: u32, // schema version, 0 for configs from before versioning
+    #[serde(default)]
+    pub sleep_time: u64, // seconds between checks of a repo without its own poll_interval
+    #[serde(default = "default_max_build_logs")]
+    pub max_build_logs: usize, // build logs kept per repo
+    #[serde(default = "default_max_log_bytes")]
+    pub max_log_bytes: u64, // a build log stops growing past this size
+    #[serde(default = "default_max_concurrent_builds")]
+    pub max_concurrent_builds: usize, // builds of different repos running at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookConfig>, // listen for push webhooks, off when left out
+}
+
+/// Where zlorbrs-service listens for push webhooks and how it checks that
+/// they really come from the forge
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub listen: String,      // e.g. 127.0.0.1:8787
+    pub secret_file: String, // holds the secret configured on the forge's webhook
+}
+
+impl WebhookConfig {
+    /// The address to listen on
+    pub fn address(&self) -> Result<SocketAddr> {
+        self.listen
+            .parse()
+            .map_err(|e| Error::Config(format!("listen {:?}: {e}", self.listen)))
+    }
+
+    /// Reads the shared secret, ignoring a trailing newline
+    pub fn secret(&self) -> Result<Vec<u8>> {
+        let path = expand_home(&self.secret_file).map_err(|e| {
+            Error::Config(format!(
+                "secret_file {:?}: {}",
+                self.secret_file,
+                e.message()
+            ))
+        })?;
+        let secret = fs::read_to_string(&path)
+            .map_err(|e| Error::Config(format!("Failed to read {}: {e}", path.display())))?;
+        let secret = secret.trim_end_matches(['\r', '\n']);
+        if secret.is_empty() {
+            return Err(Error::Config(format!("{} is empty", path.display())));
+        }
+        Ok(secret.as_bytes().to_vec())
+    }
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            version: SERVICE_CONFIG_VERSION,
+            sleep_time: 0,
+            max_build_logs: default_max_build_logs(),
+            max_log_bytes: default_max_log_bytes(),
+            max_concurrent_builds: default_max_concurrent_builds(),
+            webhook: None,
+        }
+    }
+}
+
+fn default_max_build_logs() -> usize {
+    Retention::default().max_logs
+}
+
+fn default_max_log_bytes() -> u64 {
+    Retention::default().max_bytes
+}
+
+fn default_max_concurrent_builds() -> usize {
+    1
+}
+
+impl ServiceConfig {
+    /// Where the service looks for its config, `service-config.json` when
+    /// there is none yet
+    pub fn path() -> Result<PathBuf> {
+        let dir = paths::config_dir()?;
+        Ok(
+            format::find(&dir, "service-config")?
+                .unwrap_or_else(|| dir.join("service-config.json")),
+        )
+    }
+
+    /// Parses the service config at `path`, in the format its extension
+    /// says, upgrading one from an older zlorbrs on the way
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let parse = || -> Result<Self> {
+            let format = Format::from_path(path).unwrap_or_default();
+            let mut value = format.parse(&contents)?;
+            migrate::upgrade(Schema::Service, &mut value)?;
+            Ok(serde_json::from_value(value)?)
+        };
+        parse().map_err(|e| match e {
+            Error::Config(message) => Error::Config(format!("{}: {message}", path.display())),
+            e => e,
+        })
+    }
+
+    pub fn log_retention(&self) -> Retention {
+        Retention {
+            max_logs: self.max_build_logs,
+            max_bytes: self.max_log_bytes,
+        }
+    }
+
+    /// Settings that work but are probably not what was meant, and webhook
+    /// settings the service can't start with
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut problems = Vec::new();
+        if self.sleep_time == 0 {
+            problems.push(Diagnostic::warning(
+                "sleep_time",
+                String::from("is 0, repos are fetched back to back without a pause"),
+            ));
+        }
+        if self.max_build_logs == 0 {
+            problems.push(Diagnostic::warning(
+                "max_build_logs",
+                String::from("is 0, the latest build log is kept anyway"),
+            ));
+        }
+        if self.max_log_bytes == 0 {
+            problems.push(Diagnostic::warning(
+                "max_log_bytes",
+                String::from("is 0, build logs will be empty"),
+            ));
+        }
+        if self.max_concurrent_builds == 0 {
+            problems.push(Diagnostic::warning(
+                "max_concurrent_builds",
+                String::from("is 0, builds run one at a time"),
+            ));
+        }
+        if let Some(webhook) = &self.webhook {
+            if let Err(e) = webhook.address() {
+                problems.push(Diagnostic::error("webhook", e.to_string()));
+            }
+            if let Err(e) = webhook.secret() {
+                problems.push(Diagnostic::error("webhook", e.to_string()));
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Severity;
+    use std::env;
+
+    #[test]
+    fn test_from_file() {
+        let mut dir = env::temp_dir();
+        dir.push("zlorbrs_service_from_file");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("service-config.json");
+
+        fs::write(&path, r#"{ "sleep_time": 30 }"#).unwrap();
+        let config = ServiceConfig::from_file(&path).unwrap();
+        assert_eq!(config.sleep_time, 30);
+        assert_eq!(config.log_retention(), Retention::default());
+
+        // typos are reported with the file they are in
+        fs::write(&path, r#"{ "sleep_tme": 30 }"#).unwrap();
+        let message = ServiceConfig::from_file(&path).unwrap_err().to_string();
+        assert!(message.contains("service-config.json"));
+        assert!(message.contains("sleep_tme"));
+
+        // the extension tells the format
+        let path = dir.join("service-config.toml");
+        fs::write(&path, "# seconds\nsleep_time = 45\n").unwrap();
+        assert_eq!(ServiceConfig::from_file(&path).unwrap().sleep_time, 45);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = ServiceConfig {
+            sleep_time: 60,
+            ..Default::default()
+        };
+        assert!(config.validate().is_empty());
+
+        let problems = ServiceConfig::default().validate();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Warning);
+        assert_eq!(problems[0].field, "sleep_time");
+
+        let config = ServiceConfig {
+            sleep_time: 60,
+            max_concurrent_builds: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.validate()[0].field, "max_concurrent_builds");
+    }
+
+    #[test]
+    fn test_webhook() {
+        let mut dir = env::temp_dir();
+        dir.push("zlorbrs_service_webhook");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let secret_file = dir.join("webhook-secret");
+
+        let mut config = ServiceConfig {
+            sleep_time: 60,
+            webhook: Some(WebhookConfig {
+                listen: String::from("localhost"),
+                secret_file: secret_file.to_str().unwrap().to_string(),
+            }),
+            ..Default::default()
+        };
+        let problems = config.validate();
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().all(|p| p.severity == Severity::Error));
+
+        fs::write(&secret_file, "s3cret\n").unwrap();
+        let webhook = config.webhook.as_mut().unwrap();
+        webhook.listen = String::from("127.0.0.1:8787");
+        assert_eq!(webhook.secret().unwrap(), b"s3cret");
+        assert!(config.validate().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    ffi::{CStr, CString},
+    fs, io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    },
+    path::{Component, Path, PathBuf},
+    ptr,
+};
+
+use crate::{Error, Result};
+
+/// A Unix user builds can run as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub name: String, // e.g. www-data
+    pub uid: u32,
+    pub gid: u32,      // the user's primary group
+    pub home: PathBuf, // e.g. /var/www
+}
+
+/// Whether this process may switch to other users
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The uid and gid this process runs as
+pub fn current_ids() -> (u32, u32) {
+    unsafe { (libc::geteuid(), libc::getegid()) }
+}
+
+/// .
+///
+/// # Looks up a user by name or uid
+///
+/// A name that is all digits is taken as a uid, like chown does
+pub fn user(name: &str) -> Result<Account> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let code = match name.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found)
+        },
+        Err(_) => {
+            let c_name = c_string(name)?;
+            unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    if code != 0 {
+        return Err(Error::Io(std::io::Error::from_raw_os_error(code)));
+    }
+    if found.is_null() {
+        return Err(Error::Config(format!("There is no user {name:?}")));
+    }
+    Ok(Account {
+        name: unsafe { CStr::from_ptr(passwd.pw_name) }
+            .to_string_lossy()
+            .into_owned(),
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+        home: PathBuf::from(
+            unsafe { CStr::from_ptr(passwd.pw_dir) }
+                .to_string_lossy()
+                .into_owned(),
+        ),
+    })
+}
+
+/// Looks up a group's gid by name or gid
+pub fn group(name: &str) -> Result<u32> {
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let code = match name.parse::<libc::gid_t>() {
+        Ok(gid) => unsafe {
+            libc::getgrgid_r(gid, &mut group, buf.as_mut_ptr(), buf.len(), &mut found)
+        },
+        Err(_) => {
+            let c_name = c_string(name)?;
+            unsafe {
+                libc::getgrnam_r(
+                    c_name.as_ptr(),
+                    &mut group,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    if code != 0 {
+        return Err(Error::Io(std::io::Error::from_raw_os_error(code)));
+    }
+    if found.is_null() {
+        return Err(Error::Config(format!("There is no group {name:?}")));
+    }
+    Ok(group.gr_gid)
+}
+
+/// .
+///
+/// # Hands what root created at `paths` in `dir` over to the owner of `dir`
+///
+/// For a service running as root in someone else's repository, once git wrote
+/// there. `paths` are relative to `dir`, and the directories on the way to
+/// them change hands too. Only what belongs to root does, and whatever isn't
+/// there is skipped.
+///
+/// The owner may rearrange `dir` meanwhile, so paths are walked through
+/// directory descriptors opened without following symlinks, and every entry
+/// is checked and changed through a descriptor of its own. Symlinks, files with
+/// more than one link and anything but files and directories are left alone,
+/// as they could lead outside `dir`
+pub fn hand_over(dir: &Path, paths: &[PathBuf]) -> Result<()> {
+    let dir: OwnedFd = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(dir)?
+        .into();
+    let owner = fstat(&dir)?;
+    if owner.st_uid == 0 {
+        return Ok(());
+    }
+
+    for path in paths {
+        let names = path
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => Ok(name),
+                _ => Err(Error::Config(format!(
+                    "{} isn't a path inside the directory",
+                    path.display()
+                ))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut parent = None;
+        for (i, name) in names.iter().enumerate() {
+            let name = CString::new(name.as_bytes())
+                .map_err(|_| Error::Config(format!("{} contains a NUL byte", path.display())))?;
+            let at = parent.as_ref().unwrap_or(&dir);
+            match hand_over_entry(at, &name, owner.st_uid, owner.st_gid)? {
+                Some(subdir) => parent = Some(subdir),
+                // nothing below something that isn't a directory
+                None if i + 1 < names.len() => break,
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hands `name` in `dir` over if root owns it, returning it if it's a directory
+fn hand_over_entry(dir: &OwnedFd, name: &CStr, uid: u32, gid: u32) -> Result<Option<OwnedFd>> {
+    let flags =
+        libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        // gone, a symlink or something that can't be opened like a socket
+        return match e.raw_os_error() {
+            Some(libc::ENOENT | libc::ELOOP | libc::ENXIO) => Ok(None),
+            _ => Err(e.into()),
+        };
+    }
+    let entry = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let stat = fstat(&entry)?;
+    let kind = stat.st_mode & libc::S_IFMT;
+    let is_dir = kind == libc::S_IFDIR;
+    // another link could be a name for some file outside `dir`
+    let is_file = kind == libc::S_IFREG && stat.st_nlink == 1;
+    if stat.st_uid == 0 && (is_dir || is_file) && unsafe { libc::fchown(fd, uid, gid) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(is_dir.then_some(entry))
+}
+
+fn fstat(fd: &OwnedFd) -> io::Result<libc::stat> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+fn c_string(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| Error::Config(format!("{name:?} contains a NUL byte")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::{MetadataExt, chown, symlink};
+
+    #[test]
+    fn test_lookup() {
+        let root = user("root").unwrap();
+        assert_eq!(root.uid, 0);
+        assert_eq!(user("0").unwrap(), root);
+        assert_eq!(group("0").unwrap(), 0);
+        assert!(matches!(
+            user("zlorbrs-user-that-does-not-exist"),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            group("zlorbrs-group-that-does-not-exist"),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_hand_over() {
+        if !is_root() {
+            return;
+        }
+        let dir = env::temp_dir().join("zlorbrs_users_test_hand_over");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = dir.join("repo");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        let nobody = user("nobody").unwrap();
+        chown(&repo, Some(nobody.uid), Some(nobody.gid)).unwrap();
+
+        // what root wrote, and what it didn't write but still owns
+        fs::create_dir_all(repo.join("refs/heads")).unwrap();
+        fs::write(repo.join("refs/heads/main"), "").unwrap();
+        fs::write(repo.join("untouched"), "").unwrap();
+
+        // what the owner could put in the way to reach outside
+        fs::write(outside.join("secret"), "").unwrap();
+        symlink(outside.join("secret"), repo.join("link")).unwrap();
+        fs::hard_link(outside.join("secret"), repo.join("hard")).unwrap();
+        symlink(&outside, repo.join("sneaky")).unwrap();
+
+        let paths: Vec<PathBuf> = [
+            "refs/heads/main",
+            "link",
+            "hard",
+            "sneaky/secret",
+            "gone/file",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        hand_over(&repo, &paths).unwrap();
+
+        let owner = |path: &str| fs::symlink_metadata(dir.join(path)).unwrap().uid();
+        assert_eq!(owner("repo/refs"), nobody.uid);
+        assert_eq!(owner("repo/refs/heads"), nobody.uid);
+        assert_eq!(owner("repo/refs/heads/main"), nobody.uid);
+        assert_eq!(owner("repo/untouched"), 0);
+        assert_eq!(owner("repo/link"), 0);
+        assert_eq!(owner("outside"), 0);
+        assert_eq!(owner("outside/secret"), 0);
+
+        assert!(matches!(
+            hand_over(&repo, &[PathBuf::from("../outside/secret")]),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(owner("outside/secret"), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::BufReader,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex, mpsc::Sender},
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use zlorbrs_lib::{
+    Error, Result,
+    build::Cancel,
+    ipc::{self, RepoStatus, Request, Response, ServiceStatus},
+};
+
+/// How long a connected client may take to send its next request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Work the control socket hands to the main loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    Check(String),
+    Build(String),
+    Reload,
+}
+
+/// What the service tracks per repo, shared between the main loop and the
+/// control socket
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RepoState {
+    pub(crate) paused: bool,
+    pub(crate) checking: bool,
+    pub(crate) last_checked: Option<DateTime<Utc>>,
+    pub(crate) last_error: Option<String>,
+    pub(crate) cancel: Option<Cancel>, // set while a worker has the repo
+}
+
+#[derive(Debug)]
+pub(crate) struct ServiceState {
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) config_dir: String,
+    pub(crate) repos: BTreeMap<String, RepoState>,
+}
+
+pub(crate) type SharedState = Arc<Mutex<ServiceState>>;
+
+impl ServiceState {
+    pub(crate) fn new(config_dir: String) -> Self {
+        Self {
+            started_at: Utc::now(),
+            config_dir,
+            repos: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn is_paused(&self, repo_name: &str) -> bool {
+        self.repos.get(repo_name).is_some_and(|repo| repo.paused)
+    }
+}
+
+/// Locks the shared state, carrying on even if a thread panicked while
+/// holding it since the state stays usable
+pub(crate) fn lock(state: &SharedState) -> std::sync::MutexGuard<'_, ServiceState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// .
+///
+/// # Opens the control socket
+///
+/// Binds `path`, replacing a socket left behind by a service that is no
+/// longer running. Errors if another service is still listening on it
+pub(crate) fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::Ipc(format!(
+                "Another zlorbrs-service is already listening on {}",
+                path.display()
+            )));
+        }
+        debug!("Removing stale control socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// .
+///
+/// # Serves control requests in the background
+///
+/// Answers status and pause/resume requests straight from `state` and
+/// forwards everything that needs the main loop through `events`. Every
+/// client gets a thread of its own, so one that is slow to send its request
+/// doesn't keep the others waiting
+pub(crate) fn spawn(
+    listener: UnixListener,
+    state: SharedState,
+    events: Sender<Event>,
+    known_repos: fn() -> Vec<String>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let state = Arc::clone(&state);
+                    let events = events.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve_client(&stream, &state, &events, known_repos) {
+                            error!("Control connection failed: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept control connection: {e}"),
+            }
+        }
+    })
+}
+
+fn serve_client(
+    stream: &UnixStream,
+    state: &SharedState,
+    events: &Sender<Event>,
+    known_repos: fn() -> Vec<String>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let response = match ipc::read_message::<Request>(&mut reader) {
+            Ok(Some(request)) => {
+                debug!("Control request: {request:?}");
+                handle(request, state, events, known_repos)
+            }
+            Ok(None) => return Ok(()),
+            Err(Error::Ipc(message)) => Response::Error { message },
+            Err(e) => return Err(e),
+        };
+        ipc::write_message(stream, &response)?;
+    }
+}
+
+pub(crate) fn handle(
+    request: Request,
+    state: &SharedState,
+    events: &Sender<Event>,
+    known_repos: fn() -> Vec<String>,
+) -> Response {
+    let repo_name = match &request {
+        Request::Status => return status(state, known_repos()),
+        Request::Reload => return queue(events, Event::Reload, "Reload queued"),
+        Request::Check { repo }
+        | Request::Build { repo }
+        | Request::Pause { repo }
+        | Request::Resume { repo }
+        | Request::Cancel { repo } => repo.clone(),
+    };
+
+    if !known_repos().contains(&repo_name) {
+        return Response::Error {
+            message: format!("Theres no config found with name: {repo_name}"),
+        };
+    }
+
+    match request {
+        Request::Check { .. } => queue(
+            events,
+            Event::Check(repo_name.clone()),
+            &format!("Check of {repo_name} queued"),
+        ),
+        Request::Build { .. } => queue(
+            events,
+            Event::Build(repo_name.clone()),
+            &format!("Build of {repo_name} queued"),
+        ),
+        Request::Pause { .. } | Request::Resume { .. } => {
+            let paused = matches!(request, Request::Pause { .. });
+            lock(state)
+                .repos
+                .entry(repo_name.clone())
+                .or_default()
+                .paused = paused;
+            Response::Ok {
+                message: format!("{repo_name} {}", if paused { "paused" } else { "resumed" }),
+            }
+        }
+        Request::Cancel { .. } => {
+            let cancel = lock(state)
+                .repos
+                .get(&repo_name)
+                .and_then(|repo| repo.cancel.clone());
+            match cancel {
+                Some(cancel) => {
+                    cancel.cancel();
+                    Response::Ok {
+                        message: format!("Stopping the build of {repo_name}"),
+                    }
+                }
+                None => Response::Error {
+                    message: format!("{repo_name} isn't building"),
+                },
+            }
+        }
+        Request::Status | Request::Reload => unreachable!("answered above"),
+    }
+}
+
+fn queue(events: &Sender<Event>, event: Event, message: &str) -> Response {
+    match events.send(event) {
+        Ok(_) => Response::Ok {
+            message: String::from(message),
+        },
+        Err(_) => Response::Error {
+            message: String::from("The service is shutting down"),
+        },
+    }
+}
+
+fn status(state: &SharedState, known_repos: Vec<String>) -> Response {
+    let state = lock(state);
+    let repos = known_repos
+        .into_iter()
+        .map(|name| {
+            let repo = state.repos.get(&name).cloned().unwrap_or_default();
+            RepoStatus {
+                name,
+                paused: repo.paused,
+                checking: repo.checking,
+                last_checked: repo.last_checked,
+                last_error: repo.last_error,
+            }
+        })
+        .collect();
+
+    Response::Status(ServiceStatus {
+        pid: std::process::id(),
+        started_at: state.started_at,
+        config_dir: state.config_dir.clone(),
+        repos,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+
+    fn known_repos() -> Vec<String> {
+        vec![String::from("repo_a"), String::from("repo_b")]
+    }
+
+    fn shared_state() -> SharedState {
+        Arc::new(Mutex::new(ServiceState::new(String::from("/configs"))))
+    }
+
+    fn socket_dir(test_name: &str) -> PathBuf {
+        let mut dir = env::temp_dir();
+        dir.push(format!("zlorbrs_control_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let state = shared_state();
+        let (tx, _rx) = mpsc::channel();
+
+        let pause = Request::Pause {
+            repo: String::from("repo_a"),
+        };
+        assert!(matches!(
+            handle(pause, &state, &tx, known_repos),
+            Response::Ok { .. }
+        ));
+        assert!(lock(&state).is_paused("repo_a"));
+        assert!(!lock(&state).is_paused("repo_b"));
+
+        let resume = Request::Resume {
+            repo: String::from("repo_a"),
+        };
+        handle(resume, &state, &tx, known_repos);
+        assert!(!lock(&state).is_paused("repo_a"));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let state = shared_state();
+        let (tx, _rx) = mpsc::channel();
+        let cancel = || Request::Cancel {
+            repo: String::from("repo_a"),
+        };
+        assert!(matches!(
+            handle(cancel(), &state, &tx, known_repos),
+            Response::Error { .. }
+        ));
+
+        let build = Cancel::default();
+        lock(&state)
+            .repos
+            .entry(String::from("repo_a"))
+            .or_default()
+            .cancel = Some(build.clone());
+        assert!(matches!(
+            handle(cancel(), &state, &tx, known_repos),
+            Response::Ok { .. }
+        ));
+        assert!(build.is_cancelled());
+    }
+
+    #[test]
+    fn test_unknown_repo() {
+        let state = shared_state();
+        let (tx, rx) = mpsc::channel();
+
+        let check = Request::Check {
+            repo: String::from("nope"),
+        };
+        assert!(matches!(
+            handle(check, &state, &tx, known_repos),
+            Response::Error { .. }
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_events_are_queued() {
+        let state = shared_state();
+        let (tx, rx) = mpsc::channel();
+
+        let check = Request::Check {
+            repo: String::from("repo_b"),
+        };
+        handle(check, &state, &tx, known_repos);
+        handle(Request::Reload, &state, &tx, known_repos);
+
+        assert_eq!(rx.try_recv().unwrap(), Event::Check(String::from("repo_b")));
+        assert_eq!(rx.try_recv().unwrap(), Event::Reload);
+    }
+
+    #[test]
+    fn test_status_over_socket() {
+        let dir = socket_dir("status");
+        let path = dir.join("zlorbrs.sock");
+        let state = shared_state();
+        lock(&state).repos.insert(
+            String::from("repo_b"),
+            RepoState {
+                last_error: Some(String::from("boom")),
+                ..Default::default()
+            },
+        );
+        let (tx, _rx) = mpsc::channel();
+        spawn(bind(&path).unwrap(), state, tx, known_repos);
+
+        let status = match ipc::send_to(&path, &Request::Status).unwrap() {
+            Response::Status(status) => status,
+            other => panic!("unexpected response {other:?}"),
+        };
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(status.config_dir, "/configs");
+        assert_eq!(status.repos.len(), 2);
+        assert_eq!(status.repos[0].name, "repo_a");
+        assert_eq!(status.repos[1].last_error.as_deref(), Some("boom"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_idle_client_does_not_block_others() {
+        let dir = socket_dir("idle_client");
+        let path = dir.join("zlorbrs.sock");
+        let (tx, _rx) = mpsc::channel();
+        spawn(bind(&path).unwrap(), shared_state(), tx, known_repos);
+
+        // connected but never asking for anything
+        let _idle = UnixStream::connect(&path).unwrap();
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            ipc::send_to(&path, &Request::Status).unwrap(),
+            Response::Status(_)
+        ));
+        assert!(started.elapsed() < CLIENT_TIMEOUT / 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bind_refuses_running_service() {
+        let dir = socket_dir("bind");
+        let path = dir.join("zlorbrs.sock");
+
+        let listener = bind(&path).unwrap();
+        assert!(matches!(bind(&path), Err(Error::Ipc(_))));
+
+        // once the old service is gone its socket file gets replaced
+        drop(listener);
+        assert!(bind(&path).is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,185 +1,536 @@
-use git2::{BranchType, Cred, Error, FetchOptions, Oid, Remote, RemoteCallbacks, Repository};
-use log::{debug, error, info};
-use serde::{Deserialize, Serialize};
-use std::{fs, io::Error as IoError, process::Stdio};
-use zlorbrs_lib::config::Config;
-
-#[derive(Serialize, Deserialize, Default, Debug)]
-struct ServiceConfig {
-    sleep_time: u64,
+mod control;
+mod queue;
+mod schedule;
+mod webhook;
+
+use chrono::Utc;
+use clap::Parser;
+use control::{Event, ServiceState, SharedState};
+use git2::{BranchType, FetchOptions, Oid, Repository};
+use log::{debug, error, info, warn};
+use queue::{BuildQueue, BuildRequest, Work};
+use schedule::{Job, Plan, Scheduler};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use zlorbrs_lib::{
+    Error, Result,
+    build::{self, Cancel, RepoLock},
+    config::{self, Config},
+    credentials,
+    format::Format,
+    history::{self, BuildRecord, Trigger},
+    ipc,
+    logs::{BuildLog, Retention},
+    migrate, paths,
+    pipeline::Pipeline,
+    rebuild,
+    service::ServiceConfig,
+    users,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Keep configs, logs and history in this directory instead, for running
+    /// an isolated instance. Also read from ZLORBRS_HOME
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+    /// Read configs from /etc/zlorbrs and keep state in /var/lib/zlorbrs, for
+    /// running as a system unit that builds each repo as its build_user
+    #[arg(long, conflicts_with = "config_dir")]
+    system: bool,
 }
 
-fn setup_config_stuff() -> Result<ServiceConfig, ()> {
-    let path_to_config_file_for_service = format!(
-        "{}/.config/zlorbrs/service-config.json",
-        std::env::home_dir().unwrap().to_str().unwrap()
-    );
+/// .
+///
+/// # Reads the service config
+///
+/// Upgrades configs from an older zlorbrs, writes the defaults when there is
+/// no service config yet, and logs settings that are probably a mistake
+fn setup_config_stuff() -> Result<ServiceConfig> {
+    for migration in migrate::migrate_all(false)? {
+        match migration {
+            Ok(migration) => info!(
+                "Upgraded {} ({}), the old file is at {}",
+                migration.path.display(),
+                migration.changes.join(", "),
+                migration.backup.unwrap_or_default().display()
+            ),
+            Err(e) => warn!("{e}"),
+        }
+    }
+
+    let path_to_config_file_for_service = ServiceConfig::path()?;
 
-    if !fs::exists(&path_to_config_file_for_service).unwrap() {
+    if !fs::exists(&path_to_config_file_for_service)? {
         info!("Service config file not found.. creating it now");
-        let _ = fs::write(
+        fs::write(
             &path_to_config_file_for_service,
-            &serde_json::to_string(&ServiceConfig::default()).unwrap(),
-        );
+            Format::Json.to_string_pretty(&ServiceConfig::default())?,
+        )?;
     }
-    let config_file = std::fs::read_to_string(path_to_config_file_for_service).unwrap();
-
-    let config_data = serde_json::from_str::<ServiceConfig>(&config_file)
-        .expect("Failed to convert config file to json string");
-    Ok(config_data)
+    let config = ServiceConfig::from_file(&path_to_config_file_for_service)?;
+    for problem in config.validate() {
+        warn!("{}: {problem}", path_to_config_file_for_service.display());
+    }
+    Ok(config)
 }
 
-fn main() -> Result<(), IoError> {
+fn main() -> Result<()> {
     env_logger::init();
 
-    let config_data = setup_config_stuff().expect("Failed to setup configuration stuff");
-
-    let mut first_run = true;
-
-    loop {
-        // Handle napping at first run
-        if first_run {
-            first_run = false;
-        } else {
-            take_a_nap(config_data.sleep_time);
+    let args = Args::parse();
+    if let Some(config_dir) = args.config_dir {
+        paths::set_root(config_dir)?;
+    }
+    if args.system {
+        paths::set_system()?;
+        if !users::is_root() {
+            warn!("Running system-wide but not as root, builds can't switch to their build_user");
         }
+        trust_repos();
+    }
 
-        let dir_path = format!(
-            "{}/.config/zlorbrs/configs",
-            std::env::home_dir().unwrap().to_str().unwrap()
+    let mut config_data = setup_config_stuff()?;
+
+    let state: SharedState = Arc::new(Mutex::new(ServiceState::new(
+        paths::configs_dir()?.display().to_string(),
+    )));
+    let (events_sender, events) = mpsc::channel();
+    let socket_path = ipc::socket_path()?;
+    let listener = control::bind(&socket_path)?;
+    info!("Listening for zlorbrs-ctl on {}", socket_path.display());
+    if let Some(webhook) = &config_data.webhook {
+        let server = webhook::bind(webhook.address()?)?;
+        info!("Listening for webhooks on {}", webhook.listen);
+        webhook::spawn(
+            server,
+            webhook.secret()?,
+            Arc::clone(&state),
+            events_sender.clone(),
+            webhook::configured_targets,
         );
-        let directories = std::fs::read_dir(dir_path);
-        if directories.is_err() {
-            error!("There are no configuration files created yet");
-            continue;
+    }
+    control::spawn(
+        listener,
+        Arc::clone(&state),
+        events_sender,
+        configured_repos,
+    );
+
+    let mut scheduler = Scheduler::default();
+    let queue = BuildQueue::new(config_data.max_concurrent_builds, {
+        let state = Arc::clone(&state);
+        move |repo_name: &str, work: Work, queue: &BuildQueue| {
+            let cancel = Cancel::default();
+            control::lock(&state)
+                .repos
+                .entry(String::from(repo_name))
+                .or_default()
+                .cancel = Some(cancel.clone());
+            track(&state, repo_name, || {
+                // a build from zlorbrs-ctl may be using the checkout
+                let _lock = RepoLock::acquire(repo_name)?;
+                run_work(
+                    &paths::configs_dir()?.join(repo_name),
+                    work,
+                    &cancel,
+                    || queue.build_slot(),
+                )
+            });
+            if let Some(repo) = control::lock(&state).repos.get_mut(repo_name) {
+                repo.cancel = None;
+            }
         }
+    });
 
-        directories.unwrap().for_each(|item_wrap| {
-            let item = item_wrap.unwrap();
-            let file_contents =
-                fs::read_to_string(format!("{}/config.json", item.path().to_str().unwrap()))
-                    .unwrap();
-            let config_json = serde_json::from_str::<Config>(&file_contents).unwrap();
-
-            info!(" "); // this just makes logging easier to read
-            info!("================ {} ===============", config_json.name);
-
-            let repo = Repository::open(config_json.clone().path).expect("Failed to open repo");
-
-            // ======= Fetching ==========
-            // fast forward any changes if there is one
-            let local_branch = repo
-                .find_branch(&config_json.branch, BranchType::Local)
-                .expect("Local branch not found");
-            let local_iod: Oid = local_branch
-                .get()
-                .target()
-                .expect("Local branch has no target");
-            debug!("before iod: {local_iod}");
-
-            let _ = fast_forward(&repo, &config_json);
-
-            let remote_ref = repo
-                .resolve_reference_from_short_name(&format!("origin/{}", config_json.branch))
-                .expect("Remote ref not found");
-            let remote_iod: Oid = remote_ref.target().expect("Remote ref has no target");
-            debug!("remote iod: {remote_iod}");
-            // ======= END ==========
-
-            let dist_dir_exists = match std::fs::read_dir(format!("{}/dist", config_json.path)) {
-                Ok(_) => true,
-                Err(_) => false,
+    loop {
+        // repos added since are only opened once git trusts them
+        trust_repos();
+        // one broken repo shouldn't take the others down with it
+        for job in scheduler.due(&plans(&config_data), Instant::now(), Utc::now()) {
+            let repo_name = match &job {
+                Job::Check(repo_name) | Job::ScheduledBuild(repo_name) => repo_name.clone(),
             };
+            if control::lock(&state).is_paused(&repo_name) {
+                debug!("{repo_name} is paused, skipping it");
+                continue;
+            }
+            match job {
+                Job::Check(_) => check(&queue, &repo_name, &config_data),
+                Job::ScheduledBuild(_) => {
+                    build(&state, &queue, &repo_name, &config_data, Trigger::Scheduled)
+                }
+            }
+        }
 
-            if !dist_dir_exists || local_iod != remote_iod {
-                kick_off_build(&config_json);
+        // requests from zlorbrs-ctl are handled while waiting for the next
+        // repo to come due
+        let timeout = scheduler
+            .next_wake(Instant::now(), Utc::now())
+            .unwrap_or(Duration::from_secs(config_data.sleep_time));
+        match events.recv_timeout(timeout) {
+            Ok(event) => handle_event(event, &state, &queue, &mut config_data, &mut scheduler),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("The control socket stopped, only polling from now on");
+                thread::sleep(timeout);
             }
-        });
+        }
     }
 }
 
-fn kick_off_build(config_json: &Config) {
-    info!("Looks like we got some build pending, lets do that!");
-    let path = format!("{}", config_json.path);
-    debug!("Running build for: {}", config_json.path);
-
-    let build_command = config_json.build_command.clone();
-    let handle = std::thread::spawn(move || {
-        let set_dir_res = std::env::set_current_dir(path.clone());
-        if set_dir_res.is_err() {
-            error!(
-                "Failed to set the current directory: {}\nDir: {}",
-                set_dir_res.err().unwrap(),
-                path
-            );
-        }
-
-        let build_handle = std::process::Command::new(build_command)
-            .stdout(Stdio::piped())
-            .output();
-
-        match build_handle {
-            Ok(h) => {
-                debug!("got status: {:?}", h.status);
-                match h.status.code() {
-                    Some(0) => {
-                        // create util split_to_debug_lines
-                        let human_readable = String::from_utf8(h.stdout).unwrap();
-                        let split_readable: Vec<&str> = human_readable.split("\n").collect();
-                        for line in split_readable {
-                            info!("build succeed: {:#?}", line);
-                        }
-                    }
-                    Some(1) => {
-                        error!("build error: {:?}", h.stderr);
-                    }
-                    _ => {}
-                };
+/// .
+///
+/// # How often and when every configured repo wants to be looked at
+///
+/// `poll_interval` defaults to the service's `sleep_time`. The schedule may
+/// also come from the repository's `.zlorbrs.toml`. A config that can't be
+/// read is still checked on the default interval, so its error shows up
+fn plans(service_config: &ServiceConfig) -> Vec<Plan> {
+    let Ok(directories) = paths::configs_dir().and_then(|dir| Ok(fs::read_dir(dir)?)) else {
+        error!("There are no configuration files created yet");
+        return Vec::new();
+    };
+    let mut plans: Vec<Plan> = directories
+        .flatten()
+        .map(|item| {
+            let config = Config::from_dir(&item.path()).ok().map(|mut config| {
+                if let Ok(Some((_, pipeline))) = Pipeline::read(Path::new(&config.path)) {
+                    pipeline.apply(&mut config);
+                }
+                config
+            });
+            let poll_interval = config
+                .as_ref()
+                .and_then(|config| config.poll_interval)
+                .unwrap_or(service_config.sleep_time);
+            Plan {
+                name: item.file_name().to_string_lossy().into_owned(),
+                poll_interval: Duration::from_secs(poll_interval),
+                schedule: config.and_then(|config| config.schedule),
             }
-            Err(e) => {
-                error!("Total failure of bun_handle: {}", e);
+        })
+        .collect();
+    plans.sort_by(|a, b| a.name.cmp(&b.name));
+    plans
+}
+
+/// Lets git open the configured repositories with `--system`, see
+/// [`config::trust_configured_repos`]
+fn trust_repos() {
+    if let Err(e) = config::trust_configured_repos(None) {
+        error!("Failed to list the configured repositories for git: {e}");
+    }
+}
+
+/// Names of all repos that have a config
+fn configured_repos() -> Vec<String> {
+    let Ok(directories) = paths::configs_dir().and_then(|dir| Ok(fs::read_dir(dir)?)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = directories
+        .flatten()
+        .map(|item| item.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// .
+///
+/// # Handles a request from zlorbrs-ctl
+///
+/// Checks and builds are taken care of right away, even for paused repos since
+/// they were asked for explicitly. A reload that fails keeps the current
+/// config
+fn handle_event(
+    event: Event,
+    state: &SharedState,
+    queue: &BuildQueue,
+    config_data: &mut ServiceConfig,
+    scheduler: &mut Scheduler,
+) {
+    match event {
+        Event::Check(repo_name) => {
+            check(queue, &repo_name, config_data);
+            scheduler.checked(&repo_name, Instant::now());
+        }
+        Event::Build(repo_name) => build(state, queue, &repo_name, config_data, Trigger::Manual),
+        Event::Reload => match setup_config_stuff() {
+            Ok(new_config) => {
+                info!("Reloaded the service config");
+                if new_config.webhook != config_data.webhook {
+                    warn!("The webhook settings changed, restart the service to apply them");
+                }
+                queue.set_max_running(new_config.max_concurrent_builds);
+                *config_data = new_config;
             }
-        };
+            Err(e) => error!("Failed to reload the service config, keeping the old one: {e}"),
+        },
+    }
+}
+
+/// .
+///
+/// # Queues a check of `repo_name`, which builds when it finds changes
+///
+/// Fetching takes as long as the remote does, so it runs on the repo's worker
+/// instead of holding up other repos and requests from zlorbrs-ctl. A repo
+/// that is busy gets it queued behind that work, so it doesn't touch the
+/// checkout under a running build and any number of them make for a single
+/// follow-up build
+fn check(queue: &BuildQueue, repo_name: &str, service_config: &ServiceConfig) {
+    queue.push(repo_name, Work::check(service_config.log_retention()));
+}
+
+/// Queues a build of whatever `repo_name` has checked out
+fn build(
+    state: &SharedState,
+    queue: &BuildQueue,
+    repo_name: &str,
+    service_config: &ServiceConfig,
+    trigger: Trigger,
+) {
+    let request = track(state, repo_name, || {
+        head_request(&paths::configs_dir()?.join(repo_name), trigger)
     });
+    if let Some(request) = request {
+        queue.push(
+            repo_name,
+            Work::build(request, service_config.log_retention()),
+        );
+    }
+}
+
+/// Runs `check` for `repo_name`, keeping its status up to date for zlorbrs-ctl.
+/// Returns what it found, None when it failed
+fn track<T>(state: &SharedState, repo_name: &str, check: impl FnOnce() -> Result<T>) -> Option<T> {
+    control::lock(state)
+        .repos
+        .entry(String::from(repo_name))
+        .or_default()
+        .checking = true;
+
+    let outcome = check();
+    if let Err(e) = &outcome {
+        error!("Skipping {repo_name}: {e}");
+    }
 
-    handle.join().unwrap();
+    let mut state = control::lock(state);
+    let repo = state.repos.entry(String::from(repo_name)).or_default();
+    repo.checking = false;
+    repo.last_checked = Some(Utc::now());
+    repo.last_error = outcome.as_ref().err().map(|e| e.to_string());
+    outcome.ok()
 }
 
-fn take_a_nap(sleep_time: u64) {
-    std::thread::sleep(std::time::Duration::from_secs(sleep_time));
+/// .
+///
+/// # Checks a single repo for changes
+///
+/// Fast forwards the repo configured in `config_dir` and says what to build
+/// when new commits touching `watch_paths` came in or some of the
+/// `artifacts` are missing
+fn check_repo(config_dir: &Path) -> Result<Option<BuildRequest>> {
+    let config_json = Config::from_dir(config_dir)?;
+
+    info!(" "); // this just makes logging easier to read
+    info!("================ {} ===============", config_json.name);
+
+    let repo = Repository::open(&config_json.path)?;
+
+    // ======= Fetching ==========
+    // fast forward any changes if there is one
+    let local_branch = repo.find_branch(&config_json.branch, BranchType::Local)?;
+    let local_iod: Oid = local_branch.get().target().ok_or_else(|| {
+        Error::Config(format!("Local branch {} has no target", config_json.branch))
+    })?;
+    debug!("before iod: {local_iod}");
+
+    fast_forward(&repo, &config_json)?;
+
+    let remote_ref = repo.resolve_reference_from_short_name(&format!(
+        "{}/{}",
+        config_json.remote, config_json.branch
+    ))?;
+    let remote_iod: Oid = remote_ref.target().ok_or_else(|| {
+        Error::Config(format!(
+            "Remote ref {}/{} has no target",
+            config_json.remote, config_json.branch
+        ))
+    })?;
+    debug!("remote iod: {remote_iod}");
+    // ======= END ==========
+
+    // the repository's own settings as of the commit just checked out
+    let config_json = config_json.with_pipeline()?;
+    let missing = rebuild::missing_artifacts(&config_json)?;
+
+    let trigger = if local_iod != remote_iod
+        && rebuild::touches_watched(&repo, &config_json, local_iod, remote_iod)?
+    {
+        Trigger::NewCommit
+    } else if !missing.is_empty() {
+        info!("Missing build output: {}", missing.join(", "));
+        Trigger::MissingArtifacts
+    } else {
+        if local_iod != remote_iod {
+            info!("None of the new commits touch watch_paths, not building");
+        }
+        return Ok(None);
+    };
+    Ok(Some(BuildRequest {
+        trigger,
+        old_oid: Some(local_iod),
+        new_oid: Some(remote_iod),
+    }))
+}
+
+/// A build of the commit the repo configured in `config_dir` has checked out,
+/// whether or not anything changed
+fn head_request(config_dir: &Path, trigger: Trigger) -> Result<BuildRequest> {
+    let config_json = Config::from_dir(config_dir)?;
+    let repo = Repository::open(&config_json.path)?;
+    Ok(BuildRequest {
+        trigger,
+        old_oid: None,
+        new_oid: repo.head()?.target(),
+    })
 }
 
-fn fast_forward(repo: &Repository, config_json: &Config) -> Result<(), git2::Error> {
-    let remote: Result<Remote, git2::Error> = repo.find_remote("origin");
-    if remote.is_err() {
-        error!("Remote Not Found");
-        return Err(Error::from_str("Remote Not Found"));
+/// .
+///
+/// # Does queued work for the repo configured in `config_dir`
+///
+/// Checks first when asked to and builds when that found something or a
+/// build was asked for. A build asked for while a check was queued too
+/// builds up to whatever the check fetched. Builds wait for `build_slot` and
+/// run until what it returned is dropped
+fn run_work<Slot>(
+    config_dir: &Path,
+    work: Work,
+    cancel: &Cancel,
+    build_slot: impl FnOnce() -> Slot,
+) -> Result<()> {
+    let mut request = work.build;
+    if work.check
+        && let Some(found) = check_repo(config_dir)?
+    {
+        request = Some(match request {
+            Some(asked) => asked.merge(found),
+            None => found,
+        });
     }
+    let Some(request) = request else {
+        return Ok(());
+    };
+    let _slot = build_slot();
+    // the repository's own settings as of the commit just checked out
+    let config_json = Config::from_dir(config_dir)?.with_pipeline()?;
+    // logs and history go by the config directory, like everywhere else
+    let repo_name = config_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    kick_off_build(&repo_name, &config_json, &request, work.retention, cancel)
+}
+
+fn kick_off_build(
+    repo_name: &str,
+    config_json: &Config,
+    request: &BuildRequest,
+    retention: Retention,
+    cancel: &Cancel,
+) -> Result<()> {
+    info!("Looks like we got some build pending, lets do that!");
+    let mut log = BuildLog::create(repo_name, retention)?;
+    info!("Build #{} log: {}", log.id, log.path.display());
+
+    let started_at = SystemTime::now();
+    let outcome = build::run(config_json, &mut log, cancel);
+
+    let old_oid = request.old_oid.map(|oid| oid.to_string());
+    let new_oid = request.new_oid.map(|oid| oid.to_string());
+    let record = match &outcome {
+        Ok(result) => {
+            BuildRecord::from_result(log.id, repo_name, request.trigger, old_oid, new_oid, result)
+        }
+        Err(e) => BuildRecord::from_error(
+            log.id,
+            repo_name,
+            request.trigger,
+            old_oid,
+            new_oid,
+            started_at,
+            e,
+        ),
+    };
+    if let Err(e) = history::append(&record) {
+        error!("Failed to record build #{} in the history: {e}", log.id);
+    }
+
+    let result = outcome?;
+
+    if let Some(stopped) = result.stopped {
+        return Err(Error::Build(format!(
+            "{} {stopped}, see {}",
+            result.failed_step.unwrap_or_default(),
+            log.path.display()
+        )));
+    }
+    if !result.success() {
+        for line in result.stderr.lines() {
+            error!("build error: {line}");
+        }
+        return Err(Error::Build(format!(
+            "{} exited with {:?}, see {}",
+            result.failed_step.unwrap_or_default(),
+            result.exit_code,
+            log.path.display()
+        )));
+    }
+
+    info!("Build #{} finished in {:?}", log.id, result.duration);
+    Ok(())
+}
+
+fn fast_forward(repo: &Repository, config_json: &Config) -> Result<()> {
+    let mut remote = repo.find_remote(&config_json.remote)?;
+    let tracking = format!("refs/remotes/{}/{}", config_json.remote, config_json.branch);
+    let fetched_before = repo.refname_to_id(&tracking).ok();
 
-    // setup credentails
-    let mut callbacks = RemoteCallbacks::new();
-    callbacks.credentials(|_, _, _| {
-        Cred::userpass_plaintext(
-            // TODO: use credential helper instead
-            "USERNAME", "PASSWORD",
-        )
-    });
     // apply credentials to fetch options
     let mut fetch_options = FetchOptions::new();
-    fetch_options.remote_callbacks(callbacks);
+    fetch_options.remote_callbacks(credentials::remote_callbacks(repo, config_json));
+    // the tracking branch below says what came in, so a fetch that brings
+    // nothing new doesn't write anything
+    fetch_options.update_fetchhead(false);
 
-    let fetch_res = remote.unwrap().fetch(
-        &[config_json.branch.clone()],
-        Some(&mut fetch_options),
-        None,
-    );
-    if fetch_res.is_err() {
-        error!("failed to fetch remote: {}", fetch_res.err().unwrap());
+    // fetch into the configured remote's tracking branch so comparisons
+    // against `{remote}/{branch}` see what we just fetched
+    let refspec = format!("+refs/heads/{}:{tracking}", config_json.branch);
+    remote.fetch(&[refspec], Some(&mut fetch_options), None)?;
+
+    let fetched = repo.find_reference(&tracking)?;
+    if fetched.target() != fetched_before {
+        let mut written = vec![PathBuf::from(&tracking), Path::new("logs").join(&tracking)];
+        for entry in fs::read_dir(repo.path().join("objects/pack"))? {
+            written.push(Path::new("objects/pack").join(entry?.file_name()));
+        }
+        hand_back(repo, &written, &[])?;
     }
 
-    let fetch_head = repo.find_reference("FETCH_HEAD").unwrap();
-    let fetch_commit = repo.reference_to_annotated_commit(&fetch_head).unwrap();
-    let analysis = repo.merge_analysis(&[&fetch_commit]).unwrap();
+    let fetch_commit = repo.reference_to_annotated_commit(&fetched)?;
+    let analysis = repo.merge_analysis(&[&fetch_commit])?;
 
     if analysis.0.is_up_to_date() {
         info!("repo is already up to date, skipping fast forward");
@@ -188,24 +539,58 @@ fn fast_forward(repo: &Repository, config_json: &Config) -> Result<(), git2::Err
 
     if analysis.0.is_fast_forward() {
         info!("Repo needs an update, updating...");
+        let old_tree = repo.head()?.peel_to_tree()?;
         let refname = format!("refs/heads/{}", config_json.branch);
-        let mut reference = repo.find_reference(&refname).unwrap();
-        reference
-            .set_target(fetch_commit.id(), "Fast-Forward")
-            .unwrap();
-        repo.set_head(&refname).unwrap();
-        return repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()));
+        let mut reference = repo.find_reference(&refname)?;
+        reference.set_target(fetch_commit.id(), "Fast-Forward")?;
+        repo.set_head(&refname)?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+
+        let new_tree = repo.find_commit(fetch_commit.id())?.tree()?;
+        let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+        let checked_out: Vec<PathBuf> = diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().map(Path::to_path_buf))
+            .collect();
+        let written = ["HEAD", "logs/HEAD", "index"]
+            .iter()
+            .map(PathBuf::from)
+            .chain([PathBuf::from(&refname), Path::new("logs").join(&refname)])
+            .collect::<Vec<_>>();
+        return hand_back(repo, &written, &checked_out);
     }
 
-    error!("Fast-forward only!");
-    Err(Error::from_str("Fast-forward only!"))
+    Err(Error::Git(git2::Error::from_str("Fast-forward only!")))
+}
+
+/// .
+///
+/// # Gives what the fast-forward wrote back to whoever owns the repository
+///
+/// A service running as root, e.g. with `--system`, fetches and checks out
+/// as root. The new objects, refs and index and the checked out files would
+/// belong to root then, and neither the build running as `build_user` nor
+/// the owner's own git could write there anymore. So the `written` paths in
+/// the git directory and the `checked_out` ones in the work tree go to the
+/// owner of each
+fn hand_back(repo: &Repository, written: &[PathBuf], checked_out: &[PathBuf]) -> Result<()> {
+    if !users::is_root() {
+        return Ok(());
+    }
+    users::hand_over(repo.path(), written)?;
+    if let Some(workdir) = repo.workdir() {
+        users::hand_over(workdir, checked_out)?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::env;
+    use std::os::unix::fs::{MetadataExt, lchown};
     use std::path::PathBuf;
+    use zlorbrs_lib::config::{BuildCommand, BuildStep};
     use zlorbrs_lib::shared_test_utils::ENV_MUTEX;
 
     struct TestEnv {
@@ -267,4 +652,492 @@ mod tests {
         let result = setup_config_stuff();
         assert!(result.is_err());
     }
+
+    struct TestRemote {
+        dir: PathBuf,
+        upstream: Repository,
+        local: Repository,
+        branch: String,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TestRemote {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn commit_file(repo: &Repository, name: &str, contents: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        fs::write(workdir.join(name), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test User", "test@example.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, name, &tree, &parents)
+            .unwrap()
+    }
+
+    fn setup_test_remote(test_name: &str) -> TestRemote {
+        let lock = ENV_MUTEX.lock().unwrap();
+        let mut dir = env::temp_dir();
+        dir.push(format!("zlorbrs_svc_remote_{}", test_name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("home")).unwrap();
+
+        // builds write their logs below HOME
+        unsafe {
+            env::set_var("HOME", dir.join("home").to_str().unwrap());
+        }
+
+        let upstream = Repository::init(dir.join("upstream")).unwrap();
+        commit_file(&upstream, "README.md", "first");
+        let branch = upstream.head().unwrap().shorthand().unwrap().to_string();
+
+        let url = format!("file://{}", dir.join("upstream").to_str().unwrap());
+        let local = Repository::clone(&url, dir.join("local")).unwrap();
+
+        TestRemote {
+            dir,
+            upstream,
+            local,
+            branch,
+            _lock: lock,
+        }
+    }
+
+    fn test_config(remote: &TestRemote) -> Config {
+        Config {
+            name: String::from("test_repo"),
+            path: remote.dir.join("local").to_str().unwrap().to_string(),
+            branch: remote.branch.clone(),
+            remote: String::from("origin"),
+            build_command: BuildCommand::from("true"),
+            ..Default::default()
+        }
+    }
+
+    fn manual_request() -> BuildRequest {
+        BuildRequest {
+            trigger: Trigger::MissingArtifacts,
+            old_oid: None,
+            new_oid: None,
+        }
+    }
+
+    fn write_config(remote: &TestRemote, config: &Config) -> PathBuf {
+        let config_dir = remote.dir.join("configs/test_repo");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(
+            config_dir.join("config.json"),
+            serde_json::to_string(config).unwrap(),
+        )
+        .unwrap();
+        config_dir
+    }
+
+    #[test]
+    fn test_check_repo_records_history() {
+        let remote = setup_test_remote("records_history");
+        let old_head = remote.local.head().unwrap().target().unwrap();
+        let new_head = commit_file(&remote.upstream, "README.md", "second");
+        let config_dir = write_config(&remote, &test_config(&remote));
+
+        // a new commit builds, and since dist/ never shows up the next check
+        // builds again because the output is missing
+        run_work(
+            &config_dir,
+            Work::check(Retention::default()),
+            &Cancel::default(),
+            || (),
+        )
+        .unwrap();
+        run_work(
+            &config_dir,
+            Work::check(Retention::default()),
+            &Cancel::default(),
+            || (),
+        )
+        .unwrap();
+
+        let records = history::read("test_repo").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, 1);
+        assert_eq!(records[0].trigger, Trigger::NewCommit);
+        assert_eq!(records[0].old_oid, Some(old_head.to_string()));
+        assert_eq!(records[0].new_oid, Some(new_head.to_string()));
+        assert_eq!(records[0].status, history::BuildStatus::Success);
+        assert_eq!(records[1].id, 2);
+        assert_eq!(records[1].trigger, Trigger::MissingArtifacts);
+    }
+
+    #[test]
+    fn test_check_repo_rebuild_condition() {
+        let remote = setup_test_remote("rebuild_condition");
+        let mut config = test_config(&remote);
+        config.artifacts = Some(vec![String::from("public/*.html")]);
+        config.watch_paths = vec![String::from("src")];
+        let config_dir = write_config(&remote, &config);
+
+        // commits outside watch_paths are checked out without building
+        let docs = commit_file(&remote.upstream, "README.md", "second");
+        fs::create_dir_all(remote.dir.join("local/public")).unwrap();
+        fs::write(remote.dir.join("local/public/index.html"), "").unwrap();
+        assert_eq!(check_repo(&config_dir).unwrap(), None);
+        assert_eq!(remote.local.head().unwrap().target(), Some(docs));
+
+        fs::create_dir_all(remote.dir.join("upstream/src")).unwrap();
+        let src = commit_file(&remote.upstream, "src/main.ts", "code");
+        let request = check_repo(&config_dir).unwrap().unwrap();
+        assert_eq!(request.trigger, Trigger::NewCommit);
+        assert_eq!(request.old_oid, Some(docs));
+        assert_eq!(request.new_oid, Some(src));
+
+        // and missing output builds even without new commits
+        fs::remove_file(remote.dir.join("local/public/index.html")).unwrap();
+        let request = check_repo(&config_dir).unwrap().unwrap();
+        assert_eq!(request.trigger, Trigger::MissingArtifacts);
+    }
+
+    #[test]
+    fn test_force_build_is_manual() {
+        let remote = setup_test_remote("force_build");
+        let head = remote.local.head().unwrap().target().unwrap();
+        // named differently from its directory, which is what counts
+        let mut config = test_config(&remote);
+        config.name = String::from("Website");
+        let config_dir = write_config(&remote, &config);
+
+        let request = head_request(&config_dir, Trigger::Manual).unwrap();
+        run_work(
+            &config_dir,
+            Work::build(request, Retention::default()),
+            &Cancel::default(),
+            || (),
+        )
+        .unwrap();
+
+        let records = history::read("test_repo").unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].repo, "test_repo");
+        assert_eq!(zlorbrs_lib::logs::list("test_repo").unwrap().len(), 1);
+        assert_eq!(records[0].trigger, Trigger::Manual);
+        assert_eq!(records[0].old_oid, None);
+        assert_eq!(records[0].new_oid, Some(head.to_string()));
+    }
+
+    #[test]
+    fn test_track_records_outcome() {
+        let state: SharedState = Arc::new(Mutex::new(ServiceState::new(String::new())));
+
+        track(&state, "test_repo", || {
+            assert!(control::lock(&state).repos["test_repo"].checking);
+            Err::<(), _>(Error::Build(String::from("boom")))
+        });
+        let repo = control::lock(&state).repos["test_repo"].clone();
+        assert!(!repo.checking);
+        assert!(repo.last_checked.is_some());
+        assert_eq!(repo.last_error.as_deref(), Some("build error: boom"));
+
+        track(&state, "test_repo", || Ok(()));
+        assert_eq!(control::lock(&state).repos["test_repo"].last_error, None);
+    }
+
+    #[test]
+    fn test_check_repo_malformed_config() {
+        let remote = setup_test_remote("malformed_config");
+        let config_dir = remote.dir.join("configs/test_repo");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(config_dir.join("config.json"), "{ not json").unwrap();
+
+        let result = check_repo(&config_dir);
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_check_repo_missing_branch() {
+        let remote = setup_test_remote("missing_branch");
+        let mut config = test_config(&remote);
+        config.branch = String::from("does-not-exist");
+
+        let config_dir = remote.dir.join("configs/test_repo");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(
+            config_dir.join("config.json"),
+            serde_json::to_string(&config).unwrap(),
+        )
+        .unwrap();
+
+        let result = check_repo(&config_dir);
+        assert!(matches!(result, Err(Error::Git(_))));
+    }
+
+    #[test]
+    fn test_kick_off_build_failure() {
+        let remote = setup_test_remote("build_failure");
+        let mut config = test_config(&remote);
+
+        config.build_command = BuildCommand::from("false");
+        assert!(matches!(
+            kick_off_build(
+                "test_repo",
+                &config,
+                &manual_request(),
+                Retention::default(),
+                &Cancel::default()
+            ),
+            Err(Error::Build(_))
+        ));
+
+        config.build_command = BuildCommand::from("zlorbrs-command-that-does-not-exist");
+        assert!(matches!(
+            kick_off_build(
+                "test_repo",
+                &config,
+                &manual_request(),
+                Retention::default(),
+                &Cancel::default()
+            ),
+            Err(Error::Build(_))
+        ));
+
+        // both end up in the history
+        let records = history::read("test_repo").unwrap();
+        assert_eq!(records[0].status, history::BuildStatus::Failed);
+        assert_eq!(records[0].exit_code, Some(1));
+        assert_eq!(records[1].status, history::BuildStatus::Error);
+        assert!(records[1].error.is_some());
+    }
+
+    #[test]
+    fn test_kick_off_build_writes_log() {
+        let remote = setup_test_remote("build_log");
+        let mut config = test_config(&remote);
+        config.build_command = BuildCommand::from("echo hello; echo oops >&2");
+        config.shell = true;
+
+        assert!(
+            kick_off_build(
+                "test_repo",
+                &config,
+                &manual_request(),
+                Retention::default(),
+                &Cancel::default()
+            )
+            .is_ok()
+        );
+        config.build_command = BuildCommand::from("exit 2");
+        assert!(
+            kick_off_build(
+                "test_repo",
+                &config,
+                &manual_request(),
+                Retention::default(),
+                &Cancel::default()
+            )
+            .is_err()
+        );
+
+        let logs_dir = remote.dir.join("home/.config/zlorbrs/logs/test_repo");
+        let first = fs::read_to_string(logs_dir.join("1.log")).unwrap();
+        assert!(first.contains("hello\n"));
+        assert!(first.contains("oops\n"));
+        let second = fs::read_to_string(logs_dir.join("2.log")).unwrap();
+        assert!(second.contains("failed with exit code Some(2)"));
+    }
+
+    #[test]
+    fn test_kick_off_build_steps() {
+        let remote = setup_test_remote("build_steps");
+        let mut config = test_config(&remote);
+        let marker = remote.dir.join("local/built");
+
+        // arguments are split from the command line, and shell steps can redirect
+        config.steps = vec![
+            BuildStep {
+                name: Some(String::from("install")),
+                command: BuildCommand::from("test -d ."),
+                shell: false,
+            },
+            BuildStep {
+                name: Some(String::from("build")),
+                command: BuildCommand::from("echo done > built"),
+                shell: true,
+            },
+        ];
+        assert!(
+            kick_off_build(
+                "test_repo",
+                &config,
+                &manual_request(),
+                Retention::default(),
+                &Cancel::default()
+            )
+            .is_ok()
+        );
+        assert!(marker.exists());
+
+        // a failing step stops the ones after it
+        fs::remove_file(&marker).unwrap();
+        config.steps.insert(
+            0,
+            BuildStep {
+                name: Some(String::from("test")),
+                command: BuildCommand::Args(vec![String::from("false")]),
+                shell: false,
+            },
+        );
+        assert!(matches!(
+            kick_off_build(
+                "test_repo",
+                &config,
+                &manual_request(),
+                Retention::default(),
+                &Cancel::default()
+            ),
+            Err(Error::Build(_))
+        ));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_kick_off_build_env_and_working_dir() {
+        let remote = setup_test_remote("build_env");
+        let package_dir = remote.dir.join("local/packages/web");
+        fs::create_dir_all(&package_dir).unwrap();
+        fs::write(remote.dir.join("local/.env"), "API_KEY=secret\n").unwrap();
+
+        let mut config = test_config(&remote);
+        config.working_dir = Some(String::from("packages/web"));
+        config.env_file = Some(String::from(".env"));
+        config
+            .env
+            .insert(String::from("NODE_ENV"), String::from("production"));
+        config.build_command = BuildCommand::from("echo $NODE_ENV $API_KEY > out");
+        config.shell = true;
+
+        assert!(
+            kick_off_build(
+                "test_repo",
+                &config,
+                &manual_request(),
+                Retention::default(),
+                &Cancel::default()
+            )
+            .is_ok()
+        );
+        let out = fs::read_to_string(package_dir.join("out")).unwrap();
+        assert_eq!(out.trim(), "production secret");
+    }
+
+    #[test]
+    fn test_fast_forward_file_remote() {
+        let remote = setup_test_remote("fast_forward");
+        let new_head = commit_file(&remote.upstream, "README.md", "second");
+
+        let result = fast_forward(&remote.local, &test_config(&remote));
+        assert!(result.is_ok());
+
+        let local_head = remote.local.head().unwrap().target().unwrap();
+        assert_eq!(local_head, new_head);
+    }
+
+    #[test]
+    fn test_fast_forward_hands_back_to_owner() {
+        if !users::is_root() {
+            return;
+        }
+        let remote = setup_test_remote("hands_back");
+        let nobody = users::user("nobody").unwrap();
+        let local_dir = remote.dir.join("local");
+        lchown(&local_dir, Some(nobody.uid), Some(nobody.gid)).unwrap();
+        let mut dirs = vec![local_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                lchown(&path, Some(nobody.uid), Some(nobody.gid)).unwrap();
+                if fs::symlink_metadata(&path).unwrap().is_dir() {
+                    dirs.push(path);
+                }
+            }
+        }
+
+        fs::create_dir_all(remote.dir.join("upstream/docs/guide")).unwrap();
+        commit_file(&remote.upstream, "docs/guide/index.md", "new");
+        assert!(fast_forward(&remote.local, &test_config(&remote)).is_ok());
+        // and a check that finds nothing new writes nothing
+        assert!(fast_forward(&remote.local, &test_config(&remote)).is_ok());
+
+        // running as root, none of what the fetch and checkout wrote is
+        // left to root
+        let mut left_to_root = Vec::new();
+        let mut dirs = vec![local_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                let metadata = fs::symlink_metadata(&path).unwrap();
+                if metadata.uid() == 0 {
+                    left_to_root.push(path.clone());
+                }
+                if metadata.is_dir() {
+                    dirs.push(path);
+                }
+            }
+        }
+        assert_eq!(left_to_root, Vec::<PathBuf>::new());
+        let guide = fs::metadata(local_dir.join("docs/guide/index.md")).unwrap();
+        assert_eq!((guide.uid(), guide.gid()), (nobody.uid, nobody.gid));
+    }
+
+    #[test]
+    fn test_fast_forward_configured_remote() {
+        let remote = setup_test_remote("configured_remote");
+        let new_head = commit_file(&remote.upstream, "README.md", "second");
+
+        // track the same upstream under a different name and drop origin
+        let url = remote
+            .local
+            .find_remote("origin")
+            .unwrap()
+            .url()
+            .unwrap()
+            .to_string();
+        remote.local.remote("upstream", &url).unwrap();
+        remote.local.remote_delete("origin").unwrap();
+
+        let mut config = test_config(&remote);
+        config.remote = String::from("upstream");
+
+        assert!(fast_forward(&remote.local, &config).is_ok());
+        assert_eq!(remote.local.head().unwrap().target().unwrap(), new_head);
+
+        let tracking = remote
+            .local
+            .resolve_reference_from_short_name(&format!("upstream/{}", remote.branch))
+            .unwrap();
+        assert_eq!(tracking.target().unwrap(), new_head);
+    }
+
+    #[test]
+    fn test_fast_forward_missing_remote() {
+        let remote = setup_test_remote("missing_remote");
+        let mut config = test_config(&remote);
+        config.remote = String::from("mirror");
+
+        assert!(fast_forward(&remote.local, &config).is_err());
+    }
+
+    #[test]
+    fn test_fast_forward_up_to_date() {
+        let remote = setup_test_remote("up_to_date");
+        let before = remote.local.head().unwrap().target().unwrap();
+
+        let result = fast_forward(&remote.local, &test_config(&remote));
+        assert!(result.is_ok());
+
+        assert_eq!(remote.local.head().unwrap().target().unwrap(), before);
+    }
 }
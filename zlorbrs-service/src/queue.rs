@@ -0,0 +1,327 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+
+use git2::Oid;
+use zlorbrs_lib::{history::Trigger, logs::Retention};
+
+/// Why and from which commits a build is started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BuildRequest {
+    pub(crate) trigger: Trigger,
+    pub(crate) old_oid: Option<Oid>,
+    pub(crate) new_oid: Option<Oid>,
+}
+
+impl BuildRequest {
+    /// One build standing in for this one and a `later` one, keeping why and
+    /// from where this one was asked for and building up to the later commit
+    pub(crate) fn merge(self, later: BuildRequest) -> BuildRequest {
+        BuildRequest {
+            trigger: self.trigger,
+            old_oid: self.old_oid,
+            new_oid: later.new_oid.or(self.new_oid),
+        }
+    }
+}
+
+/// What a worker does for a repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Work {
+    pub(crate) check: bool, // fetch first, and build when something changed
+    pub(crate) build: Option<BuildRequest>, // build even when nothing changed
+    pub(crate) retention: Retention,
+}
+
+impl Work {
+    pub(crate) fn check(retention: Retention) -> Self {
+        Self {
+            check: true,
+            build: None,
+            retention,
+        }
+    }
+
+    pub(crate) fn build(request: BuildRequest, retention: Retention) -> Self {
+        Self {
+            check: false,
+            build: Some(request),
+            retention,
+        }
+    }
+
+    /// Folds `later` into work that is still waiting, so however often a repo
+    /// is asked for it only runs once more
+    fn merge(&mut self, later: Work) {
+        self.check |= later.check;
+        self.build = match (self.build.take(), later.build) {
+            (Some(first), Some(later)) => Some(first.merge(later)),
+            (first, later) => first.or(later),
+        };
+        self.retention = later.retention;
+    }
+}
+
+#[derive(Debug)]
+struct Queue {
+    running: BTreeSet<String>,
+    waiting: VecDeque<String>,
+    pending: BTreeMap<String, Work>,
+}
+
+/// The builds running and the workers waiting to start one
+#[derive(Debug)]
+struct Slots {
+    max_running: usize,
+    running: usize,
+    waiting: VecDeque<u64>, // tickets of the waiting workers, first come first served
+    next_ticket: u64,
+}
+
+/// A worker's turn to build, over once dropped
+pub(crate) struct BuildSlot {
+    slots: Arc<(Mutex<Slots>, Condvar)>,
+}
+
+impl Drop for BuildSlot {
+    fn drop(&mut self) {
+        let (slots, freed) = &*self.slots;
+        slots.lock().unwrap_or_else(|e| e.into_inner()).running -= 1;
+        freed.notify_all();
+    }
+}
+
+type Runner = dyn Fn(&str, Work, &BuildQueue) + Send + Sync;
+
+/// .
+///
+/// # Works on repos in the background
+///
+/// A repo is never worked on by two workers at once. Work for a repo that is
+/// busy waits until it is done, and everything asked for in the meantime is
+/// merged into that one follow-up run. Other repos get a worker right away,
+/// so a slow fetch only holds up its own repo, while builds wait for one of a
+/// bounded number of slots, see [`BuildQueue::build_slot`]
+#[derive(Clone)]
+pub(crate) struct BuildQueue {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    slots: Arc<(Mutex<Slots>, Condvar)>,
+    run: Arc<Runner>,
+}
+
+impl BuildQueue {
+    /// A queue doing work with `run` and building at most `max_running` repos
+    /// at once
+    pub(crate) fn new(
+        max_running: usize,
+        run: impl Fn(&str, Work, &BuildQueue) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            queue: Arc::new((
+                Mutex::new(Queue {
+                    running: BTreeSet::new(),
+                    waiting: VecDeque::new(),
+                    pending: BTreeMap::new(),
+                }),
+                Condvar::new(),
+            )),
+            slots: Arc::new((
+                Mutex::new(Slots {
+                    max_running: max_running.max(1),
+                    running: 0,
+                    waiting: VecDeque::new(),
+                    next_ticket: 0,
+                }),
+                Condvar::new(),
+            )),
+            run: Arc::new(run),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_slots(&self) -> MutexGuard<'_, Slots> {
+        self.slots.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queues `work` for `repo_name`, merging it with what is already waiting
+    pub(crate) fn push(&self, repo_name: &str, work: Work) {
+        let mut queue = self.lock();
+        match queue.pending.get_mut(repo_name) {
+            Some(pending) => pending.merge(work),
+            None => {
+                queue.pending.insert(String::from(repo_name), work);
+                queue.waiting.push_back(String::from(repo_name));
+            }
+        }
+        self.dispatch(queue);
+    }
+
+    /// Changes how many builds may run at once, for a reloaded service config
+    pub(crate) fn set_max_running(&self, max_running: usize) {
+        self.lock_slots().max_running = max_running.max(1);
+        self.slots.1.notify_all();
+    }
+
+    /// .
+    ///
+    /// # Waits until the calling worker may build
+    ///
+    /// At most `max_running` builds run at once, and workers get to build in
+    /// the order they asked. The build is done when the slot is dropped
+    pub(crate) fn build_slot(&self) -> BuildSlot {
+        let mut slots = self.lock_slots();
+        let ticket = slots.next_ticket;
+        slots.next_ticket += 1;
+        slots.waiting.push_back(ticket);
+        while slots.waiting.front() != Some(&ticket) || slots.running >= slots.max_running {
+            slots = self.slots.1.wait(slots).unwrap_or_else(|e| e.into_inner());
+        }
+        slots.waiting.pop_front();
+        slots.running += 1;
+        // the next in line may fit as well
+        self.slots.1.notify_all();
+        BuildSlot {
+            slots: Arc::clone(&self.slots),
+        }
+    }
+
+    /// Hands waiting work of idle repos to new workers
+    fn dispatch(&self, mut queue: MutexGuard<'_, Queue>) {
+        while let Some(index) = queue
+            .waiting
+            .iter()
+            .position(|name| !queue.running.contains(name))
+        {
+            let repo_name = queue.waiting.remove(index).unwrap_or_default();
+            let Some(work) = queue.pending.remove(&repo_name) else {
+                continue;
+            };
+            queue.running.insert(repo_name.clone());
+
+            let this = self.clone();
+            thread::spawn(move || {
+                (this.run)(&repo_name, work, &this);
+                let mut queue = this.lock();
+                queue.running.remove(&repo_name);
+                this.queue.1.notify_all();
+                this.dispatch(queue);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+
+    impl BuildQueue {
+        fn wait_idle(&self) {
+            let mut queue = self.lock();
+            while !queue.running.is_empty() || !queue.pending.is_empty() {
+                queue = self.queue.1.wait(queue).unwrap();
+            }
+        }
+
+        fn is_busy(&self, repo_name: &str) -> bool {
+            let queue = self.lock();
+            queue.running.contains(repo_name) || queue.pending.contains_key(repo_name)
+        }
+    }
+
+    fn request(trigger: Trigger, old: u8, new: u8) -> BuildRequest {
+        BuildRequest {
+            trigger,
+            old_oid: Some(Oid::from_bytes(&[old; 20]).unwrap()),
+            new_oid: Some(Oid::from_bytes(&[new; 20]).unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut work = Work::check(Retention::default());
+        work.merge(Work::build(
+            request(Trigger::NewCommit, 1, 2),
+            Retention::default(),
+        ));
+        work.merge(Work::build(
+            request(Trigger::Manual, 2, 3),
+            Retention::default(),
+        ));
+        assert!(work.check);
+        assert_eq!(work.build, Some(request(Trigger::NewCommit, 1, 3)));
+    }
+
+    #[test]
+    fn test_one_build_per_repo_and_coalescing() {
+        let (started, starts) = mpsc::channel();
+        let gate = Arc::new(Mutex::new(()));
+        let held = gate.lock().unwrap();
+        let worker_gate = Arc::clone(&gate);
+        let queue = BuildQueue::new(2, move |repo_name, work, _| {
+            started.send((repo_name.to_string(), work.check)).unwrap();
+            drop(worker_gate.lock().unwrap());
+        });
+
+        // the first build of "site" runs, everything after it waits for it
+        queue.push(
+            "site",
+            Work::build(request(Trigger::Manual, 1, 1), Retention::default()),
+        );
+        assert_eq!(starts.recv().unwrap(), (String::from("site"), false));
+        for _ in 0..3 {
+            queue.push("site", Work::check(Retention::default()));
+        }
+        assert!(queue.is_busy("site"));
+
+        // another repo gets a worker of its own
+        queue.push("docs", Work::check(Retention::default()));
+        assert_eq!(starts.recv().unwrap(), (String::from("docs"), true));
+        assert!(starts.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // the three checks run as one
+        drop(held);
+        queue.wait_idle();
+        assert_eq!(
+            starts.try_iter().collect::<Vec<_>>(),
+            [(String::from("site"), true)]
+        );
+        assert!(!queue.is_busy("site"));
+    }
+
+    #[test]
+    fn test_max_running() {
+        let (started, starts) = mpsc::channel();
+        let gate = Arc::new(Mutex::new(()));
+        let held = gate.lock().unwrap();
+        let worker_gate = Arc::clone(&gate);
+        let queue = BuildQueue::new(1, move |repo_name, work, queue| {
+            // checks go ahead, builds wait for a slot
+            let _slot = work.build.is_some().then(|| queue.build_slot());
+            started.send(repo_name.to_string()).unwrap();
+            drop(worker_gate.lock().unwrap());
+        });
+        let build = || Work::build(request(Trigger::Manual, 1, 1), Retention::default());
+
+        queue.push("a", build());
+        assert_eq!(starts.recv().unwrap(), "a");
+        queue.push("b", build());
+        assert!(starts.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // a check doesn't wait for the builds
+        queue.push("c", Work::check(Retention::default()));
+        assert_eq!(starts.recv().unwrap(), "c");
+
+        // raising the limit starts the waiting build right away
+        queue.set_max_running(2);
+        assert_eq!(starts.recv().unwrap(), "b");
+
+        drop(held);
+        queue.wait_idle();
+    }
+}
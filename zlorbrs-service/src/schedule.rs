@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use zlorbrs_lib::config;
+
+/// How often and when a repo wants to be looked at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Plan {
+    pub(crate) name: String,
+    pub(crate) poll_interval: Duration,
+    pub(crate) schedule: Option<String>, // cron expression, e.g. "0 3 * * *"
+}
+
+/// Something the main loop has to do for a repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Job {
+    Check(String),          // fetch and build when something changed
+    ScheduledBuild(String), // build whether or not anything changed
+}
+
+/// When a repo is next due
+#[derive(Debug)]
+struct Due {
+    poll_interval: Duration,
+    check: Instant,
+    schedule: Option<String>,
+    build: Option<DateTime<Utc>>,
+}
+
+/// .
+///
+/// # Keeps track of when every repo is next due
+///
+/// Each repo is checked every `poll_interval` on its own clock and built
+/// whenever its schedule comes due, so a repo that is slow to check or
+/// build doesn't push the others back
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    repos: BTreeMap<String, Due>,
+}
+
+impl Scheduler {
+    /// .
+    ///
+    /// # What is due at `now`
+    ///
+    /// Brings the schedule in line with `plans` first. New repos are checked
+    /// right away, repos without a plan are forgotten, and a changed interval
+    /// or schedule applies from now on. Everything returned is considered
+    /// done, its next time is already set
+    pub(crate) fn due(&mut self, plans: &[Plan], now: Instant, wall: DateTime<Utc>) -> Vec<Job> {
+        self.repos
+            .retain(|name, _| plans.iter().any(|plan| &plan.name == name));
+
+        let mut jobs = Vec::new();
+        for plan in plans {
+            let due = self.repos.entry(plan.name.clone()).or_insert_with(|| Due {
+                poll_interval: plan.poll_interval,
+                check: now,
+                schedule: None,
+                build: None,
+            });
+            if due.poll_interval != plan.poll_interval {
+                // count the new interval from the last check
+                let last_check = due.check.checked_sub(due.poll_interval).unwrap_or(now);
+                due.check = last_check + plan.poll_interval;
+                due.poll_interval = plan.poll_interval;
+            }
+            if due.schedule != plan.schedule {
+                due.build = next_build(&plan.name, plan.schedule.as_deref(), wall);
+                due.schedule = plan.schedule.clone();
+            }
+
+            if due.check <= now {
+                jobs.push(Job::Check(plan.name.clone()));
+                due.check = now + due.poll_interval;
+            }
+            if due.build.is_some_and(|build| build <= wall) {
+                jobs.push(Job::ScheduledBuild(plan.name.clone()));
+                due.build = next_build(&plan.name, due.schedule.as_deref(), wall);
+            }
+        }
+        jobs
+    }
+
+    /// Pushes the next check of `repo_name` back a whole interval, for when
+    /// it was just checked on request
+    pub(crate) fn checked(&mut self, repo_name: &str, now: Instant) {
+        if let Some(due) = self.repos.get_mut(repo_name) {
+            due.check = now + due.poll_interval;
+        }
+    }
+
+    /// How long until the next repo is due, None when there are no repos
+    pub(crate) fn next_wake(&self, now: Instant, wall: DateTime<Utc>) -> Option<Duration> {
+        self.repos
+            .values()
+            .flat_map(|due| {
+                let check = due.check.saturating_duration_since(now);
+                let build = due
+                    .build
+                    .map(|build| (build - wall).to_std().unwrap_or_default());
+                [Some(check), build]
+            })
+            .flatten()
+            .min()
+    }
+}
+
+fn next_build(
+    repo_name: &str,
+    schedule: Option<&str>,
+    wall: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    match config::next_occurrence(schedule?, wall) {
+        Ok(next) => Some(next),
+        Err(e) => {
+            warn!("{repo_name} won't build on a schedule: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(name: &str, seconds: u64, schedule: Option<&str>) -> Plan {
+        Plan {
+            name: String::from(name),
+            poll_interval: Duration::from_secs(seconds),
+            schedule: schedule.map(String::from),
+        }
+    }
+
+    fn check(name: &str) -> Job {
+        Job::Check(String::from(name))
+    }
+
+    #[test]
+    fn test_each_repo_on_its_own_interval() {
+        let mut scheduler = Scheduler::default();
+        let plans = [plan("fast", 10, None), plan("slow", 60, None)];
+        let start = Instant::now();
+        let wall = Utc::now();
+
+        // everything is checked once on startup
+        assert_eq!(
+            scheduler.due(&plans, start, wall),
+            [check("fast"), check("slow")]
+        );
+        assert!(scheduler.due(&plans, start, wall).is_empty());
+        assert_eq!(
+            scheduler.next_wake(start, wall),
+            Some(Duration::from_secs(10))
+        );
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(scheduler.due(&plans, later, wall), [check("fast")]);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            scheduler.due(&plans, later, wall),
+            [check("fast"), check("slow")]
+        );
+
+        // a check on request restarts the clock
+        scheduler.checked("fast", later + Duration::from_secs(5));
+        assert!(
+            scheduler
+                .due(&plans, later + Duration::from_secs(10), wall)
+                .is_empty()
+        );
+
+        // removed repos are forgotten
+        scheduler.due(&plans[1..], later, wall);
+        assert_eq!(
+            scheduler.next_wake(later, wall),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(Scheduler::default().next_wake(later, wall), None);
+    }
+
+    #[test]
+    fn test_changed_interval() {
+        let mut scheduler = Scheduler::default();
+        let start = Instant::now();
+        let wall = Utc::now();
+        scheduler.due(&[plan("site", 3600, None)], start, wall);
+
+        // shortening the interval doesn't wait out the old one
+        let later = start + Duration::from_secs(120);
+        assert_eq!(
+            scheduler.due(&[plan("site", 60, None)], later, wall),
+            [check("site")]
+        );
+        assert_eq!(
+            scheduler.next_wake(later, wall),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_scheduled_builds() {
+        let mut scheduler = Scheduler::default();
+        let plans = [plan("site", 3600, Some("* * * * *"))];
+        let start = Instant::now();
+        let wall = Utc::now();
+
+        // not on startup, only once the schedule comes due
+        assert_eq!(scheduler.due(&plans, start, wall), [check("site")]);
+        let wake = scheduler.next_wake(start, wall).unwrap();
+        assert!(wake <= Duration::from_secs(60));
+
+        let wall = wall + chrono::Duration::seconds(61);
+        assert_eq!(
+            scheduler.due(&plans, start, wall),
+            [Job::ScheduledBuild(String::from("site"))]
+        );
+        assert!(scheduler.due(&plans, start, wall).is_empty());
+
+        // a broken schedule just means no scheduled builds
+        let plans = [plan("site", 3600, Some("every night"))];
+        assert!(scheduler.due(&plans, start, wall).is_empty());
+        assert_eq!(
+            scheduler.next_wake(start, wall),
+            Some(Duration::from_secs(3600))
+        );
+    }
+}
@@ -0,0 +1,663 @@
+use std::{collections::BTreeMap, fs, io::Read, net::SocketAddr, sync::mpsc::Sender, thread};
+
+use git2::Repository;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use sha2::Sha256;
+use tiny_http::{Method, Server};
+use zlorbrs_lib::{Error, Result, config::Config, paths};
+
+use crate::control::{self, Event, SharedState};
+
+/// Pushes are small, anything bigger than this isn't one
+const MAX_BODY_BYTES: u64 = 5 * 1024 * 1024;
+
+/// Which forge sent a webhook, told apart by its event header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Provider {
+    GitHub,
+    GitLab,
+    Gitea,
+    Generic, // {"repository": "<name or url>", "branch": "main"}
+}
+
+impl Provider {
+    /// Gitea sends GitHub's headers too, so it is looked for first
+    fn detect(headers: &BTreeMap<String, String>) -> Self {
+        if headers.contains_key("x-gitea-event") {
+            Provider::Gitea
+        } else if headers.contains_key("x-gitlab-event") {
+            Provider::GitLab
+        } else if headers.contains_key("x-github-event") {
+            Provider::GitHub
+        } else {
+            Provider::Generic
+        }
+    }
+
+    fn event_header(self) -> Option<&'static str> {
+        match self {
+            Provider::GitHub => Some("x-github-event"),
+            Provider::GitLab => Some("x-gitlab-event"),
+            Provider::Gitea => Some("x-gitea-event"),
+            Provider::Generic => None,
+        }
+    }
+
+    fn push_event(self) -> &'static str {
+        match self {
+            Provider::GitLab => "Push Hook",
+            _ => "push",
+        }
+    }
+}
+
+/// A webhook request, with lowercased header names
+#[derive(Debug, Default)]
+pub(crate) struct Delivery {
+    pub(crate) headers: BTreeMap<String, String>,
+    pub(crate) body: Vec<u8>,
+}
+
+/// The HTTP status and message a delivery is answered with
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Reply {
+    pub(crate) status: u16,
+    pub(crate) message: String,
+}
+
+impl Reply {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// What a push says about where it went
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Push {
+    names: Vec<String>, // e.g. site and org/site
+    urls: Vec<String>,  // normalized, see normalize_url
+    branch: Option<String>,
+}
+
+/// A configured repo as webhooks are matched against it
+#[derive(Debug, Clone)]
+pub(crate) struct Target {
+    pub(crate) name: String,
+    pub(crate) branch: String,
+    pub(crate) remote_url: Option<String>, // of the configured remote
+}
+
+/// Every configured repo, skipping configs that can't be read
+pub(crate) fn configured_targets() -> Vec<Target> {
+    let Ok(directories) = paths::configs_dir().and_then(|dir| Ok(fs::read_dir(dir)?)) else {
+        return Vec::new();
+    };
+    directories
+        .flatten()
+        .filter_map(|entry| {
+            let config = Config::from_dir(&entry.path()).ok()?;
+            Some(Target {
+                remote_url: Repository::open(&config.path).ok().and_then(|repo| {
+                    repo.find_remote(&config.remote)
+                        .ok()?
+                        .url()
+                        .map(String::from)
+                }),
+                // what the rest of the service knows the repo by
+                name: entry.file_name().to_string_lossy().into_owned(),
+                branch: config.branch,
+            })
+        })
+        .collect()
+}
+
+/// Starts listening for webhooks on `address`
+pub(crate) fn bind(address: SocketAddr) -> Result<Server> {
+    Server::http(address)
+        .map_err(|e| Error::Ipc(format!("Failed to listen for webhooks on {address}: {e}")))
+}
+
+/// .
+///
+/// # Serves webhooks in the background
+///
+/// Every push that is signed with `secret` and matches configured repos
+/// queues a check of them through `events`, so they don't wait for the next
+/// round
+pub(crate) fn spawn(
+    server: Server,
+    secret: Vec<u8>,
+    state: SharedState,
+    events: Sender<Event>,
+    targets: fn() -> Vec<Target>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let reply = if *request.method() != Method::Post {
+                Reply::new(405, "Webhooks are POSTed")
+            } else {
+                match read_delivery(&mut request) {
+                    Ok(delivery) => handle(&delivery, &secret, &state, &events, targets),
+                    Err(e) => Reply::new(400, e.to_string()),
+                }
+            };
+            debug!(
+                "Webhook from {:?}: {} {}",
+                request.remote_addr(),
+                reply.status,
+                reply.message
+            );
+            let response = tiny_http::Response::from_string(reply.message + "\n")
+                .with_status_code(reply.status);
+            if let Err(e) = request.respond(response) {
+                error!("Failed to answer a webhook: {e}");
+            }
+        }
+    })
+}
+
+fn read_delivery(request: &mut tiny_http::Request) -> Result<Delivery> {
+    let headers = request
+        .headers()
+        .iter()
+        .map(|header| {
+            (
+                header.field.as_str().as_str().to_ascii_lowercase(),
+                header.value.as_str().to_string(),
+            )
+        })
+        .collect();
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES + 1)
+        .read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return Err(Error::Ipc(format!(
+            "The payload is bigger than {MAX_BODY_BYTES} bytes"
+        )));
+    }
+    Ok(Delivery { headers, body })
+}
+
+/// .
+///
+/// # Answers a webhook
+///
+/// Checks the signature before looking at the payload at all, then queues a
+/// check of every configured repo the push went to. Paused repos are left
+/// alone and anything but a push to a branch is acknowledged and ignored
+pub(crate) fn handle(
+    delivery: &Delivery,
+    secret: &[u8],
+    state: &SharedState,
+    events: &Sender<Event>,
+    targets: fn() -> Vec<Target>,
+) -> Reply {
+    let provider = Provider::detect(&delivery.headers);
+    if !verify(provider, delivery, secret) {
+        warn!("Rejected a {provider:?} webhook with a missing or wrong signature");
+        return Reply::new(401, "The signature doesn't match");
+    }
+
+    if let Some(header) = provider.event_header() {
+        let event = delivery.headers[header].as_str();
+        if event == "ping" {
+            return Reply::new(200, "pong");
+        }
+        if event != provider.push_event() {
+            return Reply::new(200, format!("Ignoring {event} events"));
+        }
+    }
+
+    let push = match parse(provider, &delivery.body) {
+        Ok(push) => push,
+        Err(e) => return Reply::new(400, e.to_string()),
+    };
+    let Some(branch) = &push.branch else {
+        return Reply::new(200, "Ignoring a push that isn't to a branch");
+    };
+
+    let matching: Vec<Target> = targets()
+        .into_iter()
+        .filter(|target| {
+            push.names.contains(&target.name)
+                || target
+                    .remote_url
+                    .as_deref()
+                    .is_some_and(|url| push.urls.contains(&normalize_url(url)))
+        })
+        .collect();
+    if matching.is_empty() {
+        let pushed_to = if push.names.is_empty() {
+            &push.urls
+        } else {
+            &push.names
+        };
+        return Reply::new(
+            404,
+            format!("No repo is configured for {}", pushed_to.join(" or ")),
+        );
+    }
+
+    let mut queued = Vec::new();
+    for target in matching
+        .into_iter()
+        .filter(|target| &target.branch == branch)
+    {
+        if control::lock(state).is_paused(&target.name) {
+            info!("Webhook for {} ignored, it is paused", target.name);
+            continue;
+        }
+        if events.send(Event::Check(target.name.clone())).is_err() {
+            return Reply::new(503, "The service is shutting down");
+        }
+        info!("Webhook queued a check of {}", target.name);
+        queued.push(target.name);
+    }
+    if queued.is_empty() {
+        return Reply::new(200, format!("Nothing to check for {branch}"));
+    }
+    Reply::new(202, format!("Check of {} queued", queued.join(", ")))
+}
+
+/// .
+///
+/// # Checks that a delivery comes from someone who knows `secret`
+///
+/// GitHub, Gitea and generic senders sign the body with HMAC-SHA256, GitLab
+/// sends the secret itself as its token
+fn verify(provider: Provider, delivery: &Delivery, secret: &[u8]) -> bool {
+    let header = match provider {
+        Provider::GitLab => {
+            return delivery
+                .headers
+                .get("x-gitlab-token")
+                .is_some_and(|token| constant_time_eq(token.as_bytes(), secret));
+        }
+        Provider::Gitea => delivery
+            .headers
+            .get("x-gitea-signature")
+            .map(String::as_str),
+        Provider::GitHub | Provider::Generic => delivery
+            .headers
+            .get("x-hub-signature-256")
+            .and_then(|signature| signature.strip_prefix("sha256=")),
+    };
+    let Some(signature) = header.and_then(|header| hex::decode(header.trim()).ok()) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(&delivery.body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Pulls the repository and branch out of a push payload
+fn parse(provider: Provider, body: &[u8]) -> Result<Push> {
+    let payload: Value = serde_json::from_slice(body)
+        .map_err(|e| Error::Ipc(format!("The payload isn't JSON: {e}")))?;
+    let strings = |object: &Value, keys: &[&str]| -> Vec<String> {
+        keys.iter()
+            .filter_map(|key| object.get(key)?.as_str())
+            .map(String::from)
+            .collect()
+    };
+
+    let (names, urls, git_ref) = match provider {
+        Provider::GitHub | Provider::Gitea => {
+            let repository = &payload["repository"];
+            (
+                strings(repository, &["name", "full_name"]),
+                strings(repository, &["clone_url", "ssh_url", "html_url", "git_url"]),
+                payload["ref"].as_str(),
+            )
+        }
+        Provider::GitLab => {
+            let project = &payload["project"];
+            (
+                strings(project, &["name", "path_with_namespace"]),
+                strings(project, &["git_http_url", "git_ssh_url", "web_url"]),
+                payload["ref"].as_str(),
+            )
+        }
+        Provider::Generic => {
+            let repository = strings(&payload, &["repository"]);
+            let branch = payload["branch"].as_str().map(String::from);
+            return match repository.first() {
+                Some(repository) => Ok(Push {
+                    names: vec![repository.clone()],
+                    urls: vec![normalize_url(repository)],
+                    branch: branch.or_else(|| {
+                        payload["ref"]
+                            .as_str()
+                            .and_then(|git_ref| git_ref.strip_prefix("refs/heads/"))
+                            .map(String::from)
+                    }),
+                }),
+                None => Err(Error::Ipc(String::from(
+                    "The payload needs a \"repository\" with the repo's name or remote url",
+                ))),
+            };
+        }
+    };
+    if names.is_empty() && urls.is_empty() {
+        return Err(Error::Ipc(String::from("The payload names no repository")));
+    }
+    Ok(Push {
+        names,
+        urls: urls.iter().map(|url| normalize_url(url)).collect(),
+        branch: git_ref
+            .and_then(|git_ref| git_ref.strip_prefix("refs/heads/"))
+            .map(String::from),
+    })
+}
+
+/// .
+///
+/// # Reduces a remote url to host and path
+///
+/// So the ssh and https urls of one repository compare equal, e.g.
+/// `git@github.com:org/site.git` and `https://github.com/org/site` both
+/// become `github.com/org/site`
+fn normalize_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+    let url = url.strip_suffix(".git").unwrap_or(url);
+    let rest = match url.split_once("://") {
+        Some((_, rest)) => rest.to_string(),
+        // scp-like, user@host:path
+        None => url.replacen(':', "/", 1),
+    };
+    let (host, path) = rest.split_once('/').unwrap_or((&rest, ""));
+    let host = host.rsplit('@').next().unwrap_or(host);
+    let host = host.split(':').next().unwrap_or(host);
+    format!("{host}/{path}").to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ServiceState;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex, mpsc};
+
+    const SECRET: &[u8] = b"s3cret";
+
+    fn targets() -> Vec<Target> {
+        vec![
+            Target {
+                name: String::from("site"),
+                branch: String::from("main"),
+                remote_url: Some(String::from("git@github.com:org/site.git")),
+            },
+            Target {
+                name: String::from("site-staging"),
+                branch: String::from("staging"),
+                remote_url: Some(String::from("https://github.com/org/site")),
+            },
+            Target {
+                name: String::from("docs"),
+                branch: String::from("main"),
+                remote_url: None,
+            },
+        ]
+    }
+
+    fn shared_state() -> SharedState {
+        Arc::new(Mutex::new(ServiceState::new(String::from("/configs"))))
+    }
+
+    fn sign(body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+        mac.update(body.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn delivery(headers: &[(&str, &str)], body: &str) -> Delivery {
+        Delivery {
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    const GITHUB_PUSH: &str = r#"{"ref":"refs/heads/main","repository":{"name":"site","full_name":"org/site","clone_url":"https://github.com/org/site.git","ssh_url":"git@github.com:org/site.git"}}"#;
+
+    #[test]
+    fn test_normalize_url() {
+        for url in [
+            "git@github.com:org/site.git",
+            "https://github.com/org/site",
+            "https://token@GitHub.com/org/site.git/",
+            "ssh://git@github.com:22/org/site.git",
+        ] {
+            assert_eq!(normalize_url(url), "github.com/org/site", "{url}");
+        }
+    }
+
+    #[test]
+    fn test_github_push() {
+        let state = shared_state();
+        let (tx, rx) = mpsc::channel();
+        let signature = format!("sha256={}", sign(GITHUB_PUSH));
+
+        let reply = handle(
+            &delivery(
+                &[
+                    ("x-github-event", "push"),
+                    ("x-hub-signature-256", &signature),
+                ],
+                GITHUB_PUSH,
+            ),
+            SECRET,
+            &state,
+            &tx,
+            targets,
+        );
+        assert_eq!(reply.status, 202);
+        // matched by its remote url, the staging deploy is on another branch
+        assert_eq!(rx.try_recv().unwrap(), Event::Check(String::from("site")));
+        assert!(rx.try_recv().is_err());
+
+        // a paused repo waits for its round like anything else
+        control::lock(&state)
+            .repos
+            .entry(String::from("site"))
+            .or_default()
+            .paused = true;
+        let reply = handle(
+            &delivery(
+                &[
+                    ("x-github-event", "push"),
+                    ("x-hub-signature-256", &signature),
+                ],
+                GITHUB_PUSH,
+            ),
+            SECRET,
+            &state,
+            &tx,
+            targets,
+        );
+        assert_eq!(reply.status, 200);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_bad_signatures() {
+        let state = shared_state();
+        let (tx, rx) = mpsc::channel();
+
+        for headers in [
+            vec![("x-github-event", "push")],
+            vec![
+                ("x-github-event", "push"),
+                ("x-hub-signature-256", "sha256=00ff"),
+            ],
+            vec![("x-gitlab-event", "Push Hook"), ("x-gitlab-token", "s3cre")],
+            vec![("x-gitea-event", "push"), ("x-gitea-signature", "not hex")],
+        ] {
+            let reply = handle(
+                &delivery(&headers, GITHUB_PUSH),
+                SECRET,
+                &state,
+                &tx,
+                targets,
+            );
+            assert_eq!(reply.status, 401, "{headers:?}");
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_gitlab_gitea_and_generic() {
+        let state = shared_state();
+        let (tx, rx) = mpsc::channel();
+
+        let gitlab = r#"{"ref":"refs/heads/staging","project":{"name":"site","path_with_namespace":"org/site","git_ssh_url":"git@github.com:org/site.git"}}"#;
+        let reply = handle(
+            &delivery(
+                &[
+                    ("x-gitlab-event", "Push Hook"),
+                    ("x-gitlab-token", "s3cret"),
+                ],
+                gitlab,
+            ),
+            SECRET,
+            &state,
+            &tx,
+            targets,
+        );
+        assert_eq!(reply.status, 202);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Event::Check(String::from("site-staging"))
+        );
+
+        let gitea =
+            r#"{"ref":"refs/heads/main","repository":{"name":"docs","full_name":"org/docs"}}"#;
+        let reply = handle(
+            &delivery(
+                &[
+                    ("x-gitea-event", "push"),
+                    ("x-github-event", "push"),
+                    ("x-gitea-signature", &sign(gitea)),
+                ],
+                gitea,
+            ),
+            SECRET,
+            &state,
+            &tx,
+            targets,
+        );
+        assert_eq!(reply.status, 202);
+        assert_eq!(rx.try_recv().unwrap(), Event::Check(String::from("docs")));
+
+        let generic = r#"{"repository":"docs","branch":"main"}"#;
+        let signature = format!("sha256={}", sign(generic));
+        let reply = handle(
+            &delivery(&[("x-hub-signature-256", &signature)], generic),
+            SECRET,
+            &state,
+            &tx,
+            targets,
+        );
+        assert_eq!(reply.status, 202);
+        assert_eq!(rx.try_recv().unwrap(), Event::Check(String::from("docs")));
+
+        let unknown = r#"{"repository":"nope","branch":"main"}"#;
+        let signature = format!("sha256={}", sign(unknown));
+        let reply = handle(
+            &delivery(&[("x-hub-signature-256", &signature)], unknown),
+            SECRET,
+            &state,
+            &tx,
+            targets,
+        );
+        assert_eq!(reply.status, 404);
+    }
+
+    #[test]
+    fn test_other_events() {
+        let state = shared_state();
+        let (tx, rx) = mpsc::channel();
+
+        let body = "{}";
+        let signature = format!("sha256={}", sign(body));
+        for event in ["ping", "issues"] {
+            let reply = handle(
+                &delivery(
+                    &[
+                        ("x-github-event", event),
+                        ("x-hub-signature-256", &signature),
+                    ],
+                    body,
+                ),
+                SECRET,
+                &state,
+                &tx,
+                targets,
+            );
+            assert_eq!(reply.status, 200, "{event}");
+        }
+
+        let tag = r#"{"ref":"refs/tags/v1","repository":{"name":"site"}}"#;
+        let signature = format!("sha256={}", sign(tag));
+        let reply = handle(
+            &delivery(
+                &[
+                    ("x-github-event", "push"),
+                    ("x-hub-signature-256", &signature),
+                ],
+                tag,
+            ),
+            SECRET,
+            &state,
+            &tx,
+            targets,
+        );
+        assert_eq!(reply.status, 200);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_over_http() {
+        let server = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let (tx, rx) = mpsc::channel();
+        spawn(server, SECRET.to_vec(), shared_state(), tx, targets);
+
+        let send = |request: String| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = send(format!(
+            "POST /hook HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nX-GitHub-Event: push\r\nX-Hub-Signature-256: sha256={}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{GITHUB_PUSH}",
+            sign(GITHUB_PUSH),
+            GITHUB_PUSH.len()
+        ));
+        assert!(response.starts_with("HTTP/1.1 202"), "{response}");
+        assert_eq!(rx.recv().unwrap(), Event::Check(String::from("site")));
+
+        let response = send(String::from(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        ));
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+    }
+}